@@ -4,6 +4,18 @@ fn main() {
     // Pass in build-time environment variables, which could be used in
     // crates by `env!` macros.
     println!("cargo:rustc-env=PATH_HOSTNAMECTL=hostnamectl");
+    println!("cargo:rustc-env=PATH_HOSTNAME=hostname");
     println!("cargo:rustc-env=PATH_USERADD=useradd");
     println!("cargo:rustc-env=PATH_PASSWD=passwd");
+    println!("cargo:rustc-env=PATH_CHPASSWD=chpasswd");
+    println!("cargo:rustc-env=PATH_USERMOD=usermod");
+    println!("cargo:rustc-env=PATH_ID=id");
+    println!("cargo:rustc-env=PATH_GETENT=getent");
+    println!("cargo:rustc-env=PATH_RESTORECON=restorecon");
+    println!("cargo:rustc-env=PATH_LOCALECTL=localectl");
+    println!("cargo:rustc-env=PATH_TIMEDATECTL=timedatectl");
+    println!("cargo:rustc-env=PATH_DMIDECODE=dmidecode");
+    println!("cargo:rustc-env=PATH_VISUDO=visudo");
+    println!("cargo:rustc-env=PATH_SH=sh");
+    println!("cargo:rustc-env=PATH_SYSTEMD_DETECT_VIRT=systemd-detect-virt");
 }
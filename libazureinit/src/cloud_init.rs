@@ -0,0 +1,75 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Detecting cloud-init, which provisions the same things as azure-init.
+
+use std::path::Path;
+
+use crate::rootfs::under;
+
+/// Written by cloud-init once it has run this boot.
+pub const PATH_RESULT: &str = "/run/cloud-init/result.json";
+
+/// Written by cloud-init's systemd generator when it is enabled this boot.
+pub const PATH_ENABLED: &str = "/run/cloud-init/enabled";
+
+/// Disables cloud-init, whatever the state of its units.
+pub const PATH_DISABLED: &str = "/etc/cloud/cloud-init.disabled";
+
+/// Whether cloud-init is active on this system.
+pub fn is_active() -> bool {
+    is_active_in(Path::new("/"))
+}
+
+/// Whether cloud-init is active on the system rooted at `root`: it is not
+/// disabled, and has either run or been enabled this boot.
+fn is_active_in(root: &Path) -> bool {
+    if under(root, PATH_DISABLED).exists() {
+        return false;
+    }
+
+    [PATH_RESULT, PATH_ENABLED].iter().any(|path| {
+        let path = under(root, path);
+        let found = path.exists();
+        if found {
+            tracing::info!(path = %path.display(), "Found cloud-init");
+        }
+        found
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn touch(root: &Path, path: &str) {
+        let path = under(root, path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    #[test]
+    fn absent() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(!is_active_in(root.path()));
+    }
+
+    #[test]
+    fn detected_by_sentinel() {
+        for sentinel in [PATH_RESULT, PATH_ENABLED] {
+            let root = tempfile::tempdir().unwrap();
+            touch(root.path(), sentinel);
+            assert!(is_active_in(root.path()), "{sentinel}");
+        }
+    }
+
+    #[test]
+    fn disabled_wins() {
+        let root = tempfile::tempdir().unwrap();
+        touch(root.path(), PATH_ENABLED);
+        touch(root.path(), PATH_DISABLED);
+        assert!(!is_active_in(root.path()));
+    }
+}
@@ -0,0 +1,1317 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Runtime configuration for azure-init.
+//!
+//! Configuration is layered: built-in defaults are overridden by the system
+//! configuration file, then by the `*.toml` drop-ins next to it, then by
+//! `AZURE_INIT_` environment variables, then by the drop-ins in a directory
+//! passed by the caller, and finally by a file passed by the caller.
+//!
+//! Environment variables name a setting by its section and key, separated by
+//! `__`, e.g. `AZURE_INIT_SELINUX__RESTORE_CONTEXTS=false`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::Figment;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::provision::hooks;
+use crate::{goalstate, imds, media, user};
+
+/// The configuration file loaded when it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/azure-init/azure-init.toml";
+
+/// The directory of drop-ins loaded after [`DEFAULT_CONFIG_PATH`], in order
+/// of file name.
+pub const DEFAULT_CONFIG_DIR: &str = "/etc/azure-init/azure-init.toml.d";
+
+/// The directory of `*.toml` files, each describing a user to create along
+/// with the provisioned one, in order of file name.
+pub const DEFAULT_USERS_DIR: &str = "/etc/azure-init/users.d";
+
+/// The prefix of environment variables holding settings.
+pub const ENV_PREFIX: &str = "AZURE_INIT_";
+
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct Config {
+    pub imds: Imds,
+    pub wireserver: Wireserver,
+    pub user_provisioners: UserProvisioners,
+    pub selinux: Selinux,
+    pub machine_info: MachineInfo,
+    pub provisioning_media: ProvisioningMedia,
+    pub hooks: Hooks,
+    pub post_provision: PostProvision,
+    pub ssh: Ssh,
+    pub hostname_provisioners: HostnameProvisioners,
+    pub system: System,
+    pub conflict_detection: ConflictDetection,
+    pub container: Container,
+    pub http: Http,
+    pub azure_init_log_path: AzureInitLogPath,
+    pub telemetry: Telemetry,
+    pub security: Security,
+    pub custom_data: CustomData,
+    pub azure_proxy_agent: AzureProxyAgent,
+    /// Users to create along with the provisioned one, which are also read
+    /// from the files in [`DEFAULT_USERS_DIR`].
+    pub users: Vec<ExtraUser>,
+}
+
+/// Settings for the Azure Instance Metadata Service.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Imds {
+    /// Base URL of the metadata service.
+    pub endpoint: String,
+    /// How long a preprovisioned VM waits to be assigned before giving up.
+    pub preprovision_poll_timeout_secs: u64,
+    /// How long to wait for a connection to the metadata service, in
+    /// seconds.
+    pub connect_timeout_secs: f64,
+    /// How long to wait for each request to the metadata service to
+    /// complete, in seconds.
+    pub total_timeout_secs: f64,
+    /// Retry the instance metadata query at `ipv6_endpoint` when `endpoint`
+    /// can't be connected to, e.g. on IPv6-only networks.
+    pub try_ipv6: bool,
+    /// Base URL of the metadata service over IPv6, e.g. `http://[fd00::1]`.
+    pub ipv6_endpoint: Option<String>,
+}
+
+impl Imds {
+    /// How long to wait for a connection to the metadata service, which is
+    /// never more than [`Imds::total_timeout`] as the connection is part of
+    /// the request.
+    ///
+    /// The timeouts must have been validated.
+    pub fn connect_timeout(&self) -> Duration {
+        if self.total_timeout_secs < self.connect_timeout_secs {
+            tracing::warn!(
+                total_timeout_secs = self.total_timeout_secs,
+                connect_timeout_secs = self.connect_timeout_secs,
+                "imds.connect_timeout_secs is more than total_timeout_secs, \
+                 using total_timeout_secs"
+            );
+            return self.total_timeout();
+        }
+        Duration::from_secs_f64(self.connect_timeout_secs)
+    }
+
+    /// How long to wait for each request to the metadata service to
+    /// complete.
+    ///
+    /// The timeouts must have been validated.
+    pub fn total_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.total_timeout_secs)
+    }
+
+    /// The endpoint to fall back to if `endpoint` can't be connected to.
+    pub fn fallback_endpoint(&self) -> Option<&str> {
+        if !self.try_ipv6 {
+            return None;
+        }
+        if self.ipv6_endpoint.is_none() {
+            tracing::warn!("imds.try_ipv6 is set without an ipv6_endpoint");
+        }
+        self.ipv6_endpoint.as_deref()
+    }
+}
+
+impl Default for Imds {
+    fn default() -> Self {
+        Self {
+            endpoint: imds::DEFAULT_IMDS_URL.to_string(),
+            preprovision_poll_timeout_secs: 3600,
+            connect_timeout_secs: 30.0,
+            total_timeout_secs: 30.0,
+            try_ipv6: false,
+            ipv6_endpoint: None,
+        }
+    }
+}
+
+/// Settings for the Azure wireserver.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Wireserver {
+    /// Base URL of the wireserver.
+    pub endpoint: String,
+    /// The format of health reports, `xml` for the wireserver or `json` for
+    /// endpoints which expect it.
+    pub health_format: HealthFormat,
+}
+
+impl Default for Wireserver {
+    fn default() -> Self {
+        Self {
+            endpoint: goalstate::DEFAULT_WIRESERVER_URL.to_string(),
+            health_format: HealthFormat::default(),
+        }
+    }
+}
+
+/// The body format of a health report.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Serialize,
+    PartialEq,
+    Eq,
+    JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthFormat {
+    #[default]
+    Xml,
+    Json,
+}
+
+/// Settings for the provisioned user account.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct UserProvisioners {
+    /// The login shell of the user, if the system default isn't wanted.
+    pub default_shell: Option<PathBuf>,
+    /// Supplementary groups to add the user to.
+    pub groups: Vec<String>,
+    /// Create the user as a system account, e.g. for appliance images. No
+    /// password is set for system accounts.
+    pub system_account: bool,
+    /// Grant the user passwordless sudo, as Azure does for the admin user.
+    pub grant_sudo: bool,
+    /// The user to provision when neither IMDS nor the OVF environment
+    /// names one, e.g. for keys-only images.
+    pub default_username: Option<String>,
+    /// Set the login shell of a user which already exists, e.g. one baked
+    /// into the image, rather than leaving it as it is.
+    pub update_existing: bool,
+}
+
+impl Default for UserProvisioners {
+    fn default() -> Self {
+        Self {
+            default_shell: None,
+            groups: vec![],
+            system_account: false,
+            grant_sudo: true,
+            default_username: None,
+            update_existing: false,
+        }
+    }
+}
+
+/// Settings for SELinux handling.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Selinux {
+    /// Restore the contexts of written files when SELinux is enabled.
+    pub restore_contexts: bool,
+}
+
+impl Default for Selinux {
+    fn default() -> Self {
+        Self {
+            restore_contexts: true,
+        }
+    }
+}
+
+/// Settings for writing `/etc/machine-info`.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct MachineInfo {
+    /// Record the VM's location and size from IMDS in the file.
+    pub enable: bool,
+}
+
+/// Settings for reading the provisioning media.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct ProvisioningMedia {
+    /// Read NoCloud `meta-data` and `user-data` from media without an OVF
+    /// environment, e.g. when testing outside Azure.
+    pub nocloud: bool,
+    /// How many more times to try mounting a device which fails to mount,
+    /// e.g. because udev hasn't finished with it early in boot.
+    pub mount_retries: u32,
+    /// How long to wait between attempts to mount a device.
+    pub mount_retry_interval_ms: u64,
+    /// How long, in seconds, to wait for `mount` before giving up on the
+    /// attempt, e.g. when the device is stuck.
+    pub mount_timeout_secs: f64,
+    /// Leave the media mounted after reading it, e.g. to inspect an OVF
+    /// environment which failed to parse.
+    pub keep_mounted: bool,
+}
+
+impl Default for ProvisioningMedia {
+    fn default() -> Self {
+        Self {
+            nocloud: false,
+            mount_retries: 3,
+            mount_retry_interval_ms: 500,
+            mount_timeout_secs: media::DEFAULT_MOUNT_TIMEOUT.as_secs_f64(),
+            keep_mounted: false,
+        }
+    }
+}
+
+/// Settings for running hook scripts during provisioning.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Hooks {
+    /// Run the executables in `dir` at each provisioning phase.
+    pub enable: bool,
+    /// The directory holding the hooks.
+    pub dir: PathBuf,
+    /// Fail provisioning if a hook fails, rather than only logging it.
+    pub fatal: bool,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            dir: PathBuf::from(hooks::PATH_HOOKS_DIR),
+            fatal: false,
+        }
+    }
+}
+
+/// Settings for reading a cloud-config from the VM's custom data.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct CustomData {
+    /// Apply the supported subset of a cloud-config passed as custom data.
+    pub enable: bool,
+}
+
+/// A user baked into the image, created along with the provisioned user.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+pub struct ExtraUser {
+    /// The username.
+    pub name: String,
+    /// Supplementary groups to add the user to.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// SSH public keys to authorize for the user.
+    #[serde(default)]
+    pub ssh_authorized_keys: Vec<String>,
+    /// The login shell, else `user_provisioners.default_shell`, else the
+    /// system's default.
+    #[serde(default)]
+    pub shell: Option<PathBuf>,
+    /// Grant the user passwordless sudo.
+    #[serde(default)]
+    pub sudo: bool,
+}
+
+/// Settings for the Azure proxy agent, which can mediate the VM's requests to
+/// IMDS.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct AzureProxyAgent {
+    /// Query the instance metadata through `endpoint`, falling back to IMDS
+    /// directly if that fails.
+    pub enable: bool,
+    /// Base URL at which the proxy agent serves the metadata service.
+    pub endpoint: Option<String>,
+}
+
+impl AzureProxyAgent {
+    /// The endpoint to query the instance metadata through first, if any.
+    pub fn proxy_endpoint(&self) -> Option<&str> {
+        if !self.enable {
+            return None;
+        }
+        if self.endpoint.is_none() {
+            tracing::warn!(
+                "azure_proxy_agent.enable is set without an endpoint"
+            );
+        }
+        self.endpoint.as_deref()
+    }
+}
+
+/// Settings for a single command run after provisioning succeeds.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct PostProvision {
+    /// A shell command to run once every provisioning step has succeeded,
+    /// e.g. `systemctl enable ssh`.
+    pub command: Option<String>,
+    /// Fail provisioning if the command fails, rather than only logging it.
+    pub fatal: bool,
+}
+
+/// Settings for SSH key provisioning.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Ssh {
+    /// The authorized keys file to write when provisioning root, rather
+    /// than `/root/.ssh/authorized_keys`.
+    pub root_keys_path: Option<PathBuf>,
+    /// Keys authorized for the provisioned user in addition to those in the
+    /// provisioning metadata, e.g. a break-glass key baked into the image.
+    pub extra_authorized_keys: Vec<String>,
+    /// sshd settings, keyword to value, to enforce with a drop-in, e.g.
+    /// `PermitRootLogin = "no"`.
+    pub enforce_settings: BTreeMap<String, String>,
+    /// The authorized keys file, relative to the user's home directory,
+    /// rather than `.ssh/authorized_keys`. It may not be outside the home
+    /// directory; see `honor_key_paths` for that.
+    pub authorized_keys_path: Option<PathBuf>,
+    /// Write each key to the file named by its `path` in the metadata,
+    /// rather than all of them to the user's authorized keys file. Paths
+    /// outside the user's home directory are ignored.
+    pub honor_key_paths: bool,
+    /// Create the user's `.ssh` directory even when there are no keys to
+    /// write, e.g. for password-only accounts.
+    pub always_create_ssh_dir: bool,
+    /// An SSH certificate authority's public key, trusted to sign user
+    /// certificates with sshd's `TrustedUserCAKeys`.
+    pub trusted_user_ca_keys: Option<String>,
+    /// Wait for the user's home directory to be writable before writing
+    /// keys, e.g. when it is on a network filesystem mounted later in boot.
+    pub wait_for_home: bool,
+    /// How long to wait for the home directory, in seconds.
+    pub wait_for_home_timeout_secs: f64,
+}
+
+impl Default for Ssh {
+    fn default() -> Self {
+        Self {
+            root_keys_path: None,
+            extra_authorized_keys: vec![],
+            enforce_settings: BTreeMap::new(),
+            authorized_keys_path: None,
+            honor_key_paths: false,
+            always_create_ssh_dir: false,
+            trusted_user_ca_keys: None,
+            wait_for_home: false,
+            wait_for_home_timeout_secs: 60.0,
+        }
+    }
+}
+
+/// Settings for setting the hostname.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct HostnameProvisioners {
+    /// Set the hostname from the provisioning metadata. Disable this to keep
+    /// a hostname changed after deployment across reboots.
+    pub enable: bool,
+    /// Fall back to the hostname in the DHCP lease when the provisioning
+    /// metadata has none.
+    pub dhcp_fallback: bool,
+    /// Resolve the hostname to the VM's primary private IP with a line in
+    /// `/etc/hosts`.
+    pub map_private_ip: bool,
+    /// Also write `/etc/hostname` when a backend like `hostnamectl`, which
+    /// is expected to write it, sets the hostname.
+    pub write_etc_hostname: bool,
+}
+
+impl Default for HostnameProvisioners {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            dhcp_fallback: false,
+            map_private_ip: false,
+            write_etc_hostname: false,
+        }
+    }
+}
+
+/// System settings applied while provisioning; each is left alone unless
+/// set.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct System {
+    /// The locale, e.g. `en_US.UTF-8`.
+    pub locale: Option<String>,
+    /// The timezone, e.g. `Europe/Berlin`.
+    pub timezone: Option<String>,
+    /// The console keyboard layout, e.g. `us`.
+    pub keymap: Option<String>,
+}
+
+/// Settings for coexisting with other provisioning agents.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct ConflictDetection {
+    /// Exit without provisioning if cloud-init is active.
+    pub defer_to_cloud_init: bool,
+}
+
+/// Settings for running in a container rather than a VM.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct Container {
+    /// Exit without provisioning if running in a container.
+    pub skip: bool,
+}
+
+/// Settings for the HTTP requests to IMDS and the wireserver.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct Http {
+    /// Appended to the User-Agent, e.g. to identify the image.
+    pub user_agent_suffix: Option<String>,
+}
+
+/// Where azure-init writes its log, besides stderr.
+#[derive(
+    Clone, Debug, Default, Deserialize, Serialize, PartialEq, JsonSchema,
+)]
+#[serde(default)]
+pub struct AzureInitLogPath {
+    /// The file to append the log to; none is written if unset.
+    pub path: Option<PathBuf>,
+}
+
+/// Settings for what azure-init logs.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Telemetry {
+    /// Regular expressions whose matches are replaced with `***` in the log,
+    /// in addition to values of keys like `password` and `token`. Only the
+    /// group named `value` is replaced if a pattern has one.
+    pub redact_patterns: Vec<String>,
+    /// Rotate the log file when it's larger than this when azure-init
+    /// starts; zero disables rotation.
+    pub log_max_bytes: u64,
+    /// How many rotated log files to keep.
+    pub log_max_files: u32,
+    /// Don't write the log file when its filesystem has less than this
+    /// available; zero disables the check. Stderr still has the log.
+    pub min_free_bytes: u64,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            redact_patterns: vec![],
+            log_max_bytes: 10 * 1024 * 1024,
+            log_max_files: 5,
+            min_free_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Settings for the permissions of the files azure-init writes.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(default)]
+pub struct Security {
+    /// The mode of files such as `authorized_keys`, which may only be
+    /// stricter than `0o600`.
+    pub file_mode: u32,
+    /// The mode of directories such as `.ssh`, which may only be stricter
+    /// than `0o700`.
+    pub dir_mode: u32,
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Self {
+            file_mode: user::DEFAULT_FILE_MODE,
+            dir_mode: user::DEFAULT_DIR_MODE,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from [`DEFAULT_CONFIG_PATH`] and the drop-ins
+    /// in [`DEFAULT_CONFIG_DIR`], if present, then from [`ENV_PREFIX`]
+    /// environment variables, and then from `path`, if provided.
+    ///
+    /// Unlike the system configuration file, a caller-provided `path` must
+    /// exist.
+    pub fn load(path: Option<&Path>) -> Result<Config, Error> {
+        Self::load_with_dir(None, path)
+    }
+
+    /// Load the configuration as [`Config::load`] does, with the drop-ins in
+    /// `dir`, if provided, applied before `path`.
+    ///
+    /// `dir` must be an existing directory, and `path` a file.
+    pub fn load_with_dir(
+        dir: Option<&Path>,
+        path: Option<&Path>,
+    ) -> Result<Config, Error> {
+        Self::load_from(Path::new(DEFAULT_CONFIG_PATH), dir, path)
+    }
+
+    /// Load the configuration as [`Config::load_with_dir`] does, along with
+    /// the files it was loaded from, in the order they were merged.
+    pub fn load_with_sources(
+        dir: Option<&Path>,
+        path: Option<&Path>,
+    ) -> Result<(Config, Vec<PathBuf>), Error> {
+        Self::load_from_with_sources(Path::new(DEFAULT_CONFIG_PATH), dir, path)
+    }
+
+    fn load_from(
+        base: &Path,
+        dir: Option<&Path>,
+        path: Option<&Path>,
+    ) -> Result<Config, Error> {
+        Self::load_from_with_sources(base, dir, path).map(|(config, _)| config)
+    }
+
+    fn load_from_with_sources(
+        base: &Path,
+        dir: Option<&Path>,
+        path: Option<&Path>,
+    ) -> Result<(Config, Vec<PathBuf>), Error> {
+        Self::load_with_env(base, ENV_PREFIX, dir, path)
+    }
+
+    fn load_with_env(
+        base: &Path,
+        env_prefix: &str,
+        dir: Option<&Path>,
+        path: Option<&Path>,
+    ) -> Result<(Config, Vec<PathBuf>), Error> {
+        let mut figment =
+            Figment::from(Serialized::defaults(Config::default()));
+        let mut sources = vec![];
+        let mut merge_file = |figment: Figment, path: PathBuf| {
+            tracing::info!(path = %path.display(), "Loading configuration");
+            let figment = figment.merge(Toml::file(&path));
+            sources.push(path);
+            figment
+        };
+        if base.exists() {
+            figment = merge_file(figment, base.to_path_buf());
+        }
+        let base_dir = drop_in_dir(base);
+        if base_dir.is_dir() {
+            for drop_in in drop_ins(&base_dir)? {
+                figment = merge_file(figment, drop_in);
+            }
+        }
+        // Only variables naming a section's key are settings; others, like
+        // AZURE_INIT_LOG, are read elsewhere.
+        figment = figment.merge(
+            Env::prefixed(env_prefix)
+                .filter(|key| key.as_str().contains("__"))
+                .split("__"),
+        );
+        if let Some(dir) = dir {
+            if !dir.is_dir() {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{} is not a directory", dir.display()),
+                )));
+            }
+            for drop_in in drop_ins(dir)? {
+                figment = merge_file(figment, drop_in);
+            }
+        }
+        if let Some(path) = path {
+            if !path.exists() {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{} does not exist", path.display()),
+                )));
+            }
+            if path.is_dir() {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is a directory, pass it as a drop-in directory",
+                        path.display()
+                    ),
+                )));
+            }
+            figment = merge_file(figment, path.to_path_buf());
+        }
+
+        let mut config: Config = figment.extract().map_err(Box::new)?;
+        let users_dir = base.with_file_name("users.d");
+        if users_dir.is_dir() {
+            for path in drop_ins(&users_dir)? {
+                tracing::info!(path = %path.display(), "Loading user");
+                let user: ExtraUser = Figment::from(Toml::file(&path))
+                    .extract()
+                    .map_err(Box::new)?;
+                config.users.push(user);
+                sources.push(path);
+            }
+        }
+        config.validate()?;
+
+        Ok((config, sources))
+    }
+
+    /// Check the values that can't be expressed in the type system.
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_url(&self.imds.endpoint)?;
+        validate_url(&self.wireserver.endpoint)?;
+        if let Some(url) = &self.imds.ipv6_endpoint {
+            validate_url(url)?;
+        }
+        if let Some(url) = &self.azure_proxy_agent.endpoint {
+            validate_url(url)?;
+        }
+        validate_timeout(
+            "imds.connect_timeout_secs",
+            self.imds.connect_timeout_secs,
+        )?;
+        validate_timeout(
+            "imds.total_timeout_secs",
+            self.imds.total_timeout_secs,
+        )?;
+        validate_timeout(
+            "imds.preprovision_poll_timeout_secs",
+            self.imds.preprovision_poll_timeout_secs as f64,
+        )?;
+        validate_timeout(
+            "provisioning_media.mount_timeout_secs",
+            self.provisioning_media.mount_timeout_secs,
+        )?;
+        validate_timeout(
+            "ssh.wait_for_home_timeout_secs",
+            self.ssh.wait_for_home_timeout_secs,
+        )?;
+        for pattern in &self.telemetry.redact_patterns {
+            if let Err(error) = regex::Regex::new(pattern) {
+                return Err(Error::InvalidRedactPattern {
+                    pattern: pattern.clone(),
+                    reason: error.to_string(),
+                });
+            }
+        }
+        for (i, user) in self.users.iter().enumerate() {
+            if self.users[..i].iter().any(|other| other.name == user.name) {
+                return Err(Error::DuplicateUser {
+                    name: user.name.clone(),
+                });
+            }
+        }
+        validate_mode(self.security.file_mode, user::DEFAULT_FILE_MODE)?;
+        validate_mode(self.security.dir_mode, user::DEFAULT_DIR_MODE)?;
+
+        Ok(())
+    }
+
+    /// The settings which differ from [`Config::default`], keyed by their
+    /// dotted path, e.g. `imds.endpoint`.
+    pub fn non_default(&self) -> BTreeMap<String, serde_json::Value> {
+        let mut current = BTreeMap::new();
+        flatten("", to_value(self), &mut current);
+        let mut defaults = BTreeMap::new();
+        flatten("", to_value(&Config::default()), &mut defaults);
+
+        current
+            .into_iter()
+            .filter(|(key, value)| defaults.get(key) != Some(value))
+            .collect()
+    }
+}
+
+/// The drop-in directory belonging to the configuration file at `base`,
+/// e.g. `azure-init.toml.d` for `azure-init.toml`.
+fn drop_in_dir(base: &Path) -> PathBuf {
+    let mut dir = base.as_os_str().to_owned();
+    dir.push(".d");
+    PathBuf::from(dir)
+}
+
+/// The `*.toml` files in `dir`, in order of file name.
+fn drop_ins(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut drop_ins = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+            drop_ins.push(path);
+        }
+    }
+    drop_ins.sort();
+
+    Ok(drop_ins)
+}
+
+/// A JSON Schema describing the configuration file.
+pub fn schema() -> serde_json::Value {
+    schemars::schema_for!(Config).to_value()
+}
+
+fn to_value(config: &Config) -> serde_json::Value {
+    serde_json::to_value(config).expect("Config is always serializable")
+}
+
+/// Collect the leaves of `value` into `out`, keyed by their dotted path.
+fn flatten(
+    prefix: &str,
+    value: serde_json::Value,
+    out: &mut BTreeMap<String, serde_json::Value>,
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(&path, value, out);
+            }
+        }
+        leaf => {
+            out.insert(prefix.to_string(), leaf);
+        }
+    }
+}
+
+/// Ensure `url` is an absolute HTTP(S) URL usable as an endpoint base.
+pub fn validate_url(url: &str) -> Result<(), Error> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        _ => Err(Error::InvalidUrl {
+            url: url.to_string(),
+        }),
+    }
+}
+
+/// Check `mode` grants nothing `default` doesn't.
+fn validate_mode(mode: u32, default: u32) -> Result<(), Error> {
+    if mode & !default == 0 {
+        Ok(())
+    } else {
+        Err(Error::PermissiveMode { mode, default })
+    }
+}
+
+/// Check `secs`, the value of `field`, is usable as a timeout: a positive
+/// [`Duration`] whose deadline can be computed.
+pub fn validate_timeout(field: &str, secs: f64) -> Result<(), Error> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(timeout)
+            if !timeout.is_zero()
+                && Instant::now().checked_add(timeout).is_some() =>
+        {
+            Ok(())
+        }
+        _ => Err(Error::InvalidTimeout {
+            field: field.to_string(),
+            secs,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn defaults_without_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config =
+            Config::load_from(&dir.path().join("missing.toml"), None, None)
+                .unwrap();
+
+        assert_eq!(config, Config::default());
+        assert_eq!(config.imds.endpoint, imds::DEFAULT_IMDS_URL);
+        assert_eq!(
+            config.wireserver.endpoint,
+            goalstate::DEFAULT_WIRESERVER_URL
+        );
+    }
+
+    #[test]
+    fn caller_file_overrides_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        let mut file = std::fs::File::create(&base).unwrap();
+        writeln!(file, "[imds]\nendpoint = \"http://127.0.0.1:1\"").unwrap();
+        writeln!(file, "[wireserver]\nendpoint = \"http://127.0.0.1:2\"")
+            .unwrap();
+
+        let extra = dir.path().join("extra.toml");
+        let mut file = std::fs::File::create(&extra).unwrap();
+        writeln!(file, "[imds]\nendpoint = \"http://127.0.0.1:3\"").unwrap();
+
+        let config = Config::load_from(&base, None, Some(&extra)).unwrap();
+        assert_eq!(config.imds.endpoint, "http://127.0.0.1:3");
+        assert_eq!(config.wireserver.endpoint, "http://127.0.0.1:2");
+    }
+
+    #[test]
+    fn environment_layer() {
+        // A prefix of its own keeps this from affecting other tests.
+        let prefix = "AZURE_INIT_ENV_LAYER_TEST_";
+        std::env::set_var(
+            format!("{prefix}IMDS__ENDPOINT"),
+            "http://127.0.0.1:4",
+        );
+        std::env::set_var(
+            format!("{prefix}SELINUX__RESTORE_CONTEXTS"),
+            "false",
+        );
+        std::env::set_var(format!("{prefix}LOG"), "debug");
+
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        let mut file = std::fs::File::create(&base).unwrap();
+        writeln!(file, "[imds]\nendpoint = \"http://127.0.0.1:1\"").unwrap();
+        writeln!(file, "[selinux]\nrestore_contexts = true").unwrap();
+
+        let (config, _) =
+            Config::load_with_env(&base, prefix, None, None).unwrap();
+        assert_eq!(config.imds.endpoint, "http://127.0.0.1:4");
+        assert!(!config.selinux.restore_contexts);
+
+        // The caller's file wins over the environment.
+        let extra = dir.path().join("extra.toml");
+        let mut file = std::fs::File::create(&extra).unwrap();
+        writeln!(file, "[imds]\nendpoint = \"http://127.0.0.1:3\"").unwrap();
+        let (config, _) =
+            Config::load_with_env(&base, prefix, None, Some(&extra)).unwrap();
+        assert_eq!(config.imds.endpoint, "http://127.0.0.1:3");
+        assert!(!config.selinux.restore_contexts);
+
+        std::env::remove_var(format!("{prefix}IMDS__ENDPOINT"));
+        std::env::remove_var(format!("{prefix}SELINUX__RESTORE_CONTEXTS"));
+        std::env::remove_var(format!("{prefix}LOG"));
+    }
+
+    #[test]
+    fn invalid_timeout() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+
+        for secs in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e20] {
+            config.imds.total_timeout_secs = secs;
+            assert!(matches!(
+                config.validate(),
+                Err(Error::InvalidTimeout { .. })
+            ));
+        }
+
+        let mut config = Config::default();
+        config.provisioning_media.mount_timeout_secs = -0.5;
+        match config.validate() {
+            Err(Error::InvalidTimeout { field, secs }) => {
+                assert_eq!(field, "provisioning_media.mount_timeout_secs");
+                assert_eq!(secs, -0.5);
+            }
+            result => panic!("expected an invalid timeout, got {result:?}"),
+        }
+
+        // Too long to convert to a Duration, which would panic.
+        let mut config = Config::default();
+        config.ssh.wait_for_home_timeout_secs = 1e20;
+        match config.validate() {
+            Err(Error::InvalidTimeout { field, .. }) => {
+                assert_eq!(field, "ssh.wait_for_home_timeout_secs");
+            }
+            result => panic!("expected an invalid timeout, got {result:?}"),
+        }
+
+        let mut config = Config::default();
+        config.imds.preprovision_poll_timeout_secs = 0;
+        let error = config.validate().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("imds.preprovision_poll_timeout_secs"));
+    }
+
+    #[test]
+    fn invalid_timeout_in_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        std::fs::write(&base, "[imds]\nconnect_timeout_secs = nan\n").unwrap();
+        match Config::load_from(&base, None, None) {
+            Err(Error::InvalidTimeout { field, secs }) => {
+                assert_eq!(field, "imds.connect_timeout_secs");
+                assert!(secs.is_nan());
+            }
+            result => panic!("expected an invalid timeout, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn connect_timeout_clamped_to_total_timeout() {
+        let mut config = Config::default();
+        config.imds.connect_timeout_secs = 10.0;
+        config.imds.total_timeout_secs = 2.5;
+        assert!(config.validate().is_ok());
+        assert_eq!(config.imds.connect_timeout(), Duration::from_secs_f64(2.5));
+        assert_eq!(config.imds.total_timeout(), Duration::from_secs_f64(2.5));
+
+        config.imds.total_timeout_secs = 12.5;
+        assert_eq!(config.imds.connect_timeout(), Duration::from_secs(10));
+        assert_eq!(config.imds.total_timeout(), Duration::from_secs_f64(12.5));
+    }
+
+    #[test]
+    fn default_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        assert_eq!(
+            Config::load_from(&base, None, None)
+                .unwrap()
+                .user_provisioners
+                .default_shell,
+            None
+        );
+
+        let mut file = std::fs::File::create(&base).unwrap();
+        writeln!(file, "[user_provisioners]\ndefault_shell = \"/bin/bash\"")
+            .unwrap();
+        let config = Config::load_from(&base, None, None).unwrap();
+        assert_eq!(
+            config.user_provisioners.default_shell,
+            Some(PathBuf::from("/bin/bash"))
+        );
+    }
+
+    #[test]
+    fn non_default_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Config::default().non_default().is_empty());
+
+        let base = dir.path().join("base.toml");
+        let mut file = std::fs::File::create(&base).unwrap();
+        writeln!(file, "[imds]\nendpoint = \"http://127.0.0.1:1\"").unwrap();
+        // Explicitly setting a default value isn't a difference.
+        let wireserver = goalstate::DEFAULT_WIRESERVER_URL;
+        writeln!(file, "[wireserver]\nendpoint = \"{wireserver}\"").unwrap();
+        let extra = dir.path().join("extra.toml");
+        let mut file = std::fs::File::create(&extra).unwrap();
+        writeln!(file, "[user_provisioners]\ndefault_shell = \"/bin/zsh\"")
+            .unwrap();
+
+        let config = Config::load_from(&base, None, Some(&extra)).unwrap();
+        let diff = config.non_default();
+        assert_eq!(
+            diff.keys().collect::<Vec<_>>(),
+            vec!["imds.endpoint", "user_provisioners.default_shell"]
+        );
+        assert_eq!(diff["imds.endpoint"], "http://127.0.0.1:1");
+        assert_eq!(diff["user_provisioners.default_shell"], "/bin/zsh");
+    }
+
+    #[test]
+    fn schema_sections() {
+        let schema = schema();
+        let properties = schema["properties"].as_object().unwrap();
+        for section in [
+            "imds",
+            "wireserver",
+            "user_provisioners",
+            "selinux",
+            "machine_info",
+            "provisioning_media",
+            "hooks",
+            "post_provision",
+            "ssh",
+            "hostname_provisioners",
+            "system",
+            "conflict_detection",
+            "container",
+            "http",
+            "azure_init_log_path",
+            "telemetry",
+            "security",
+            "custom_data",
+            "azure_proxy_agent",
+            "users",
+        ] {
+            assert!(properties.contains_key(section), "missing {section}");
+        }
+
+        let imds = &schema["$defs"]["Imds"]["properties"];
+        assert!(imds["endpoint"]["description"].is_string());
+        assert_eq!(imds["endpoint"]["default"], imds::DEFAULT_IMDS_URL);
+    }
+
+    #[test]
+    fn missing_caller_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = Config::load_from(
+            &dir.path().join("missing.toml"),
+            None,
+            Some(&dir.path().join("also-missing.toml")),
+        );
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn drop_in_merge_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |path: &Path, contents: &str| {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        let base = dir.path().join("azure-init.toml");
+        write(
+            &base,
+            "[imds]\nendpoint = \"http://127.0.0.1:1\"\n\
+             [wireserver]\nendpoint = \"http://127.0.0.1:1\"\n\
+             [user_provisioners]\ndefault_shell = \"/bin/base\"\n\
+             groups = [\"base\"]\n",
+        );
+        let base_dir = dir.path().join("azure-init.toml.d");
+        write(
+            &base_dir.join("10-first.toml"),
+            "[imds]\nendpoint = \"http://127.0.0.1:2\"\n\
+             [wireserver]\nendpoint = \"http://127.0.0.1:2\"\n\
+             [user_provisioners]\ndefault_shell = \"/bin/default-dir\"\n",
+        );
+        write(
+            &base_dir.join("20-second.toml"),
+            "[imds]\nendpoint = \"http://127.0.0.1:3\"\n",
+        );
+        // Only *.toml files are drop-ins.
+        write(&base_dir.join("30-ignored.bak"), "not toml");
+        let cli_dir = dir.path().join("cli.d");
+        write(
+            &cli_dir.join("10-cli.toml"),
+            "[imds]\nendpoint = \"http://127.0.0.1:4\"\n\
+             [user_provisioners]\ndefault_shell = \"/bin/cli-dir\"\n",
+        );
+        let cli_file = dir.path().join("cli.toml");
+        write(&cli_file, "[imds]\nendpoint = \"http://127.0.0.1:5\"\n");
+
+        let config = Config::load_from(&base, None, None).unwrap();
+        assert_eq!(config.imds.endpoint, "http://127.0.0.1:3");
+        assert_eq!(config.wireserver.endpoint, "http://127.0.0.1:2");
+        assert_eq!(
+            config.user_provisioners.default_shell,
+            Some(PathBuf::from("/bin/default-dir"))
+        );
+
+        let config =
+            Config::load_from(&base, Some(&cli_dir), Some(&cli_file)).unwrap();
+        assert_eq!(config.imds.endpoint, "http://127.0.0.1:5");
+        assert_eq!(config.wireserver.endpoint, "http://127.0.0.1:2");
+        assert_eq!(
+            config.user_provisioners.default_shell,
+            Some(PathBuf::from("/bin/cli-dir"))
+        );
+        assert_eq!(config.user_provisioners.groups, vec!["base"]);
+
+        let (_, sources) = Config::load_from_with_sources(
+            &base,
+            Some(&cli_dir),
+            Some(&cli_file),
+        )
+        .unwrap();
+        let mut expected = vec![base.clone()];
+        expected.extend(drop_ins(&base_dir).unwrap());
+        expected.push(cli_dir.join("10-cli.toml"));
+        expected.push(cli_file.clone());
+        assert_eq!(sources, expected);
+        assert!(!sources.contains(&base_dir.join("30-ignored.bak")));
+    }
+
+    #[test]
+    fn users_from_drop_ins() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("azure-init.toml");
+        std::fs::write(&base, "[[users]]\nname = \"from-config\"\n").unwrap();
+        let users_dir = dir.path().join("users.d");
+        std::fs::create_dir(&users_dir).unwrap();
+        std::fs::write(
+            users_dir.join("20-backup.toml"),
+            "name = \"backup\"\nshell = \"/bin/sh\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            users_dir.join("10-operator.toml"),
+            "name = \"operator\"\n\
+             groups = [\"adm\", \"wheel\"]\n\
+             ssh_authorized_keys = [\"ssh-ed25519 operator_key\"]\n\
+             sudo = true\n",
+        )
+        .unwrap();
+        std::fs::write(users_dir.join("README"), "not a user").unwrap();
+
+        let (config, sources) =
+            Config::load_from_with_sources(&base, None, None).unwrap();
+        let names: Vec<&str> =
+            config.users.iter().map(|user| user.name.as_str()).collect();
+        assert_eq!(names, vec!["from-config", "operator", "backup"]);
+        assert_eq!(
+            config.users[1],
+            ExtraUser {
+                name: "operator".to_string(),
+                groups: vec!["adm".to_string(), "wheel".to_string()],
+                ssh_authorized_keys: vec![
+                    "ssh-ed25519 operator_key".to_string()
+                ],
+                shell: None,
+                sudo: true,
+            }
+        );
+        assert_eq!(config.users[2].shell, Some(PathBuf::from("/bin/sh")));
+        assert!(!config.users[2].sudo);
+        assert_eq!(sources[1], users_dir.join("10-operator.toml"));
+    }
+
+    #[test]
+    fn duplicate_users_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("azure-init.toml");
+        std::fs::write(&base, "[[users]]\nname = \"operator\"\n").unwrap();
+        let users_dir = dir.path().join("users.d");
+        std::fs::create_dir(&users_dir).unwrap();
+        std::fs::write(
+            users_dir.join("operator.toml"),
+            "name = \"operator\"\n",
+        )
+        .unwrap();
+
+        let result = Config::load_from(&base, None, None);
+        assert!(matches!(result, Err(Error::DuplicateUser { .. })));
+
+        // A user file without a name is an error, not a nameless user.
+        std::fs::write(users_dir.join("operator.toml"), "sudo = true\n")
+            .unwrap();
+        assert!(Config::load_from(&base, None, None).is_err());
+    }
+
+    #[test]
+    fn sources_only_loaded_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, sources) = Config::load_from_with_sources(
+            &dir.path().join("missing.toml"),
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(sources.is_empty());
+    }
+
+    #[test]
+    fn config_file_and_dir_not_interchangeable() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("missing.toml");
+        let file = dir.path().join("file.toml");
+        std::fs::write(&file, "").unwrap();
+
+        let result = Config::load_from(&base, None, Some(dir.path()));
+        assert!(matches!(result, Err(Error::Io(_))));
+        let result = Config::load_from(&base, Some(&file), None);
+        assert!(matches!(result, Err(Error::Io(_))));
+        let result =
+            Config::load_from(&base, Some(&dir.path().join("missing.d")), None);
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn invalid_endpoint_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        let mut file = std::fs::File::create(&base).unwrap();
+        writeln!(file, "[imds]\nendpoint = \"not a url\"").unwrap();
+
+        let result = Config::load_from(&base, None, None);
+        assert!(matches!(result, Err(Error::InvalidUrl { .. })));
+    }
+
+    #[test]
+    fn health_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        assert_eq!(
+            Config::load_from(&base, None, None)
+                .unwrap()
+                .wireserver
+                .health_format,
+            HealthFormat::Xml
+        );
+
+        std::fs::write(&base, "[wireserver]\nhealth_format = \"json\"\n")
+            .unwrap();
+        let config = Config::load_from(&base, None, None).unwrap();
+        assert_eq!(config.wireserver.health_format, HealthFormat::Json);
+    }
+
+    #[test]
+    fn invalid_redact_pattern() {
+        let mut config = Config::default();
+        config.telemetry.redact_patterns = vec!["sig=(".to_string()];
+        assert!(matches!(
+            config.validate(),
+            Err(Error::InvalidRedactPattern { .. })
+        ));
+    }
+
+    #[test]
+    fn file_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        std::fs::write(
+            &base,
+            "[security]\nfile_mode = 0o400\ndir_mode = 0o500\n",
+        )
+        .unwrap();
+        let config = Config::load_from(&base, None, None).unwrap();
+        assert_eq!(config.security.file_mode, 0o400);
+        assert_eq!(config.security.dir_mode, 0o500);
+        assert!(config.validate().is_ok());
+
+        for security in [
+            Security {
+                file_mode: 0o644,
+                ..Default::default()
+            },
+            Security {
+                dir_mode: 0o755,
+                ..Default::default()
+            },
+        ] {
+            let config = Config {
+                security,
+                ..Default::default()
+            };
+            assert!(matches!(
+                config.validate(),
+                Err(Error::PermissiveMode { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn url_validation() {
+        assert!(validate_url("http://169.254.169.254").is_ok());
+        assert!(validate_url("https://localhost:8080/").is_ok());
+        assert!(validate_url("ftp://localhost").is_err());
+        assert!(validate_url("169.254.169.254").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Detecting a container, where provisioning a VM makes no sense.
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::rootfs::under;
+use crate::runner::{CommandRunner, SystemRunner};
+
+/// Created by Docker in the root of its containers.
+pub const PATH_DOCKERENV: &str = "/.dockerenv";
+
+/// Created by Podman in its containers.
+pub const PATH_CONTAINERENV: &str = "/run/.containerenv";
+
+/// Written by systemd, naming the container manager, when it runs in a
+/// container.
+pub const PATH_SYSTEMD_CONTAINER: &str = "/run/systemd/container";
+
+/// The cgroups of PID 1, which name the container runtime inside one.
+pub const PATH_INIT_CGROUP: &str = "/proc/1/cgroup";
+
+/// Container runtimes which show up in [`PATH_INIT_CGROUP`].
+const CGROUP_MARKERS: &[&str] =
+    &["docker", "kubepods", "containerd", "libpod", "lxc"];
+
+/// Whether azure-init is running in a container.
+pub fn is_container() -> bool {
+    is_container_in(Path::new("/"), &SystemRunner)
+}
+
+/// Whether the system rooted at `root` is a container: a container runtime
+/// left a sentinel file, PID 1's cgroup names one, or `systemd-detect-virt`
+/// says so.
+fn is_container_in(root: &Path, runner: &dyn CommandRunner) -> bool {
+    for sentinel in [PATH_DOCKERENV, PATH_CONTAINERENV, PATH_SYSTEMD_CONTAINER]
+    {
+        let path = under(root, sentinel);
+        if path.exists() {
+            tracing::info!(path = %path.display(), "Found a container");
+            return true;
+        }
+    }
+
+    if let Ok(cgroup) = std::fs::read_to_string(under(root, PATH_INIT_CGROUP)) {
+        if let Some(marker) = CGROUP_MARKERS
+            .iter()
+            .find(|marker| cgroup.contains(*marker))
+        {
+            tracing::info!(marker, "Found a container in PID 1's cgroup");
+            return true;
+        }
+    }
+
+    let path_systemd_detect_virt = env!("PATH_SYSTEMD_DETECT_VIRT");
+    let mut command = Command::new(path_systemd_detect_virt);
+    command.arg("--container");
+    match runner.output(&mut command) {
+        Ok(output) if output.status.success() => {
+            let virt = String::from_utf8_lossy(&output.stdout);
+            tracing::info!(
+                virt = virt.trim(),
+                "systemd-detect-virt found a container"
+            );
+            true
+        }
+        // It exits non-zero outside a container, and may not be installed.
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::runner::mock::{output, MockRunner};
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = under(root, path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn vm_is_not_container() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path(), PATH_INIT_CGROUP, "0::/init.scope\n");
+        let runner = MockRunner::failing("systemd-detect-virt", 1);
+        assert!(!is_container_in(root.path(), &runner));
+        assert_eq!(
+            runner.calls(),
+            vec![vec!["systemd-detect-virt", "--container"]]
+        );
+    }
+
+    #[test]
+    fn detected_by_sentinel() {
+        for sentinel in
+            [PATH_DOCKERENV, PATH_CONTAINERENV, PATH_SYSTEMD_CONTAINER]
+        {
+            let root = tempfile::tempdir().unwrap();
+            write(root.path(), sentinel, "");
+            let runner = MockRunner::failing("systemd-detect-virt", 1);
+            assert!(is_container_in(root.path(), &runner), "{sentinel}");
+            assert!(runner.calls().is_empty());
+        }
+    }
+
+    #[test]
+    fn detected_by_cgroup() {
+        let root = tempfile::tempdir().unwrap();
+        write(
+            root.path(),
+            PATH_INIT_CGROUP,
+            "0::/kubepods/besteffort/pod1234/abcdef\n",
+        );
+        let runner = MockRunner::failing("systemd-detect-virt", 1);
+        assert!(is_container_in(root.path(), &runner));
+    }
+
+    #[test]
+    fn detected_by_systemd_detect_virt() {
+        let root = tempfile::tempdir().unwrap();
+        let runner = MockRunner::new(|_| Ok(output(0, "systemd-nspawn\n")));
+        assert!(is_container_in(root.path(), &runner));
+
+        let runner =
+            MockRunner::new(|_| Err(std::io::ErrorKind::NotFound.into()));
+        assert!(!is_container_in(root.path(), &runner));
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Reading a minimal subset of cloud-init's cloud-config from the VM's
+//! custom data.
+//!
+//! Only `hostname`, `users` (their names and `ssh_authorized_keys`),
+//! `ssh_authorized_keys`, and `write_files` (`path`, `content`, `encoding`,
+//! `owner`, `permissions`, and `append`) are supported; anything else is
+//! ignored. This is not a replacement for cloud-init.
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::media::CloudConfigUser;
+
+/// The first line of custom data holding a cloud-config.
+const CLOUD_CONFIG_HEADER: &str = "#cloud-config";
+
+/// The supported subset of a cloud-config passed as custom data.
+#[derive(Debug, Default, Deserialize)]
+pub struct CustomData {
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    users: Vec<CloudConfigUser>,
+    #[serde(default)]
+    ssh_authorized_keys: Vec<String>,
+    #[serde(default)]
+    write_files: Vec<WriteFile>,
+}
+
+/// A file to write, as in cloud-config's `write_files`.
+#[derive(Debug, Deserialize)]
+struct WriteFile {
+    path: PathBuf,
+    #[serde(default)]
+    content: String,
+    /// How `content` is encoded: plain text, the default, or base64 (`b64`
+    /// or `base64`). Compressed content isn't supported.
+    #[serde(default)]
+    encoding: Option<String>,
+    /// The `user` or `user:group` to own the file, root by default.
+    #[serde(default)]
+    owner: Option<String>,
+    /// The mode, `0644` by default.
+    #[serde(default)]
+    permissions: Option<Mode>,
+    /// Add `content` to the end of the file rather than replacing it.
+    #[serde(default)]
+    append: bool,
+}
+
+/// A file's mode, as cloud-init reads it: a string is octal, e.g. `'0644'`,
+/// while a number is taken as is, e.g. `0o644`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Mode {
+    Number(u32),
+    Text(String),
+}
+
+impl Mode {
+    fn bits(&self) -> Result<u32, Error> {
+        let bits = match self {
+            Mode::Number(bits) => Some(*bits),
+            Mode::Text(text) => {
+                u32::from_str_radix(text.trim_start_matches("0o"), 8).ok()
+            }
+        };
+        bits.filter(|bits| *bits <= 0o7777).ok_or_else(|| {
+            Error::InvalidPermissions {
+                permissions: match self {
+                    Mode::Number(bits) => bits.to_string(),
+                    Mode::Text(text) => text.clone(),
+                },
+            }
+        })
+    }
+}
+
+impl WriteFile {
+    /// The bytes to write, decoded as `encoding` says.
+    fn decoded_content(&self) -> Result<Vec<u8>, Error> {
+        match self.encoding.as_deref() {
+            None | Some("text/plain") => Ok(self.content.clone().into_bytes()),
+            Some("b64" | "base64") => {
+                Ok(base64::engine::general_purpose::STANDARD
+                    .decode(self.content.trim())?)
+            }
+            Some(encoding) => Err(Error::UnsupportedEncoding {
+                encoding: encoding.to_string(),
+            }),
+        }
+    }
+
+    /// The user and group to own the file.
+    fn owner(&self) -> Result<(nix::unistd::Uid, nix::unistd::Gid), Error> {
+        let owner = self.owner.as_deref().unwrap_or("root:root");
+        let (user, group) = match owner.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (owner, None),
+        };
+        let user =
+            nix::unistd::User::from_name(user)?.ok_or(Error::UserMissing {
+                user: user.to_string(),
+            })?;
+        let gid = match group {
+            Some(group) => {
+                nix::unistd::Group::from_name(group)?
+                    .ok_or(Error::GroupMissing {
+                        group: group.to_string(),
+                    })?
+                    .gid
+            }
+            None => user.gid,
+        };
+
+        Ok((user.uid, gid))
+    }
+}
+
+/// Decode the base64-encoded `custom_data` and parse it as a cloud-config.
+///
+/// Returns `None` if the custom data isn't a cloud-config, e.g. a script
+/// meant for something else.
+pub fn decode(custom_data: &str) -> Result<Option<CustomData>, Error> {
+    let decoded =
+        base64::engine::general_purpose::STANDARD.decode(custom_data.trim())?;
+    let decoded = String::from_utf8_lossy(&decoded);
+    if !decoded.starts_with(CLOUD_CONFIG_HEADER) {
+        tracing::info!("The custom data is not a cloud-config, ignoring it");
+        return Ok(None);
+    }
+
+    Ok(Some(serde_yaml::from_str(&decoded)?))
+}
+
+impl CustomData {
+    /// The hostname to set, if any.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    /// The keys to authorize for `username`: those of the user of that name
+    /// and the top-level `ssh_authorized_keys`.
+    ///
+    /// Users with other names aren't provisioned, so their keys are skipped.
+    pub fn authorized_keys(&self, username: &str) -> Vec<String> {
+        let mut keys = vec![];
+        for user in &self.users {
+            match user {
+                CloudConfigUser::User {
+                    name,
+                    ssh_authorized_keys,
+                } if name == username => {
+                    keys.extend(ssh_authorized_keys.iter().cloned())
+                }
+                CloudConfigUser::User { name, .. }
+                | CloudConfigUser::Name(name) => tracing::warn!(
+                    %name,
+                    "Only the provisioned user is supported, skipping"
+                ),
+            }
+        }
+        keys.extend(self.ssh_authorized_keys.iter().cloned());
+        keys
+    }
+
+    /// Write each of the `write_files`, creating their directories.
+    ///
+    /// A file is only written once its content, owner, and mode are known
+    /// to be valid.
+    pub fn write_files(&self) -> Result<(), Error> {
+        for file in &self.write_files {
+            let mode = match &file.permissions {
+                Some(permissions) => permissions.bits()?,
+                None => 0o644,
+            };
+            let content = file.decoded_content()?;
+            let (uid, gid) = file.owner()?;
+
+            tracing::info!(path = %file.path.display(), "Writing file from custom data");
+            if let Some(dir) = file.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(file.append)
+                .truncate(!file.append)
+                .open(&file.path)?
+                .write_all(&content)?;
+            nix::unistd::chown(&file.path, Some(uid), Some(gid))?;
+            fs::set_permissions(&file.path, fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(custom_data: &str) -> String {
+        base64::engine::general_purpose::STANDARD.encode(custom_data)
+    }
+
+    #[test]
+    fn cloud_config_parsed() {
+        let custom_data = encode(
+            r#"#cloud-config
+hostname: custom-host
+users:
+  - default
+  - name: azureuser
+    ssh_authorized_keys:
+      - ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHN1 azureuser@host
+  - name: otheruser
+    ssh_authorized_keys:
+      - ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHN2 otheruser@host
+ssh_authorized_keys:
+  - ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ shared@host
+runcmd:
+  - echo unsupported
+"#,
+        );
+
+        let custom_data = decode(&custom_data).unwrap().unwrap();
+        assert_eq!(custom_data.hostname(), Some("custom-host"));
+        assert_eq!(
+            custom_data.authorized_keys("azureuser"),
+            vec![
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHN1 azureuser@host",
+                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ shared@host",
+            ]
+        );
+    }
+
+    #[test]
+    fn other_custom_data_ignored() {
+        assert!(decode(&encode("#!/bin/sh\necho hello\n"))
+            .unwrap()
+            .is_none());
+        assert!(matches!(decode("not base64!"), Err(Error::Base64(_))));
+    }
+
+    #[test]
+    fn files_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let motd = dir.path().join("etc/motd");
+        let secret = dir.path().join("etc/app/secret");
+        let custom_data = encode(&format!(
+            r#"#cloud-config
+write_files:
+  - path: {}
+    content: |
+      Welcome
+  - path: {}
+    content: hunter2
+    permissions: '0600'
+"#,
+            motd.display(),
+            secret.display()
+        ));
+
+        decode(&custom_data)
+            .unwrap()
+            .unwrap()
+            .write_files()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&motd).unwrap(), "Welcome\n");
+        let mode = fs::metadata(&motd).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "hunter2");
+        let mode = fs::metadata(&secret).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn base64_content_decoded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("binary");
+        let custom_data = encode(&format!(
+            "#cloud-config\nwrite_files:\n  - path: {}\n    encoding: b64\n    \
+             content: {}\n",
+            path.display(),
+            encode("hello\0world")
+        ));
+
+        decode(&custom_data)
+            .unwrap()
+            .unwrap()
+            .write_files()
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello\0world");
+    }
+
+    #[test]
+    fn unsupported_encoding_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let custom_data = encode(&format!(
+            "#cloud-config\nwrite_files:\n  - path: {}\n    encoding: gzip\n",
+            path.display()
+        ));
+
+        let result = decode(&custom_data).unwrap().unwrap().write_files();
+        assert!(matches!(result, Err(Error::UnsupportedEncoding { .. })));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unquoted_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let leading_zero = dir.path().join("leading_zero");
+        let octal = dir.path().join("octal");
+        let custom_data = encode(&format!(
+            "#cloud-config\nwrite_files:\n  - path: {}\n    permissions: 0600\n  \
+             - path: {}\n    permissions: 0o640\n",
+            leading_zero.display(),
+            octal.display()
+        ));
+
+        decode(&custom_data)
+            .unwrap()
+            .unwrap()
+            .write_files()
+            .unwrap();
+        let mode = fs::metadata(&leading_zero).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let mode = fs::metadata(&octal).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn owner_and_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        fs::write(&path, "first\n").unwrap();
+        let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let custom_data = encode(&format!(
+            "#cloud-config\nwrite_files:\n  - path: {}\n    content: second\n    \
+             append: true\n    owner: {}\n",
+            path.display(),
+            current.name
+        ));
+
+        decode(&custom_data)
+            .unwrap()
+            .unwrap()
+            .write_files()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond");
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::uid(&metadata),
+            current.uid.as_raw()
+        );
+
+        let custom_data = encode(&format!(
+            "#cloud-config\nwrite_files:\n  - path: {}\n    owner: nosuchuser\n",
+            path.display()
+        ));
+        let result = decode(&custom_data).unwrap().unwrap().write_files();
+        assert!(matches!(result, Err(Error::UserMissing { .. })));
+    }
+
+    #[test]
+    fn invalid_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let custom_data = encode(&format!(
+            "#cloud-config\nwrite_files:\n  - path: {}\n    permissions: rw\n",
+            dir.path().join("file").display()
+        ));
+
+        let result = decode(&custom_data).unwrap().unwrap().write_files();
+        assert!(matches!(result, Err(Error::InvalidPermissions { .. })));
+    }
+}
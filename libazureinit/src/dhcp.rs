@@ -0,0 +1,144 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Reading the hostname offered by the DHCP server, as a last resort when
+//! the provisioning metadata has none.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where `dhclient` keeps its `*.leases` files.
+pub const PATH_DHCLIENT_LEASES: &str = "/var/lib/dhcp";
+
+/// Where `systemd-networkd` keeps its leases, one file per interface.
+pub const PATH_NETWORKD_LEASES: &str = "/run/systemd/netif/leases";
+
+/// The hostname from the first lease in [`PATH_DHCLIENT_LEASES`] or
+/// [`PATH_NETWORKD_LEASES`] that has one, or `None` if there is none.
+pub fn lease_hostname() -> Option<String> {
+    lease_hostname_in(
+        Path::new(PATH_DHCLIENT_LEASES),
+        Path::new(PATH_NETWORKD_LEASES),
+    )
+}
+
+fn lease_hostname_in(dhclient: &Path, networkd: &Path) -> Option<String> {
+    let dhclient_leases = lease_files(dhclient)
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "leases"));
+    dhclient_leases
+        .chain(lease_files(networkd))
+        .find_map(|path| {
+            let contents = fs::read_to_string(&path).ok()?;
+            let hostname = parse_lease_hostname(&contents)?;
+            tracing::info!(
+                path = %path.display(),
+                hostname,
+                "Found a hostname in a DHCP lease"
+            );
+            Some(hostname)
+        })
+}
+
+/// The files in `dir`, in order of name. A missing or unreadable directory
+/// has none.
+fn lease_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+    files
+}
+
+/// The hostname in a `dhclient` lease file, from its last `option
+/// host-name`, or in a `systemd-networkd` lease, from its `HOSTNAME=` line.
+pub fn parse_lease_hostname(contents: &str) -> Option<String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let value = match line.strip_prefix("option host-name ") {
+                Some(value) => value.trim_end_matches(';').trim_matches('"'),
+                None => line.strip_prefix("HOSTNAME=")?,
+            };
+            Some(value.trim())
+        })
+        .rfind(|hostname| !hostname.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DHCLIENT_LEASES: &str = r#"lease {
+  interface "eth0";
+  fixed-address 10.0.0.4;
+  option subnet-mask 255.255.255.0;
+  option host-name "old-host";
+  option domain-name "reddog.microsoft.com";
+  renew 2 2026/10/13 08:00:00;
+}
+lease {
+  interface "eth0";
+  fixed-address 10.0.0.4;
+  option host-name "lease-host";
+  option domain-name "reddog.microsoft.com";
+  renew 5 2026/10/16 08:00:00;
+}
+"#;
+
+    const NETWORKD_LEASE: &str = "# This is private data. Do not parse.
+ADDRESS=10.0.0.4
+NETMASK=255.255.255.0
+HOSTNAME=networkd-host
+DOMAINNAME=reddog.microsoft.com
+";
+
+    #[test]
+    fn hostname_parsed() {
+        assert_eq!(
+            parse_lease_hostname(DHCLIENT_LEASES).as_deref(),
+            Some("lease-host")
+        );
+        assert_eq!(
+            parse_lease_hostname(NETWORKD_LEASE).as_deref(),
+            Some("networkd-host")
+        );
+        assert_eq!(parse_lease_hostname("lease {\n}\n"), None);
+    }
+
+    #[test]
+    fn hostname_read_from_lease_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let dhcp = dir.path().join("dhcp");
+        fs::create_dir(&dhcp).unwrap();
+        fs::write(dhcp.join("dhclient.eth0.leases"), DHCLIENT_LEASES).unwrap();
+        fs::write(dhcp.join("README"), "HOSTNAME=not-a-lease\n").unwrap();
+        let networkd = dir.path().join("leases");
+        fs::create_dir(&networkd).unwrap();
+        fs::write(networkd.join("2"), NETWORKD_LEASE).unwrap();
+
+        assert_eq!(
+            lease_hostname_in(&dhcp, &networkd).as_deref(),
+            Some("lease-host")
+        );
+        assert_eq!(
+            lease_hostname_in(&dir.path().join("missing"), &networkd)
+                .as_deref(),
+            Some("networkd-host")
+        );
+    }
+
+    #[test]
+    fn no_lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        assert_eq!(lease_hostname_in(dir.path(), &missing), None);
+    }
+}
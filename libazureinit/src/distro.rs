@@ -1,51 +1,274 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use std::fs;
+use std::path::Path;
 use std::process::Command;
 
 use crate::error::Error;
+use crate::runner::{redacted_argv, run, run_with_stdin, CommandRunner};
+use crate::user::User;
 
-pub fn create_user_with_useradd(username: &str) -> Result<i32, Error> {
+/// The target of the event logged before each backend runs its command.
+const COMMAND_TARGET: &str = "libazureinit::distro::command";
+
+/// Log the command line `backend` is about to run, with any password
+/// redacted, so it's clear exactly what was attempted.
+fn log_command(backend: &str, command: &Command) {
+    tracing::debug!(
+        target: COMMAND_TARGET,
+        backend,
+        argv = ?redacted_argv(command),
+        "Running provisioning command"
+    );
+}
+
+/// The file holding the static hostname.
+pub const PATH_ETC_HOSTNAME: &str = "/etc/hostname";
+
+/// A method of setting the VM's hostname.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostnameProvisioner {
+    /// Use `hostnamectl`, which requires systemd-hostnamed.
+    Hostnamectl,
+    /// Use the `hostname` command and write [`PATH_ETC_HOSTNAME`] so the
+    /// name persists across reboots.
+    Hostname,
+    /// Call `sethostname(2)` directly, for minimal images without either
+    /// command, and write [`PATH_ETC_HOSTNAME`].
+    Sethostname,
+    #[cfg(test)]
+    FakeFailure,
+    #[cfg(test)]
+    FakeSuccess,
+}
+
+/// The hostname backends tried, in order, by default.
+pub const DEFAULT_HOSTNAME_PROVISIONERS: &[HostnameProvisioner] = &[
+    HostnameProvisioner::Hostnamectl,
+    HostnameProvisioner::Hostname,
+    HostnameProvisioner::Sethostname,
+];
+
+impl HostnameProvisioner {
+    pub fn set(
+        &self,
+        runner: &dyn CommandRunner,
+        hostname: &str,
+    ) -> Result<(), Error> {
+        self.set_at(runner, hostname, Path::new(PATH_ETC_HOSTNAME))
+    }
+
+    /// Set the hostname, writing `etc_hostname` rather than
+    /// [`PATH_ETC_HOSTNAME`] where the backend writes one.
+    pub fn set_at(
+        &self,
+        runner: &dyn CommandRunner,
+        hostname: &str,
+        etc_hostname: &Path,
+    ) -> Result<(), Error> {
+        match self {
+            Self::Hostnamectl => {
+                set_hostname_with_hostnamectl(runner, hostname).map(|_| ())
+            }
+            Self::Hostname => {
+                hostname_with_etc_hostname(runner, hostname, etc_hostname)
+            }
+            Self::Sethostname => {
+                sethostname_with_etc_hostname(hostname, etc_hostname)
+            }
+            #[cfg(test)]
+            Self::FakeFailure => Err(Error::NoHostnameProvisioner),
+            #[cfg(test)]
+            Self::FakeSuccess => Ok(()),
+        }
+    }
+}
+
+/// Set the hostname with the first of `backends` that succeeds, returning the
+/// backend that was used.
+pub fn set_hostname(
+    runner: &dyn CommandRunner,
+    hostname: &str,
+    backends: &[HostnameProvisioner],
+) -> Result<HostnameProvisioner, Error> {
+    set_hostname_at(runner, hostname, backends, Path::new(PATH_ETC_HOSTNAME))
+}
+
+/// Like [`set_hostname`], writing `etc_hostname` rather than
+/// [`PATH_ETC_HOSTNAME`].
+pub fn set_hostname_at(
+    runner: &dyn CommandRunner,
+    hostname: &str,
+    backends: &[HostnameProvisioner],
+    etc_hostname: &Path,
+) -> Result<HostnameProvisioner, Error> {
+    backends.iter().find_map(|backend| {
+        match backend.set_at(runner, hostname, etc_hostname) {
+            Ok(()) => {
+                tracing::info!(?backend, hostname, "Set hostname");
+                Some(*backend)
+            }
+            Err(e) => {
+                tracing::warn!(?backend, error = ?e, "Failed to set hostname");
+                None
+            }
+        }
+    })
+    .ok_or(Error::NoHostnameProvisioner)
+}
+
+/// The groups the admin user is created in.
+pub const ADMIN_GROUPS: &str =
+    "adm,audio,cdrom,dialout,dip,floppy,lxd,netdev,plugdev,sudo,video";
+
+pub fn create_user_with_useradd(
+    runner: &dyn CommandRunner,
+    user: &User,
+) -> Result<i32, Error> {
     let path_useradd = env!("PATH_USERADD");
-    let home_path = format!("/home/{username}");
-
-    let status = Command::new(path_useradd)
-                    .arg(username)
-                    .arg("--comment")
-                    .arg(
-                      "Provisioning agent created this user based on username provided in IMDS",
-                    )
-                    .arg("--groups")
-                    .arg("adm,audio,cdrom,dialout,dip,floppy,lxd,netdev,plugdev,sudo,video")
-                    .arg("-d")
-                    .arg(home_path.clone())
-                    .arg("-m")
-                    .status()?;
-    if !status.success() {
-        return Err(Error::SubprocessFailed {
-            command: path_useradd.to_string(),
-            status,
-        });
+
+    user.validate_shell()?;
+
+    let mut command = Command::new(path_useradd);
+    command.arg(&user.name).arg("--comment");
+    if user.admin {
+        command
+            .arg(
+                "Provisioning agent created this user based on username provided in IMDS",
+            )
+            .arg("--groups")
+            .arg(ADMIN_GROUPS);
+    } else {
+        command
+            .arg("Provisioning agent created this user from its configuration");
     }
+    command.arg("-d").arg(user.home()).arg(if user.create_home {
+        "-m"
+    } else {
+        "-M"
+    });
+    if user.system {
+        command.arg("--system");
+    }
+    if let Some(shell) = &user.shell {
+        command.arg("--shell").arg(shell);
+    }
+    log_command("useradd", &command);
+    run(runner, command)?;
 
     Ok(0)
 }
 
+/// Create `user` with `useradd`, unless it already exists, e.g. because it
+/// was baked into the image or this is a re-provision.
+///
+/// An existing user is left alone, except that its login shell is set with
+/// `usermod` if `update_existing` is set and it differs from `user`'s.
+/// Groups are reconciled separately, whether or not the user already existed.
+pub fn create_or_update_user(
+    runner: &dyn CommandRunner,
+    user: &User,
+    update_existing: bool,
+) -> Result<(), Error> {
+    let Some(existing) = nix::unistd::User::from_name(&user.name)? else {
+        return create_user_with_useradd(runner, user).map(|_| ());
+    };
+
+    tracing::info!(user = user.name, "User already exists, not creating it");
+    match &user.shell {
+        Some(shell) if update_existing && *shell != existing.shell => {
+            user.validate_shell()?;
+            tracing::info!(
+                user = user.name,
+                shell = %shell.display(),
+                "Updating the login shell of the existing user"
+            );
+            let mut command = Command::new(env!("PATH_USERMOD"));
+            command.arg("--shell").arg(shell).arg(&user.name);
+            run(runner, command)?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// A method of setting the user's password.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasswordProvisioner {
+    /// Use `passwd`.
+    Passwd,
+    /// Use `chpasswd`, which reads `user:password` lines from stdin and so
+    /// never prompts.
+    Chpasswd,
+    #[cfg(test)]
+    FakeFailure,
+}
+
+/// The password backends tried, in order, by default.
+pub const DEFAULT_PASSWORD_PROVISIONERS: &[PasswordProvisioner] =
+    &[PasswordProvisioner::Passwd, PasswordProvisioner::Chpasswd];
+
+impl PasswordProvisioner {
+    pub fn set(
+        &self,
+        runner: &dyn CommandRunner,
+        username: &str,
+        password: &str,
+    ) -> Result<(), Error> {
+        match self {
+            Self::Passwd => {
+                set_password_with_passwd(runner, username, password).map(|_| ())
+            }
+            Self::Chpasswd => {
+                set_password_with_chpasswd(runner, username, password)
+            }
+            #[cfg(test)]
+            Self::FakeFailure => Err(Error::NoPasswordProvisioner),
+        }
+    }
+}
+
+/// Set the password with the first of `backends` that succeeds, returning
+/// the backend that was used.
+pub fn set_password(
+    runner: &dyn CommandRunner,
+    username: &str,
+    password: &str,
+    backends: &[PasswordProvisioner],
+) -> Result<PasswordProvisioner, Error> {
+    if !password.is_empty() {
+        return Err(Error::NonEmptyPassword);
+    }
+
+    backends
+        .iter()
+        .find_map(|backend| match backend.set(runner, username, password) {
+            Ok(()) => {
+                tracing::info!(?backend, user = username, "Set password");
+                Some(*backend)
+            }
+            Err(e) => {
+                tracing::warn!(?backend, error = ?e, "Failed to set password");
+                None
+            }
+        })
+        .ok_or(Error::NoPasswordProvisioner)
+}
+
 pub fn set_password_with_passwd(
+    runner: &dyn CommandRunner,
     username: &str,
     password: &str,
 ) -> Result<i32, Error> {
     let path_passwd = env!("PATH_PASSWD");
 
     if password.is_empty() {
-        let status =
-            Command::new(path_passwd).arg("-d").arg(username).status()?;
-        if !status.success() {
-            return Err(Error::SubprocessFailed {
-                command: path_passwd.to_string(),
-                status,
-            });
-        }
+        let mut command = Command::new(path_passwd);
+        command.arg("-d").arg(username);
+        log_command("passwd", &command);
+        run(runner, command)?;
     } else {
         // creating user with a non-empty password is not allowed.
         return Err(Error::NonEmptyPassword);
@@ -54,19 +277,641 @@ pub fn set_password_with_passwd(
     Ok(0)
 }
 
-pub fn set_hostname_with_hostnamectl(hostname: &str) -> Result<i32, Error> {
+/// Set the password with `chpasswd`.
+///
+/// Only an empty password is supported, given as an empty encrypted
+/// password, which is equivalent to `passwd -d`.
+pub fn set_password_with_chpasswd(
+    runner: &dyn CommandRunner,
+    username: &str,
+    password: &str,
+) -> Result<(), Error> {
+    if !password.is_empty() {
+        return Err(Error::NonEmptyPassword);
+    }
+
+    let mut command = Command::new(env!("PATH_CHPASSWD"));
+    command.arg("-e");
+    // The input, which holds the password, isn't logged.
+    log_command("chpasswd", &command);
+    run_with_stdin(runner, command, chpasswd_input(username).as_bytes())?;
+
+    Ok(())
+}
+
+fn chpasswd_input(username: &str) -> String {
+    format!("{username}:\n")
+}
+
+/// Add `username` to each of `groups` it isn't already a member of, returning
+/// the groups it was added to.
+///
+/// Groups which don't exist yet are skipped with a warning, so running this
+/// again once they've been created adds the user to them.
+pub fn reconcile_groups_with_usermod(
+    runner: &dyn CommandRunner,
+    username: &str,
+    groups: &[String],
+) -> Result<Vec<String>, Error> {
+    if groups.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut command = Command::new(env!("PATH_ID"));
+    command.arg("-nG").arg(username);
+    let output = run(runner, command)?;
+    let current = String::from_utf8_lossy(&output.stdout).into_owned();
+    let current: Vec<&str> = current.split_whitespace().collect();
+
+    let mut missing: Vec<String> = vec![];
+    for group in groups {
+        if current.contains(&group.as_str()) || missing.contains(group) {
+            continue;
+        }
+        let mut command = Command::new(env!("PATH_GETENT"));
+        command.arg("group").arg(group);
+        if runner.output(&mut command)?.status.success() {
+            missing.push(group.clone());
+        } else {
+            tracing::warn!(%group, username, "Group does not exist yet");
+        }
+    }
+
+    if !missing.is_empty() {
+        let mut command = Command::new(env!("PATH_USERMOD"));
+        command.arg("-aG").arg(missing.join(",")).arg(username);
+        run(runner, command)?;
+    }
+
+    Ok(missing)
+}
+
+pub fn set_hostname_with_hostnamectl(
+    runner: &dyn CommandRunner,
+    hostname: &str,
+) -> Result<i32, Error> {
     let path_hostnamectl = env!("PATH_HOSTNAMECTL");
 
-    let status = Command::new(path_hostnamectl)
-        .arg("set-hostname")
-        .arg(hostname)
-        .status()?;
-    if status.success() {
-        Ok(status.code().unwrap_or(1))
-    } else {
-        Err(Error::SubprocessFailed {
-            command: path_hostnamectl.to_string(),
-            status,
+    let mut command = Command::new(path_hostnamectl);
+    command.arg("set-hostname").arg(hostname);
+    log_command("hostnamectl", &command);
+    let output = run(runner, command)?;
+
+    Ok(output.status.code().unwrap_or(1))
+}
+
+pub fn set_hostname_with_hostname(
+    runner: &dyn CommandRunner,
+    hostname: &str,
+) -> Result<i32, Error> {
+    hostname_with_etc_hostname(runner, hostname, Path::new(PATH_ETC_HOSTNAME))?;
+
+    Ok(0)
+}
+
+fn hostname_with_etc_hostname(
+    runner: &dyn CommandRunner,
+    hostname: &str,
+    etc_hostname: &Path,
+) -> Result<(), Error> {
+    let path_hostname = env!("PATH_HOSTNAME");
+
+    let mut command = Command::new(path_hostname);
+    command.arg(hostname);
+    log_command("hostname", &command);
+    run(runner, command)?;
+    write_etc_hostname(hostname, etc_hostname)?;
+
+    Ok(())
+}
+
+fn sethostname_with_etc_hostname(
+    hostname: &str,
+    etc_hostname: &Path,
+) -> Result<(), Error> {
+    tracing::debug!(
+        target: COMMAND_TARGET,
+        backend = "sethostname",
+        hostname,
+        "Calling sethostname"
+    );
+    // The kernel accepts an empty name, which would blank the hostname.
+    if hostname.is_empty() {
+        return Err(Error::EmptyHostname);
+    }
+    nix::unistd::sethostname(hostname)?;
+    write_etc_hostname(hostname, etc_hostname)?;
+
+    Ok(())
+}
+
+/// Write `hostname` to `path`, unless it already holds it, returning whether
+/// it was written.
+pub(crate) fn write_etc_hostname(
+    hostname: &str,
+    path: &Path,
+) -> Result<bool, Error> {
+    match fs::read_to_string(path) {
+        Ok(current) if current.trim() == hostname => return Ok(false),
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+            return Err(error.into())
+        }
+        _ => {}
+    }
+    fs::write(path, format!("{hostname}\n"))?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::http::events::Events;
+    use crate::runner::mock::{output, MockRunner};
+
+    /// The backend and command line of each command event logged by `f`.
+    fn logged_commands(f: impl FnOnce()) -> Vec<(String, String)> {
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        tracing::subscriber::with_default(subscriber, f);
+
+        let events = events.0.lock().unwrap();
+        events
+            .iter()
+            .filter(|(target, _)| target == COMMAND_TARGET)
+            .map(|(_, fields)| {
+                let field = |name: &str| {
+                    fields
+                        .iter()
+                        .find(|(field, _)| field == name)
+                        .map(|(_, value)| value.clone())
+                        .unwrap()
+                };
+                (field("backend"), field("argv"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn backend_commands_logged() {
+        let runner = MockRunner::succeeding();
+        let user = User::new("azure-init-test-no-such-user")
+            .with_home("/home/azureuser");
+
+        let commands = logged_commands(|| {
+            create_user_with_useradd(&runner, &user).unwrap();
+            set_password_with_passwd(&runner, "azureuser", "").unwrap();
+            set_password_with_chpasswd(&runner, "azureuser", "").unwrap();
+            set_hostname_with_hostnamectl(&runner, "test-host").unwrap();
+        });
+
+        let backends: Vec<&str> = commands
+            .iter()
+            .map(|(backend, _)| backend.as_str())
+            .collect();
+        assert_eq!(backends, ["useradd", "passwd", "chpasswd", "hostnamectl"]);
+        for ((_, argv), call) in commands.iter().zip(runner.calls()) {
+            assert_eq!(*argv, format!("{call:?}"));
+        }
+        assert!(!commands[2].1.contains("azureuser:"));
+    }
+
+    #[test]
+    fn hostname_fallback_order() {
+        let backend = set_hostname(
+            &MockRunner::succeeding(),
+            "test-host",
+            &[
+                HostnameProvisioner::FakeFailure,
+                HostnameProvisioner::FakeSuccess,
+            ],
+        )
+        .unwrap();
+        assert_eq!(backend, HostnameProvisioner::FakeSuccess);
+
+        assert_eq!(
+            DEFAULT_HOSTNAME_PROVISIONERS,
+            &[
+                HostnameProvisioner::Hostnamectl,
+                HostnameProvisioner::Hostname,
+                HostnameProvisioner::Sethostname,
+            ]
+        );
+    }
+
+    #[test]
+    fn sethostname_is_last_resort() {
+        // The hostname is per UTS namespace, and unshare(2) moves only the
+        // calling thread into a new one, so this can't rename the machine
+        // running the tests. Without the privilege to, there's nothing to
+        // test.
+        std::thread::spawn(|| {
+            if unsafe { libc::unshare(libc::CLONE_NEWUTS) } != 0 {
+                eprintln!("Unable to unshare the UTS namespace, skipping");
+                return;
+            }
+            // Without either command, each command backend fails in turn.
+            let runner = MockRunner::new(|_| {
+                Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+            });
+            let dir = tempfile::tempdir().unwrap();
+            let etc_hostname = dir.path().join("hostname");
+
+            let backend = set_hostname_at(
+                &runner,
+                "test-host",
+                DEFAULT_HOSTNAME_PROVISIONERS,
+                &etc_hostname,
+            )
+            .unwrap();
+            assert_eq!(backend, HostnameProvisioner::Sethostname);
+            let programs: Vec<String> = runner
+                .calls()
+                .into_iter()
+                .map(|argv| argv[0].clone())
+                .collect();
+            assert_eq!(programs, ["hostnamectl", "hostname"]);
+            assert_eq!(nix::unistd::gethostname().unwrap(), "test-host");
+            assert_eq!(
+                fs::read_to_string(&etc_hostname).unwrap(),
+                "test-host\n"
+            );
         })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn sethostname_rejects_empty_hostname() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc_hostname = dir.path().join("hostname");
+
+        let result = HostnameProvisioner::Sethostname.set_at(
+            &MockRunner::succeeding(),
+            "",
+            &etc_hostname,
+        );
+        assert!(matches!(result, Err(Error::EmptyHostname)));
+        assert!(!etc_hostname.exists());
+    }
+
+    #[test]
+    fn sethostname_failure_leaves_etc_hostname() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc_hostname = dir.path().join("hostname");
+        // Longer than the kernel allows, so this fails even as root rather
+        // than renaming the machine running the tests.
+        let hostname = "h".repeat(65);
+
+        let result = HostnameProvisioner::Sethostname.set_at(
+            &MockRunner::succeeding(),
+            &hostname,
+            &etc_hostname,
+        );
+        assert!(matches!(result, Err(Error::Nix(_))));
+        assert!(!etc_hostname.exists());
+    }
+
+    #[test]
+    fn hostname_all_backends_fail() {
+        let result = set_hostname(
+            &MockRunner::succeeding(),
+            "test-host",
+            &[
+                HostnameProvisioner::FakeFailure,
+                HostnameProvisioner::FakeFailure,
+            ],
+        );
+        assert!(matches!(result, Err(Error::NoHostnameProvisioner)));
+
+        let result = set_hostname(&MockRunner::succeeding(), "test-host", &[]);
+        assert!(matches!(result, Err(Error::NoHostnameProvisioner)));
+    }
+
+    #[test]
+    fn etc_hostname_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hostname");
+        std::fs::write(&path, "old-name\n").unwrap();
+
+        assert!(write_etc_hostname("new-name", &path).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new-name\n");
+
+        // Already up to date, so left alone.
+        assert!(!write_etc_hostname("new-name", &path).unwrap());
+        assert!(write_etc_hostname("created", &dir.path().join("new")).unwrap());
+    }
+
+    fn current_user() -> nix::unistd::User {
+        nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn existing_user_not_created() {
+        let existing = current_user();
+        let runner = MockRunner::succeeding();
+
+        let user = User::new(&existing.name).with_shell("/bin/azure-init-sh");
+        create_or_update_user(&runner, &user, false).unwrap();
+        assert!(runner.calls().is_empty());
+
+        let user = User::new(&existing.name).with_shell(&existing.shell);
+        create_or_update_user(&runner, &user, true).unwrap();
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn existing_user_updated() {
+        let existing = current_user();
+        let runner = MockRunner::succeeding();
+
+        let user = User::new(&existing.name).with_shell("/bin/azure-init-sh");
+        create_or_update_user(&runner, &user, true).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![vec![
+                "usermod",
+                "--shell",
+                "/bin/azure-init-sh",
+                &existing.name
+            ]]
+        );
+    }
+
+    #[test]
+    fn missing_user_created() {
+        let runner = MockRunner::succeeding();
+        let user = User::new("azure-init-test-no-such-user");
+
+        create_or_update_user(&runner, &user, true).unwrap();
+        assert_eq!(runner.calls()[0][0], "useradd");
+    }
+
+    #[test]
+    fn useradd_argv() {
+        let runner = MockRunner::succeeding();
+        create_user_with_useradd(&runner, &User::new("azureuser")).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![vec![
+                "useradd",
+                "azureuser",
+                "--comment",
+                "Provisioning agent created this user based on username provided in IMDS",
+                "--groups",
+                "adm,audio,cdrom,dialout,dip,floppy,lxd,netdev,plugdev,sudo,video",
+                "-d",
+                "/home/azureuser",
+                "-m",
+            ]]
+        );
+    }
+
+    #[test]
+    fn useradd_argv_without_admin_groups() {
+        let runner = MockRunner::succeeding();
+        let user = User::new("operator").with_admin(false);
+        create_user_with_useradd(&runner, &user).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![vec![
+                "useradd",
+                "operator",
+                "--comment",
+                "Provisioning agent created this user from its configuration",
+                "-d",
+                "/home/operator",
+                "-m",
+            ]]
+        );
+    }
+
+    #[test]
+    fn useradd_failure() {
+        let runner = MockRunner::failing("useradd", 9);
+        let result = create_user_with_useradd(&runner, &User::new("azureuser"));
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+    }
+
+    #[test]
+    fn useradd_argv_with_shell() {
+        let runner = MockRunner::succeeding();
+        let user = User::new("azureuser").with_shell("/bin/bash");
+        create_user_with_useradd(&runner, &user).unwrap();
+
+        let argv = &runner.calls()[0];
+        assert_eq!(argv[argv.len() - 2..], ["--shell", "/bin/bash"]);
+    }
+
+    #[test]
+    fn useradd_argv_system_account() {
+        let runner = MockRunner::succeeding();
+        let user = User::new("svc").with_system(true).with_shell("/bin/sh");
+        create_user_with_useradd(&runner, &user).unwrap();
+
+        let argv = &runner.calls()[0];
+        assert_eq!(
+            argv[argv.len() - 4..],
+            ["-m", "--system", "--shell", "/bin/sh"]
+        );
+
+        let runner = MockRunner::succeeding();
+        create_user_with_useradd(&runner, &User::new("azureuser")).unwrap();
+        assert!(!runner.calls()[0].contains(&"--system".to_string()));
+    }
+
+    #[test]
+    fn useradd_argv_with_home() {
+        let runner = MockRunner::succeeding();
+        create_user_with_useradd(&runner, &User::new("azureuser")).unwrap();
+        let argv = &runner.calls()[0];
+        let home = argv.iter().position(|arg| arg == "-d").unwrap();
+        assert_eq!(argv[home + 1..home + 3], ["/home/azureuser", "-m"]);
+
+        let runner = MockRunner::succeeding();
+        let user = User::new("svc")
+            .with_home("/opt/app")
+            .with_create_home(false);
+        create_user_with_useradd(&runner, &user).unwrap();
+        let argv = &runner.calls()[0];
+        let home = argv.iter().position(|arg| arg == "-d").unwrap();
+        assert_eq!(argv[home + 1..home + 3], ["/opt/app", "-M"]);
+    }
+
+    #[test]
+    fn useradd_rejects_relative_shell() {
+        let runner = MockRunner::succeeding();
+        let user = User::new("azureuser").with_shell("bash");
+
+        let result = create_user_with_useradd(&runner, &user);
+        assert!(matches!(result, Err(Error::InvalidShell { .. })));
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn passwd_argv() {
+        let runner = MockRunner::succeeding();
+        set_password_with_passwd(&runner, "azureuser", "").unwrap();
+        assert_eq!(runner.calls(), vec![vec!["passwd", "-d", "azureuser"]]);
+
+        let runner = MockRunner::succeeding();
+        let result = set_password_with_passwd(&runner, "azureuser", "secret");
+        assert!(matches!(result, Err(Error::NonEmptyPassword)));
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn chpasswd_stdin() {
+        let runner = MockRunner::succeeding();
+        set_password_with_chpasswd(&runner, "azureuser", "").unwrap();
+        assert_eq!(runner.calls(), vec![vec!["chpasswd", "-e"]]);
+        assert_eq!(runner.stdin(), vec![b"azureuser:\n".to_vec()]);
+
+        let result = set_password_with_chpasswd(&runner, "azureuser", "secret");
+        assert!(matches!(result, Err(Error::NonEmptyPassword)));
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn password_fallback_order() {
+        let runner = MockRunner::failing("passwd", 1);
+        let backend = set_password(
+            &runner,
+            "azureuser",
+            "",
+            DEFAULT_PASSWORD_PROVISIONERS,
+        )
+        .unwrap();
+        assert_eq!(backend, PasswordProvisioner::Chpasswd);
+        assert_eq!(
+            runner.calls(),
+            vec![vec!["passwd", "-d", "azureuser"], vec!["chpasswd", "-e"]]
+        );
+
+        let runner = MockRunner::succeeding();
+        let backend = set_password(
+            &runner,
+            "azureuser",
+            "",
+            DEFAULT_PASSWORD_PROVISIONERS,
+        )
+        .unwrap();
+        assert_eq!(backend, PasswordProvisioner::Passwd);
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn password_all_backends_fail() {
+        let runner = MockRunner::succeeding();
+        let result = set_password(
+            &runner,
+            "azureuser",
+            "",
+            &[PasswordProvisioner::FakeFailure],
+        );
+        assert!(matches!(result, Err(Error::NoPasswordProvisioner)));
+
+        let result = set_password(
+            &runner,
+            "azureuser",
+            "secret",
+            DEFAULT_PASSWORD_PROVISIONERS,
+        );
+        assert!(matches!(result, Err(Error::NonEmptyPassword)));
+        assert!(runner.calls().is_empty());
+    }
+
+    fn groups_runner(current: &'static str) -> MockRunner {
+        MockRunner::new(move |argv| match argv[0].as_str() {
+            "id" => Ok(output(0, current)),
+            "getent" if argv[2] == "nosuchgroup" => Ok(output(2, "")),
+            _ => Ok(output(0, "")),
+        })
+    }
+
+    #[test]
+    fn usermod_adds_missing_groups() {
+        let runner = groups_runner("azureuser wheel\n");
+        let groups: Vec<String> = ["wheel", "docker", "nosuchgroup", "adm"]
+            .map(String::from)
+            .to_vec();
+
+        let added =
+            reconcile_groups_with_usermod(&runner, "azureuser", &groups)
+                .unwrap();
+        assert_eq!(added, vec!["docker", "adm"]);
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &vec!["usermod", "-aG", "docker,adm", "azureuser"]
+        );
+    }
+
+    #[test]
+    fn usermod_skipped_when_reconciled() {
+        let runner = groups_runner("azureuser wheel docker\n");
+        let groups: Vec<String> =
+            ["docker", "wheel"].map(String::from).to_vec();
+
+        let added =
+            reconcile_groups_with_usermod(&runner, "azureuser", &groups)
+                .unwrap();
+        assert!(added.is_empty());
+        assert_eq!(runner.calls(), vec![vec!["id", "-nG", "azureuser"]]);
+
+        let runner = groups_runner("");
+        reconcile_groups_with_usermod(&runner, "azureuser", &[]).unwrap();
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn hostnamectl_argv() {
+        let runner = MockRunner::succeeding();
+        let backend = set_hostname(
+            &runner,
+            "test-host",
+            &[HostnameProvisioner::Hostnamectl],
+        )
+        .unwrap();
+
+        assert_eq!(backend, HostnameProvisioner::Hostnamectl);
+        assert_eq!(
+            runner.calls(),
+            vec![vec!["hostnamectl", "set-hostname", "test-host"]]
+        );
+    }
+
+    #[test]
+    fn hostname_argv() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc_hostname = dir.path().join("hostname");
+        let runner = MockRunner::succeeding();
+
+        hostname_with_etc_hostname(&runner, "test-host", &etc_hostname)
+            .unwrap();
+
+        assert_eq!(runner.calls(), vec![vec!["hostname", "test-host"]]);
+        assert_eq!(
+            std::fs::read_to_string(&etc_hostname).unwrap(),
+            "test-host\n"
+        );
+    }
+
+    #[test]
+    fn hostname_failure_leaves_etc_hostname() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc_hostname = dir.path().join("hostname");
+        let runner = MockRunner::failing("hostname", 1);
+
+        let result =
+            hostname_with_etc_hostname(&runner, "test-host", &etc_hostname);
+
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+        assert!(!etc_hostname.exists());
     }
 }
@@ -7,6 +7,10 @@ pub enum Error {
     Json(#[from] serde_json::Error),
     #[error("Unable to deserialize or serialize XML data")]
     Xml(#[from] serde_xml_rs::Error),
+    #[error("Unable to deserialize YAML data")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Unable to decode base64 data")]
+    Base64(#[from] base64::DecodeError),
     #[error("HTTP client error ocurred")]
     Http(#[from] reqwest::Error),
     #[error("An I/O error occurred")]
@@ -16,11 +20,34 @@ pub enum Error {
         endpoint: String,
         status: reqwest::StatusCode,
     },
+    #[error("IMDS is disabled or unavailable for this VM (HTTP 404 from {endpoint})")]
+    ImdsDisabled { endpoint: String },
+    #[error("IMDS did not respond in time ({endpoint})")]
+    ImdsTimeout {
+        endpoint: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("IMDS request failed (HTTP {status} from {endpoint})")]
+    ImdsHttp {
+        endpoint: String,
+        status: reqwest::StatusCode,
+    },
     #[error("executing {command} failed: {status}")]
     SubprocessFailed {
         command: String,
         status: std::process::ExitStatus,
     },
+    #[error("executing {command} timed out after {timeout:?}")]
+    SubprocessTimeout {
+        command: String,
+        timeout: std::time::Duration,
+    },
+    #[error("The home directory {home} was not writable after {timeout:?}")]
+    HomeNotReady {
+        home: std::path::PathBuf,
+        timeout: std::time::Duration,
+    },
     #[error("failed to construct a C-style string")]
     NulError(#[from] std::ffi::NulError),
     #[error("nix call failed")]
@@ -31,4 +58,316 @@ pub enum Error {
     NonEmptyPassword,
     #[error("Unable to get list of block devices")]
     BlockUtils(#[from] block_utils::BlockUtilsError),
+    #[error("Unable to load configuration")]
+    Config(#[from] Box<figment::Error>),
+    #[error("Unable to set the hostname with any of the configured backends")]
+    NoHostnameProvisioner,
+    #[error("The hostname is empty")]
+    EmptyHostname,
+    #[error("Unable to set the password with any of the configured backends")]
+    NoPasswordProvisioner,
+    #[error("The authorized keys path {path:?} is outside the home directory")]
+    KeysPathOutsideHome { path: std::path::PathBuf },
+    #[error("The login shell {shell:?} is not an absolute path")]
+    InvalidShell { shell: std::path::PathBuf },
+    #[error("The VM was not assigned within {timeout:?}")]
+    PreprovisionTimeout { timeout: std::time::Duration },
+    #[error("The timezone '{timezone}' is not in the timezone database")]
+    InvalidTimezone { timezone: String },
+    #[error("The permissions '{permissions}' are not an octal mode")]
+    InvalidPermissions { permissions: String },
+    #[error("The encoding '{encoding}' is not supported")]
+    UnsupportedEncoding { encoding: String },
+    #[error("The group {group} does not exist")]
+    GroupMissing { group: String },
+    #[error("The NoCloud user-data does not define a user")]
+    NoCloudUserMissing,
+    #[error(
+        "Invalid {field} {secs}; it must be a positive number of seconds, no more than can be waited for"
+    )]
+    InvalidTimeout { field: String, secs: f64 },
+    #[error("Neither IMDS nor the OVF environment provided a username")]
+    UsernameFailure,
+    #[error("Invalid redaction pattern {pattern}: {reason}")]
+    InvalidRedactPattern { pattern: String, reason: String },
+    #[error("Invalid endpoint URL '{url}'")]
+    InvalidUrl { url: String },
+    #[error("User {name} is configured more than once")]
+    DuplicateUser { name: String },
+    #[error(
+        "The mode {mode:#o} is more permissive than the default {default:#o}"
+    )]
+    PermissiveMode { mode: u32, default: u32 },
+    #[error("Another instance of azure-init holds the lock {path:?}")]
+    AlreadyRunning { path: std::path::PathBuf },
+}
+
+impl Error {
+    /// The `sysexits`-style code to exit with when provisioning fails with
+    /// this error.
+    ///
+    /// Errors worth retrying, like timeouts, are `EX_TEMPFAIL`, so systemd can
+    /// restart azure-init on them. Another instance holding the lock is
+    /// `EX_OK` instead: that instance is already provisioning, so this one
+    /// has nothing left to do.
+    pub fn exit_code(&self) -> exitcode::ExitCode {
+        match self {
+            Error::Http(error) if error.is_timeout() => exitcode::TEMPFAIL,
+            Error::ImdsTimeout { .. }
+            | Error::PreprovisionTimeout { .. }
+            | Error::SubprocessTimeout { .. }
+            | Error::HomeNotReady { .. } => exitcode::TEMPFAIL,
+            Error::AlreadyRunning { .. } => exitcode::OK,
+            Error::ImdsDisabled { .. } => exitcode::NOHOST,
+            Error::Http(_)
+            | Error::HttpStatus { .. }
+            | Error::ImdsHttp { .. }
+            | Error::NoHostnameProvisioner
+            | Error::NoPasswordProvisioner => exitcode::UNAVAILABLE,
+            Error::Json(_)
+            | Error::Xml(_)
+            | Error::Yaml(_)
+            | Error::Base64(_)
+            | Error::InvalidPermissions { .. }
+            | Error::UnsupportedEncoding { .. }
+            | Error::NulError(_)
+            | Error::NoCloudUserMissing
+            | Error::EmptyHostname
+            | Error::UsernameFailure => exitcode::DATAERR,
+            Error::Io(_) | Error::BlockUtils(_) => exitcode::IOERR,
+            Error::SubprocessFailed { .. } | Error::Nix(_) => exitcode::OSERR,
+            Error::UserMissing { .. }
+            | Error::GroupMissing { .. }
+            | Error::NonEmptyPassword
+            | Error::Config(_)
+            | Error::InvalidShell { .. }
+            | Error::KeysPathOutsideHome { .. }
+            | Error::InvalidTimezone { .. }
+            | Error::InvalidTimeout { .. }
+            | Error::InvalidRedactPattern { .. }
+            | Error::InvalidUrl { .. }
+            | Error::DuplicateUser { .. }
+            | Error::PermissiveMode { .. } => exitcode::CONFIG,
+        }
+    }
+}
+
+/// A provisioning step which failed, and why.
+#[derive(thiserror::Error, Debug)]
+#[error("step={step}: {source}")]
+pub struct ProvisionError {
+    /// The name of the step, as in [`ProvisionSummary::steps`].
+    ///
+    /// [`ProvisionSummary::steps`]: crate::provision::ProvisionSummary::steps
+    pub step: &'static str,
+    #[source]
+    pub source: Error,
+}
+
+/// Drops the step, for callers which only handle [`Error`].
+impl From<ProvisionError> for Error {
+    fn from(error: ProvisionError) -> Self {
+        error.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::process::ExitStatus;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn reqwest_error() -> reqwest::Error {
+        reqwest::Client::new().get("not a url").build().unwrap_err()
+    }
+
+    #[test]
+    fn exit_codes() {
+        let cases = [
+            (
+                Error::Json(serde_json::from_str::<u8>("").unwrap_err()),
+                exitcode::DATAERR,
+            ),
+            (
+                Error::Xml(serde_xml_rs::from_str::<u8>("<").unwrap_err()),
+                exitcode::DATAERR,
+            ),
+            (
+                Error::Yaml(serde_yaml::from_str::<u8>("[").unwrap_err()),
+                exitcode::DATAERR,
+            ),
+            (
+                Error::Base64(base64::DecodeError::InvalidLength(1)),
+                exitcode::DATAERR,
+            ),
+            (
+                Error::InvalidPermissions {
+                    permissions: "rw".to_string(),
+                },
+                exitcode::DATAERR,
+            ),
+            (
+                Error::UnsupportedEncoding {
+                    encoding: "gzip".to_string(),
+                },
+                exitcode::DATAERR,
+            ),
+            (
+                Error::GroupMissing {
+                    group: "nosuchgroup".to_string(),
+                },
+                exitcode::CONFIG,
+            ),
+            (Error::Http(reqwest_error()), exitcode::UNAVAILABLE),
+            (
+                Error::Io(std::io::ErrorKind::NotFound.into()),
+                exitcode::IOERR,
+            ),
+            (
+                Error::HttpStatus {
+                    endpoint: "http://168.63.129.16".to_string(),
+                    status: reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+                },
+                exitcode::UNAVAILABLE,
+            ),
+            (
+                Error::ImdsDisabled {
+                    endpoint: "http://169.254.169.254".to_string(),
+                },
+                exitcode::NOHOST,
+            ),
+            (
+                Error::ImdsTimeout {
+                    endpoint: "http://169.254.169.254".to_string(),
+                    source: reqwest_error(),
+                },
+                exitcode::TEMPFAIL,
+            ),
+            (
+                Error::ImdsHttp {
+                    endpoint: "http://169.254.169.254".to_string(),
+                    status: reqwest::StatusCode::SERVICE_UNAVAILABLE,
+                },
+                exitcode::UNAVAILABLE,
+            ),
+            (
+                Error::SubprocessFailed {
+                    command: "useradd".to_string(),
+                    status: ExitStatus::from_raw(1 << 8),
+                },
+                exitcode::OSERR,
+            ),
+            (
+                Error::SubprocessTimeout {
+                    command: "mount".to_string(),
+                    timeout: Duration::from_secs(30),
+                },
+                exitcode::TEMPFAIL,
+            ),
+            (
+                Error::HomeNotReady {
+                    home: "/home/azureuser".into(),
+                    timeout: Duration::from_secs(60),
+                },
+                exitcode::TEMPFAIL,
+            ),
+            (
+                Error::NulError(std::ffi::CString::new("\0").unwrap_err()),
+                exitcode::DATAERR,
+            ),
+            (Error::Nix(nix::Error::EPERM), exitcode::OSERR),
+            (
+                Error::UserMissing {
+                    user: "azureuser".to_string(),
+                },
+                exitcode::CONFIG,
+            ),
+            (Error::NonEmptyPassword, exitcode::CONFIG),
+            (
+                Error::BlockUtils(block_utils::BlockUtilsError::Error(
+                    "no devices".to_string(),
+                )),
+                exitcode::IOERR,
+            ),
+            (
+                Error::Config(Box::new(figment::Error::from("invalid"))),
+                exitcode::CONFIG,
+            ),
+            (Error::NoHostnameProvisioner, exitcode::UNAVAILABLE),
+            (Error::EmptyHostname, exitcode::DATAERR),
+            (Error::NoPasswordProvisioner, exitcode::UNAVAILABLE),
+            (
+                Error::KeysPathOutsideHome {
+                    path: PathBuf::from("../../etc/passwd"),
+                },
+                exitcode::CONFIG,
+            ),
+            (
+                Error::InvalidShell {
+                    shell: PathBuf::from("bash"),
+                },
+                exitcode::CONFIG,
+            ),
+            (
+                Error::PreprovisionTimeout {
+                    timeout: Duration::from_secs(60),
+                },
+                exitcode::TEMPFAIL,
+            ),
+            (
+                Error::InvalidTimezone {
+                    timezone: "Mars/Olympus".to_string(),
+                },
+                exitcode::CONFIG,
+            ),
+            (Error::NoCloudUserMissing, exitcode::DATAERR),
+            (
+                Error::InvalidTimeout {
+                    field: "imds.total_timeout_secs".to_string(),
+                    secs: -1.0,
+                },
+                exitcode::CONFIG,
+            ),
+            (Error::UsernameFailure, exitcode::DATAERR),
+            (
+                Error::InvalidRedactPattern {
+                    pattern: "(".to_string(),
+                    reason: "unclosed group".to_string(),
+                },
+                exitcode::CONFIG,
+            ),
+            (
+                Error::InvalidUrl {
+                    url: "not a url".to_string(),
+                },
+                exitcode::CONFIG,
+            ),
+            (
+                Error::DuplicateUser {
+                    name: "operator".to_string(),
+                },
+                exitcode::CONFIG,
+            ),
+            (
+                Error::PermissiveMode {
+                    mode: 0o644,
+                    default: 0o600,
+                },
+                exitcode::CONFIG,
+            ),
+            (
+                Error::AlreadyRunning {
+                    path: PathBuf::from("/run/azure-init.lock"),
+                },
+                exitcode::OK,
+            ),
+        ];
+
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{error:?}");
+        }
+    }
 }
@@ -9,9 +9,19 @@ use reqwest::Client;
 use serde::Deserialize;
 use serde_xml_rs::from_str;
 
+use crate::config::HealthFormat;
 use crate::error::Error;
+use crate::http;
 
-#[derive(Debug, Deserialize, PartialEq)]
+/// Base URL of the Azure wireserver.
+pub const DEFAULT_WIRESERVER_URL: &str = "http://168.63.129.16";
+
+/// The wireserver's goalstate, identifying the VM to health reports and
+/// other requests.
+///
+/// Fetch it once with [`get_goalstate`] and pass it to each request that
+/// needs it.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Goalstate {
     #[serde(rename = "Container")]
     container: Container,
@@ -21,7 +31,7 @@ pub struct Goalstate {
     incarnation: String,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Container {
     #[serde(rename = "ContainerId")]
     container_id: String,
@@ -29,27 +39,48 @@ pub struct Container {
     role_instance_list: RoleInstanceList,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct RoleInstanceList {
     #[serde(rename = "RoleInstance")]
     role_instance: RoleInstance,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct RoleInstance {
     #[serde(rename = "InstanceId")]
     instance_id: String,
 }
 
-pub async fn get_goalstate(client: &Client) -> Result<Goalstate, Error> {
-    let url = "http://168.63.129.16/machine/?comp=goalstate";
+impl Goalstate {
+    /// The incarnation of the goalstate.
+    pub fn incarnation(&self) -> &str {
+        &self.incarnation
+    }
+
+    /// The ID of the container the VM runs in.
+    pub fn container_id(&self) -> &str {
+        &self.container.container_id
+    }
+
+    /// The ID of this VM's role instance.
+    pub fn instance_id(&self) -> &str {
+        &self.container.role_instance_list.role_instance.instance_id
+    }
+}
+
+pub async fn get_goalstate(
+    client: &Client,
+    endpoint: &str,
+) -> Result<Goalstate, Error> {
+    let url =
+        format!("{}/machine/?comp=goalstate", endpoint.trim_end_matches('/'));
 
     let mut headers = HeaderMap::new();
     headers.insert("x-ms-agent-name", HeaderValue::from_static("azure-init"));
     headers.insert("x-ms-version", HeaderValue::from_static("2012-11-30"));
 
-    let request = client.get(url).headers(headers);
-    let response = request.send().await?;
+    let request = client.get(&url).headers(headers);
+    let response = http::send(request, 1).await?;
 
     if response.status().is_success() {
         let body = response.text().await?;
@@ -58,46 +89,76 @@ pub async fn get_goalstate(client: &Client) -> Result<Goalstate, Error> {
         Ok(goalstate)
     } else {
         Err(Error::HttpStatus {
-            endpoint: url.to_owned(),
+            endpoint: url,
             status: response.status(),
         })
     }
 }
 
+/// The health of the VM, as reported to the wireserver.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+    Ready,
+    /// Provisioning failed, with an optional description of why.
+    NotReady {
+        reason: Option<String>,
+    },
+}
+
 pub async fn report_health(
     client: &Client,
-    goalstate: Goalstate,
+    endpoint: &str,
+    goalstate: &Goalstate,
+) -> Result<(), Error> {
+    report_health_state(
+        client,
+        endpoint,
+        goalstate,
+        &Health::Ready,
+        HealthFormat::Xml,
+    )
+    .await
+}
+
+/// Report `health` for the VM described by `goalstate` to the wireserver,
+/// with the body in `format`.
+pub async fn report_health_state(
+    client: &Client,
+    endpoint: &str,
+    goalstate: &Goalstate,
+    health: &Health,
+    format: HealthFormat,
 ) -> Result<(), Error> {
-    let url = "http://168.63.129.16/machine/?comp=health";
+    let url =
+        format!("{}/machine/?comp=health", endpoint.trim_end_matches('/'));
 
     let mut headers = HeaderMap::new();
     headers.insert("x-ms-agent-name", HeaderValue::from_static("azure-init"));
     headers.insert("x-ms-version", HeaderValue::from_static("2012-11-30"));
     headers.insert(
         "Content-Type",
-        HeaderValue::from_static("text/xml;charset=utf-8"),
+        HeaderValue::from_static(format.content_type()),
     );
 
-    let post_request = build_report_health_file(goalstate);
+    let post_request = match format {
+        HealthFormat::Xml => build_report_health_file(goalstate, health),
+        HealthFormat::Json => build_report_health_json(goalstate, health),
+    };
 
-    let response = client
-        .post(url)
-        .headers(headers)
-        .body(post_request)
-        .send()
-        .await?;
+    let request = client.post(&url).headers(headers).body(post_request);
+    let response = http::send(request, 1).await?;
 
     if response.status().is_success() {
         Ok(())
     } else {
         Err(Error::HttpStatus {
-            endpoint: url.to_owned(),
+            endpoint: url,
             status: response.status(),
         })
     }
 }
 
-fn build_report_health_file(goalstate: Goalstate) -> String {
+fn build_report_health_file(goalstate: &Goalstate, health: &Health) -> String {
     let post_request =
     "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
     <Health xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n\
@@ -108,30 +169,149 @@ fn build_report_health_file(goalstate: Goalstate) -> String {
                 <Role>\n\
                     <InstanceId>$INSTANCE_ID</InstanceId>\n\
                     <Health>\n\
-                        <State>Ready</State>\n\
+                        $HEALTH\
                     </Health>\n\
                 </Role>\n\
             </RoleInstanceList>\n\
         </Container>\n\
     </Health>";
 
-    let post_request =
-        post_request.replace("$GOAL_STATE_INCARNATION", &goalstate.incarnation);
+    let health = match health {
+        Health::Ready => "<State>Ready</State>\n".to_string(),
+        Health::NotReady { reason } => format!(
+            "<State>NotReady</State>\n\
+            <Details>\n\
+                <SubStatus>ProvisioningFailed</SubStatus>\n\
+                <Description>{}</Description>\n\
+            </Details>\n",
+            escape_xml(reason.as_deref().unwrap_or_default())
+        ),
+    };
+
     let post_request = post_request
-        .replace("$CONTAINER_ID", &goalstate.container.container_id);
-    post_request.replace(
-        "$INSTANCE_ID",
-        &goalstate
-            .container
-            .role_instance_list
-            .role_instance
-            .instance_id,
-    )
+        .replace("$HEALTH", &health)
+        .replace("$GOAL_STATE_INCARNATION", goalstate.incarnation());
+    let post_request =
+        post_request.replace("$CONTAINER_ID", goalstate.container_id());
+    post_request.replace("$INSTANCE_ID", goalstate.instance_id())
+}
+
+impl HealthFormat {
+    /// The Content-Type of a health report in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            HealthFormat::Xml => "text/xml;charset=utf-8",
+            HealthFormat::Json => "application/json",
+        }
+    }
+}
+
+/// The health report, in the same shape as the XML one.
+fn build_report_health_json(goalstate: &Goalstate, health: &Health) -> String {
+    let health = match health {
+        Health::Ready => serde_json::json!({ "State": "Ready" }),
+        Health::NotReady { reason } => serde_json::json!({
+            "State": "NotReady",
+            "Details": {
+                "SubStatus": "ProvisioningFailed",
+                "Description": reason.as_deref().unwrap_or_default(),
+            },
+        }),
+    };
+
+    serde_json::json!({
+        "GoalStateIncarnation": goalstate.incarnation(),
+        "Container": {
+            "ContainerId": goalstate.container_id(),
+            "RoleInstanceList": {
+                "Role": [{
+                    "InstanceId": goalstate.instance_id(),
+                    "Health": health,
+                }],
+            },
+        },
+    })
+    .to_string()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{build_report_health_file, Goalstate};
+    use reqwest::Client;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{
+        build_report_health_file, build_report_health_json, get_goalstate,
+        report_health_state, Goalstate, Health,
+    };
+    use crate::config::HealthFormat;
+
+    fn goalstate() -> Goalstate {
+        serde_xml_rs::from_str(
+            "<Goalstate>
+                <Container>
+                    <ContainerId>2</ContainerId>
+                    <RoleInstanceList>
+                        <RoleInstance>
+                            <InstanceId>test_user_instance_id</InstanceId>
+                        </RoleInstance>
+                    </RoleInstanceList>
+                </Container>
+                <Version>example_version</Version>
+                <Incarnation>test_goal_incarnation</Incarnation>
+            </Goalstate>",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn report_formats_agree() {
+        let xml = build_report_health_file(&goalstate(), &Health::Ready);
+        for expected in [
+            "<GoalStateIncarnation>test_goal_incarnation</GoalStateIncarnation>",
+            "<ContainerId>2</ContainerId>",
+            "<InstanceId>test_user_instance_id</InstanceId>",
+            "<State>Ready</State>",
+        ] {
+            assert!(xml.contains(expected), "{expected}");
+        }
+
+        let json: serde_json::Value = serde_json::from_str(
+            &build_report_health_json(&goalstate(), &Health::Ready),
+        )
+        .unwrap();
+        assert_eq!(json["GoalStateIncarnation"], "test_goal_incarnation");
+        assert_eq!(json["Container"]["ContainerId"], "2");
+        let role = &json["Container"]["RoleInstanceList"]["Role"][0];
+        assert_eq!(role["InstanceId"], "test_user_instance_id");
+        assert_eq!(role["Health"]["State"], "Ready");
+
+        assert_eq!(HealthFormat::Xml.content_type(), "text/xml;charset=utf-8");
+        assert_eq!(HealthFormat::Json.content_type(), "application/json");
+    }
+
+    #[test]
+    fn json_report_not_ready() {
+        let health = Health::NotReady {
+            reason: Some("useradd <failed>".to_string()),
+        };
+        let json: serde_json::Value = serde_json::from_str(
+            &build_report_health_json(&goalstate(), &health),
+        )
+        .unwrap();
+        let health =
+            &json["Container"]["RoleInstanceList"]["Role"][0]["Health"];
+        assert_eq!(health["State"], "NotReady");
+        assert_eq!(health["Details"]["SubStatus"], "ProvisioningFailed");
+        assert_eq!(health["Details"]["Description"], "useradd <failed>");
+    }
 
     #[test]
     fn test_parsing_goalstate() {
@@ -197,7 +377,129 @@ mod tests {
             </Container>\n\
         </Health>";
 
-        let actual_output = build_report_health_file(goalstate);
+        let actual_output =
+            build_report_health_file(&goalstate, &Health::Ready);
         assert_eq!(actual_output, expected_output);
     }
+
+    #[test]
+    fn report_not_ready() {
+        let goalstate_str = "
+            <Goalstate>
+                <Container>
+                    <ContainerId>2</ContainerId>
+                    <RoleInstanceList>
+                        <RoleInstance>
+                            <InstanceId>test_user_instance_id</InstanceId>
+                        </RoleInstance>
+                    </RoleInstanceList>
+                </Container>
+                <Version>example_version</Version>
+                <Incarnation>test_goal_incarnation</Incarnation>
+            </Goalstate>";
+        let goalstate: Goalstate = serde_xml_rs::from_str(goalstate_str)
+            .expect("Failed to parse the goalstate XML.");
+
+        let health = Health::NotReady {
+            reason: Some("useradd <failed>".to_string()),
+        };
+        let actual_output = build_report_health_file(&goalstate, &health);
+        assert!(actual_output.contains("<State>NotReady</State>\n"));
+        assert!(actual_output
+            .contains("<Description>useradd &lt;failed&gt;</Description>\n"));
+        assert!(!actual_output.contains("<State>Ready</State>"));
+    }
+
+    /// Serve `bodies` to one request each, returning the server's URL and a
+    /// handle resolving to the request lines.
+    async fn serve(
+        bodies: Vec<&'static str>,
+    ) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut lines = vec![];
+            for body in bodies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = vec![0; 4096];
+                // Read the whole body, so the connection isn't reset.
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, rest)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_ascii_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|len| len.parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if rest.len() >= length || n == 0 {
+                            break;
+                        }
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                lines.push(request.lines().next().unwrap().to_string());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nConnection: close\r\n\
+                     Content-Length: {}\r\n\r\n{body}",
+                    body.len(),
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            lines
+        });
+
+        (endpoint, handle)
+    }
+
+    #[tokio::test]
+    async fn goalstate_fetched_once_and_reused() {
+        let goalstate_xml = "<Goalstate>\
+            <Container>\
+                <ContainerId>2</ContainerId>\
+                <RoleInstanceList>\
+                    <RoleInstance>\
+                        <InstanceId>test_user_instance_id</InstanceId>\
+                    </RoleInstance>\
+                </RoleInstanceList>\
+            </Container>\
+            <Version>example_version</Version>\
+            <Incarnation>test_goal_incarnation</Incarnation>\
+        </Goalstate>";
+        let (endpoint, server) = serve(vec![goalstate_xml, "", ""]).await;
+        let client = Client::new();
+
+        let goalstate = get_goalstate(&client, &endpoint).await.unwrap();
+        assert_eq!(goalstate, self::goalstate());
+        assert_eq!(goalstate.incarnation(), "test_goal_incarnation");
+        assert_eq!(goalstate.container_id(), "2");
+        assert_eq!(goalstate.instance_id(), "test_user_instance_id");
+
+        let not_ready = Health::NotReady { reason: None };
+        for health in [&not_ready, &Health::Ready] {
+            report_health_state(
+                &client,
+                &endpoint,
+                &goalstate,
+                health,
+                HealthFormat::Xml,
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "GET /machine/?comp=goalstate HTTP/1.1",
+                "POST /machine/?comp=health HTTP/1.1",
+                "POST /machine/?comp=health HTTP/1.1",
+            ]
+        );
+    }
 }
@@ -0,0 +1,205 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Sending HTTP requests with timing instrumentation.
+
+use std::time::Instant;
+
+use reqwest::{RequestBuilder, Response, StatusCode};
+use tracing::Instrument;
+
+/// Statuses meaning the service is briefly overloaded or unavailable, so the
+/// request is worth retrying.
+pub(crate) const RETRY_CODES: &[StatusCode] = &[
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// Statuses meaning the request itself is wrong, e.g. a bad `api-version`,
+/// so retrying it can't succeed.
+pub(crate) const HARDFAIL_CODES: &[StatusCode] = &[
+    StatusCode::BAD_REQUEST,
+    StatusCode::UNAUTHORIZED,
+    StatusCode::FORBIDDEN,
+    StatusCode::NOT_FOUND,
+    StatusCode::METHOD_NOT_ALLOWED,
+];
+
+/// Whether a request answered with `status` is worth retrying.
+pub(crate) fn is_retriable(status: StatusCode) -> bool {
+    RETRY_CODES.contains(&status)
+}
+
+/// Send `request`, the `attempt`th try at it, recording the URL, response
+/// status, and elapsed time.
+///
+/// Each request runs in an `http_request` span. Responses are logged to the
+/// `libazureinit::http::received` target, and successful ones to
+/// `libazureinit::http::success`, with the elapsed time in `elapsed_ms`.
+pub(crate) async fn send(
+    request: RequestBuilder,
+    attempt: u32,
+) -> Result<Response, reqwest::Error> {
+    let (client, request) = request.build_split();
+    let request = request?;
+
+    let span = tracing::info_span!(
+        "http_request",
+        method = %request.method(),
+        url = %request.url(),
+        attempt,
+        status = tracing::field::Empty,
+        elapsed_ms = tracing::field::Empty,
+    );
+
+    async move {
+        let start = Instant::now();
+        let result = client.execute(request).await;
+        let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let span = tracing::Span::current();
+        span.record("elapsed_ms", elapsed_ms);
+        match &result {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                span.record("status", status);
+                tracing::info!(
+                    target: "libazureinit::http::received",
+                    status,
+                    elapsed_ms,
+                    "HTTP response received"
+                );
+                if response.status().is_success() {
+                    tracing::info!(
+                        target: "libazureinit::http::success",
+                        status,
+                        elapsed_ms,
+                        "HTTP request succeeded"
+                    );
+                }
+            }
+            Err(error) => tracing::warn!(
+                target: "libazureinit::http::failed",
+                ?error,
+                elapsed_ms,
+                "HTTP request failed"
+            ),
+        }
+
+        result
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+pub(crate) mod events {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::Context;
+    use tracing_subscriber::Layer;
+
+    /// An event's target and its fields' names and values.
+    pub(crate) type Event = (String, Vec<(String, String)>);
+
+    /// Records the target and fields of every event.
+    #[derive(Clone, Default)]
+    pub(crate) struct Events(pub(crate) Arc<Mutex<Vec<Event>>>);
+
+    struct Fields(Vec<(String, String)>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push((field.name().to_string(), value.to_string()));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for Events {
+        fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+            let mut fields = Fields(vec![]);
+            event.record(&mut fields);
+            self.0
+                .lock()
+                .unwrap()
+                .push((event.metadata().target().to_string(), fields.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::Client;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::events::Events;
+    use super::*;
+
+    #[test]
+    fn status_classification() {
+        for status in RETRY_CODES {
+            assert!(is_retriable(*status), "{status}");
+            assert!(!HARDFAIL_CODES.contains(status), "{status}");
+        }
+        for status in HARDFAIL_CODES {
+            assert!(!is_retriable(*status), "{status}");
+        }
+        assert!(!is_retriable(StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn latency_recorded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let client = Client::new();
+        let response = send(client.get(format!("http://{addr}/")), 1)
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let events = events.0.lock().unwrap();
+        let targets: Vec<&str> =
+            events.iter().map(|(target, _)| target.as_str()).collect();
+        assert!(targets.contains(&"libazureinit::http::success"));
+
+        let (_, fields) = events
+            .iter()
+            .find(|(target, _)| target == "libazureinit::http::received")
+            .unwrap();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.clone())
+                .unwrap()
+        };
+        assert_eq!(field("status"), "200");
+        let elapsed_ms: f64 = field("elapsed_ms").parse().unwrap();
+        assert!(elapsed_ms >= 50.0, "elapsed {elapsed_ms}ms");
+    }
+}
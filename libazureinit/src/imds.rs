@@ -1,21 +1,173 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use std::time::Duration;
+
 use reqwest;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderValue;
 use reqwest::Client;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer};
 use serde_json;
 use serde_json::Value;
 
 use crate::error::Error;
+use crate::http;
+use crate::media::{self, Environment};
+
+/// Base URL of the Azure Instance Metadata Service.
+pub const DEFAULT_IMDS_URL: &str = "http://169.254.169.254";
+
+/// How long to wait between requests while a preprovisioned VM is
+/// unassigned.
+pub const REPROVISION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many times a request to IMDS is sent while it answers with one of
+/// the statuses worth retrying, such as 429 or 503.
+pub const IMDS_ATTEMPTS: u32 = 3;
+
+/// How long to wait before retrying a request to IMDS.
+pub const IMDS_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+const INSTANCE_PATH: &str = "metadata/instance?api-version=2021-02-01";
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct InstanceMetadata {
     /// Compute metadata
     pub compute: Compute,
+    /// Network metadata
+    #[serde(default)]
+    pub network: Network,
+}
+
+impl InstanceMetadata {
+    /// Build instance metadata without querying IMDS, e.g. for tests.
+    pub fn builder() -> InstanceMetadataBuilder {
+        InstanceMetadataBuilder::default()
+    }
+
+    /// The VM's tags.
+    ///
+    /// The structured `tagsList` is preferred; the semicolon-delimited `tags`
+    /// string is parsed when the list isn't provided.
+    pub fn tags(&self) -> Vec<Tag> {
+        if !self.compute.tags_list.is_empty() {
+            return self.compute.tags_list.clone();
+        }
+
+        self.compute
+            .tags
+            .split(';')
+            .filter(|tag| !tag.is_empty())
+            .map(|tag| {
+                let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+                Tag {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Builds an [`InstanceMetadata`] as IMDS would provide it.
+///
+/// Password authentication is disabled unless set otherwise, and everything
+/// not set is empty.
+#[derive(Clone, Debug)]
+pub struct InstanceMetadataBuilder {
+    admin_username: Option<String>,
+    computer_name: String,
+    public_keys: Vec<PublicKeys>,
+    disable_password_authentication: bool,
+    admin_password: Option<String>,
+}
+
+impl Default for InstanceMetadataBuilder {
+    fn default() -> Self {
+        Self {
+            admin_username: None,
+            computer_name: String::new(),
+            public_keys: vec![],
+            disable_password_authentication: true,
+            admin_password: None,
+        }
+    }
+}
+
+impl InstanceMetadataBuilder {
+    /// The admin account's username.
+    pub fn admin_username(self, username: impl Into<String>) -> Self {
+        Self {
+            admin_username: Some(username.into()),
+            ..self
+        }
+    }
+
+    /// The name of the virtual machine.
+    pub fn computer_name(self, computer_name: impl Into<String>) -> Self {
+        Self {
+            computer_name: computer_name.into(),
+            ..self
+        }
+    }
+
+    /// Add an SSH public key to be written to `path`, or to the user's
+    /// default file if `path` is empty.
+    pub fn public_key(
+        mut self,
+        key_data: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Self {
+        self.public_keys.push(PublicKeys {
+            key_data: key_data.into(),
+            path: path.into(),
+        });
+        self
+    }
+
+    /// Whether password authentication is disabled.
+    pub fn disable_password_authentication(self, disable: bool) -> Self {
+        Self {
+            disable_password_authentication: disable,
+            ..self
+        }
+    }
+
+    /// The admin account's password.
+    pub fn admin_password(self, password: impl Into<String>) -> Self {
+        Self {
+            admin_password: Some(password.into()),
+            ..self
+        }
+    }
+
+    pub fn build(self) -> InstanceMetadata {
+        InstanceMetadata {
+            compute: Compute {
+                os_profile: OsProfile {
+                    admin_username: self.admin_username,
+                    computer_name: self.computer_name,
+                    disable_password_authentication: self
+                        .disable_password_authentication,
+                    admin_password: self.admin_password,
+                    custom_data: None,
+                    allow_extension_operations: None,
+                },
+                public_keys: self.public_keys,
+                tags: String::new(),
+                tags_list: vec![],
+                az_environment: String::new(),
+                location: String::new(),
+                zone: String::new(),
+                vm_size: String::new(),
+                security_profile: SecurityProfile::default(),
+            },
+            network: Network::default(),
+        }
+    }
 }
 
 /// Metadata about the instance's virtual machine.
@@ -27,14 +179,71 @@ pub struct Compute {
     /// SSH Public keys.
     #[serde(rename = "publicKeys")]
     pub public_keys: Vec<PublicKeys>,
+    /// Tags in the form `name1:value1;name2:value2`.
+    #[serde(default)]
+    pub tags: String,
+    /// Tags as a list of name/value pairs.
+    #[serde(default, rename = "tagsList")]
+    pub tags_list: Vec<Tag>,
+    /// The Azure cloud, e.g. `AzurePublicCloud`.
+    #[serde(default, rename = "azEnvironment")]
+    pub az_environment: String,
+    /// The region the VM runs in, e.g. `eastus`.
+    #[serde(default)]
+    pub location: String,
+    /// The availability zone, empty if the VM isn't zonal.
+    #[serde(default)]
+    pub zone: String,
+    /// The VM size, e.g. `Standard_D2s_v3`.
+    #[serde(default, rename = "vmSize")]
+    pub vm_size: String,
+    /// The VM's Trusted Launch or Confidential VM settings.
+    #[serde(default, rename = "securityProfile")]
+    pub security_profile: SecurityProfile,
+}
+
+/// Security settings of the virtual machine, all off for a standard VM.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+pub struct SecurityProfile {
+    /// Whether UEFI Secure Boot is enabled.
+    #[serde(
+        default,
+        rename = "secureBootEnabled",
+        deserialize_with = "string_bool"
+    )]
+    pub secure_boot_enabled: bool,
+    /// Whether the VM has a virtual TPM.
+    #[serde(
+        default,
+        rename = "virtualTpmEnabled",
+        deserialize_with = "string_bool"
+    )]
+    pub virtual_tpm_enabled: bool,
+    /// `TrustedLaunch` or `ConfidentialVM`, or empty for a standard VM.
+    #[serde(default, rename = "securityType")]
+    pub security_type: String,
+}
+
+impl SecurityProfile {
+    /// Whether this is a Confidential VM.
+    pub fn is_confidential(&self) -> bool {
+        self.security_type == "ConfidentialVM"
+    }
+}
+
+/// A tag assigned to the virtual machine.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub value: String,
 }
 
 /// Metadata about the virtual machine's operating system.
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct OsProfile {
-    /// The admin account's username.
-    #[serde(rename = "adminUsername")]
-    pub admin_username: String,
+    /// The admin account's username, which some images don't have.
+    #[serde(rename = "adminUsername", default)]
+    pub admin_username: Option<String>,
     /// The name of the virtual machine.
     #[serde(rename = "computerName")]
     pub computer_name: String,
@@ -44,6 +253,28 @@ pub struct OsProfile {
         deserialize_with = "string_bool"
     )]
     pub disable_password_authentication: bool,
+    /// The admin account's password, which IMDS usually withholds.
+    #[serde(rename = "adminPassword", default)]
+    pub admin_password: Option<String>,
+    /// The base64-encoded custom data, if IMDS provides it.
+    #[serde(rename = "customData", default)]
+    pub custom_data: Option<String>,
+    /// Whether VM extensions may be installed.
+    #[serde(
+        rename = "allowExtensionOperations",
+        default,
+        deserialize_with = "optional_string_bool"
+    )]
+    pub allow_extension_operations: Option<bool>,
+}
+
+impl OsProfile {
+    /// Whether a non-empty admin password was provided.
+    pub fn has_admin_password(&self) -> bool {
+        self.admin_password
+            .as_deref()
+            .is_some_and(|password| !password.is_empty())
+    }
 }
 
 /// An SSH public key.
@@ -78,33 +309,368 @@ where
     }
 }
 
-pub async fn query(client: &Client) -> Result<InstanceMetadata, Error> {
-    let url = "http://169.254.169.254/metadata/instance?api-version=2021-02-01";
+/// Like [`string_bool`], for a field which may be missing or null.
+fn optional_string_bool<'de, D>(
+    deserializer: D,
+) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => string_bool(value)
+            .map(Some)
+            .map_err(|error| serde::de::Error::custom(error.to_string())),
+    }
+}
+
+/// Network metadata for the VM's interfaces.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+pub struct Network {
+    pub interface: Vec<NetworkInterface>,
+}
+
+impl Network {
+    /// The first private IPv4 address of the primary, i.e. first,
+    /// interface.
+    pub fn primary_private_ip(&self) -> Option<&str> {
+        self.interface
+            .first()?
+            .ipv4
+            .ip_address
+            .first()
+            .map(|address| address.private_ip_address.as_str())
+            .filter(|address| !address.is_empty())
+    }
+}
+
+/// A network interface and its addresses.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct NetworkInterface {
+    #[serde(rename = "macAddress")]
+    pub mac_address: String,
+    pub ipv4: IpConfiguration,
+    #[serde(default)]
+    pub ipv6: IpConfiguration,
+}
+
+/// The addresses and subnets of one IP family on an interface.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+pub struct IpConfiguration {
+    #[serde(default, rename = "ipAddress")]
+    pub ip_address: Vec<IpAddress>,
+    #[serde(default)]
+    pub subnet: Vec<Subnet>,
+}
+
+/// A private address and, if one is assigned, its public address.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct IpAddress {
+    #[serde(rename = "privateIpAddress")]
+    pub private_ip_address: String,
+    /// The public address, which IMDS reports as an empty string if there is
+    /// none.
+    #[serde(default, rename = "publicIpAddress")]
+    pub public_ip_address: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct Subnet {
+    pub address: String,
+    pub prefix: String,
+}
+
+/// The attested metadata document, signed by Azure.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct AttestedDocument {
+    /// The format of `signature`, e.g. `pkcs7`.
+    pub encoding: String,
+    /// The base64-encoded signed document.
+    pub signature: String,
+}
+
+/// Maintenance scheduled for the VM, from the Scheduled Events service.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+pub struct ScheduledEvents {
+    /// Increases each time the set of events changes.
+    #[serde(default, rename = "DocumentIncarnation")]
+    pub document_incarnation: u64,
+    #[serde(default, rename = "Events")]
+    pub events: Vec<ScheduledEvent>,
+}
+
+/// An upcoming maintenance event, e.g. a reboot or redeploy.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct ScheduledEvent {
+    #[serde(rename = "EventId")]
+    pub event_id: String,
+    /// The kind of event, e.g. `Reboot`, `Redeploy`, or `Freeze`.
+    #[serde(rename = "EventType")]
+    pub event_type: String,
+    #[serde(default, rename = "ResourceType")]
+    pub resource_type: String,
+    /// The VMs the event affects.
+    #[serde(default, rename = "Resources")]
+    pub resources: Vec<String>,
+    /// `Scheduled`, or `Started` once the event is underway.
+    #[serde(rename = "EventStatus")]
+    pub event_status: String,
+    /// The earliest time the event may start, empty once it has started.
+    #[serde(default, rename = "NotBefore")]
+    pub not_before: String,
+    #[serde(default, rename = "Description")]
+    pub description: String,
+    /// Whether the platform or a user initiated the event.
+    #[serde(default, rename = "EventSource")]
+    pub event_source: String,
+    /// The expected duration, or -1 if unknown.
+    #[serde(default = "unknown_duration", rename = "DurationInSeconds")]
+    pub duration_in_seconds: i64,
+}
+
+fn unknown_duration() -> i64 {
+    -1
+}
+
+/// Query the instance metadata from the IMDS at `endpoint`, which is usually
+/// [`DEFAULT_IMDS_URL`].
+pub async fn query(
+    client: &Client,
+    endpoint: &str,
+) -> Result<InstanceMetadata, Error> {
+    get(client, endpoint, INSTANCE_PATH).await
+}
+
+/// Query the instance metadata as [`query`] does, retrying at `fallback`,
+/// if given, when `endpoint` can't be connected to.
+///
+/// Only a failure to connect falls back; other errors mean the metadata
+/// service was reached.
+pub async fn query_with_fallback(
+    client: &Client,
+    endpoint: &str,
+    fallback: Option<&str>,
+) -> Result<InstanceMetadata, Error> {
+    let result = query(client, endpoint).await;
+    let Some(fallback) = fallback else {
+        return result;
+    };
+
+    match result {
+        Err(Error::ImdsTimeout { source, .. }) if source.is_connect() => {
+            tracing::warn!(
+                %endpoint,
+                %fallback,
+                "Unable to connect to IMDS, trying the fallback endpoint"
+            );
+            query(client, fallback).await
+        }
+        result => result,
+    }
+}
+
+/// Query the instance metadata through the Azure proxy agent at `proxy`, if
+/// given, and otherwise, or if that fails, as [`query_with_fallback`] does.
+pub async fn query_via_proxy(
+    client: &Client,
+    proxy: Option<&str>,
+    endpoint: &str,
+    fallback: Option<&str>,
+) -> Result<InstanceMetadata, Error> {
+    if let Some(proxy) = proxy {
+        match query(client, proxy).await {
+            Ok(metadata) => return Ok(metadata),
+            Err(error) => tracing::warn!(
+                %proxy,
+                %endpoint,
+                ?error,
+                "Unable to query IMDS through the proxy agent, querying it \
+                 directly"
+            ),
+        }
+    }
+
+    query_with_fallback(client, endpoint, fallback).await
+}
+
+/// Query the network metadata, including the private and public addresses
+/// of each interface, from the IMDS at `endpoint`.
+pub async fn query_network(
+    client: &Client,
+    endpoint: &str,
+) -> Result<Network, Error> {
+    get(
+        client,
+        endpoint,
+        "metadata/instance/network?api-version=2021-02-01",
+    )
+    .await
+}
+
+/// Query the attested metadata document from the IMDS at `endpoint`.
+pub async fn query_attested(
+    client: &Client,
+    endpoint: &str,
+) -> Result<AttestedDocument, Error> {
+    get(
+        client,
+        endpoint,
+        "metadata/attested/document?api-version=2021-02-01",
+    )
+    .await
+}
+
+/// Query the events scheduled for the VM from the IMDS at `endpoint`.
+///
+/// Nothing in provisioning calls this; it is for consumers which want to
+/// check for e.g. an imminent reboot before going ahead. The first query
+/// can be slow while the service is enabled for the VM.
+pub async fn query_scheduled_events(
+    client: &Client,
+    endpoint: &str,
+) -> Result<ScheduledEvents, Error> {
+    get(
+        client,
+        endpoint,
+        "metadata/scheduledevents?api-version=2020-07-01",
+    )
+    .await
+}
+
+/// Wait for a preprovisioned VM to be assigned, returning the OVF
+/// environment it was assigned with.
+///
+/// The IMDS at `endpoint` is polled every `interval` until it provides the
+/// reprovisioning data, giving up with [`Error::PreprovisionTimeout`] once
+/// `timeout` elapses.
+pub async fn wait_for_reprovision_data(
+    client: &Client,
+    endpoint: &str,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<Environment, Error> {
+    let url = format!(
+        "{}/metadata/reprovisiondata?api-version=2019-06-01",
+        endpoint.trim_end_matches('/')
+    );
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let request = client
+            .get(&url)
+            .header("Metadata", HeaderValue::from_static("true"));
+        let response = http::send(request, attempt).await;
+        match response {
+            Ok(response) if response.status().is_success() => {
+                let ovf_body = response.text().await?;
+                return media::parse_ovf_env(&ovf_body);
+            }
+            Ok(response) => tracing::debug!(
+                status = %response.status(),
+                "VM has not been assigned yet"
+            ),
+            Err(error) => {
+                tracing::warn!(?error, "Failed to poll for reprovisioning data")
+            }
+        }
+
+        if tokio::time::Instant::now() + interval > deadline {
+            return Err(Error::PreprovisionTimeout { timeout });
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn get<T: DeserializeOwned>(
+    client: &Client,
+    endpoint: &str,
+    path: &str,
+) -> Result<T, Error> {
+    get_with_retry(client, endpoint, path, IMDS_ATTEMPTS, IMDS_RETRY_INTERVAL)
+        .await
+}
+
+/// Get `path` from the IMDS at `endpoint`, sending the request up to
+/// `attempts` times, `interval` apart, while IMDS answers with a status worth
+/// retrying. Any other status fails immediately.
+async fn get_with_retry<T: DeserializeOwned>(
+    client: &Client,
+    endpoint: &str,
+    path: &str,
+    attempts: u32,
+    interval: Duration,
+) -> Result<T, Error> {
+    let url = format!("{}/{}", endpoint.trim_end_matches('/'), path);
     let mut headers = HeaderMap::new();
 
     headers.insert("Metadata", HeaderValue::from_static("true"));
 
-    let request = client.get(url).headers(headers);
-    let response = request.send().await?;
+    let mut attempt = 1;
+    let response = loop {
+        let request = client.get(&url).headers(headers.clone());
+        let response = match http::send(request, attempt).await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() || e.is_connect() => {
+                return Err(Error::ImdsTimeout {
+                    endpoint: url,
+                    source: e,
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let status = response.status();
+        if !http::is_retriable(status) || attempt >= attempts {
+            break response;
+        }
+        tracing::warn!(%status, attempt, "IMDS is unavailable, retrying");
+        attempt += 1;
+        tokio::time::sleep(interval).await;
+    };
 
-    if response.status().is_success() {
-        let imds_body = response.text().await?;
-        let metadata: InstanceMetadata = serde_json::from_str(&imds_body)?;
+    if http::HARDFAIL_CODES.contains(&response.status()) {
+        tracing::error!(
+            status = %response.status(),
+            endpoint = url,
+            "IMDS rejected the request, not retrying"
+        );
+    }
+    match response.status() {
+        status if status.is_success() => {
+            let imds_body = response.text().await?;
+            let metadata: T = serde_json::from_str(&imds_body)?;
 
-        Ok(metadata)
-    } else {
-        Err(Error::HttpStatus {
-            endpoint: url.to_owned(),
-            status: response.status(),
-        })
+            Ok(metadata)
+        }
+        reqwest::StatusCode::NOT_FOUND => {
+            Err(Error::ImdsDisabled { endpoint: url })
+        }
+        status => Err(Error::ImdsHttp {
+            endpoint: url,
+            status,
+        }),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use reqwest::Client;
     use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
-    use super::{InstanceMetadata, OsProfile};
+    use super::{
+        get_with_retry, query, query_attested, query_network,
+        query_scheduled_events, query_via_proxy, query_with_fallback,
+        wait_for_reprovision_data, Compute, InstanceMetadata, Network,
+        OsProfile, ScheduledEvents, SecurityProfile, Tag, INSTANCE_PATH,
+    };
+    use crate::error::Error;
 
     #[test]
     fn instance_metadata_deserialization() {
@@ -151,17 +717,58 @@ mod tests {
             "ssh-rsa test_key2".to_string()
         );
         assert_eq!(
-            metadata.compute.os_profile.admin_username,
-            "MinProvAgentUser".to_string()
+            metadata.compute.os_profile.admin_username.as_deref(),
+            Some("MinProvAgentUser")
         );
         assert_eq!(
             metadata.compute.os_profile.computer_name,
             "AzTux-MinProvAgent-Test-0001".to_string()
         );
-        assert_eq!(
-            metadata.compute.os_profile.disable_password_authentication,
-            true
-        );
+        assert!(metadata.compute.os_profile.disable_password_authentication);
+        assert_eq!(metadata.compute.az_environment, "cloud_env");
+        assert_eq!(metadata.compute.location, "eastus");
+        assert_eq!(metadata.compute.zone, "");
+    }
+
+    #[test]
+    fn security_profile_confidential_vm() {
+        let compute = json!({
+            "osProfile": {
+                "adminUsername": "azureuser",
+                "computerName": "cvm-host",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": [],
+            "securityProfile": {
+                "encryptionAtHost": "false",
+                "secureBootEnabled": "true",
+                "securityType": "ConfidentialVM",
+                "virtualTpmEnabled": "true"
+            },
+            "vmSize": "Standard_DC2as_v5"
+        });
+        let compute: Compute = serde_json::from_value(compute).unwrap();
+
+        let security_profile = &compute.security_profile;
+        assert!(security_profile.secure_boot_enabled);
+        assert!(security_profile.virtual_tpm_enabled);
+        assert_eq!(security_profile.security_type, "ConfidentialVM");
+        assert!(security_profile.is_confidential());
+    }
+
+    #[test]
+    fn security_profile_missing() {
+        let compute = json!({
+            "osProfile": {
+                "computerName": "test-host",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": []
+        });
+        let compute: Compute = serde_json::from_value(compute).unwrap();
+
+        assert_eq!(compute.security_profile, SecurityProfile::default());
+        assert!(!compute.security_profile.is_confidential());
     }
 
     #[test]
@@ -183,7 +790,59 @@ mod tests {
             "disablePasswordAuthentication": "false"
         });
         let os_profile: OsProfile = serde_json::from_value(os_profile).unwrap();
-        assert_eq!(os_profile.disable_password_authentication, false);
+        assert!(!os_profile.disable_password_authentication);
+    }
+
+    #[test]
+    fn deserialization_admin_username_missing() {
+        let os_profile = json!({
+            "computerName": "AzTux-MinProvAgent-Test-0001",
+            "disablePasswordAuthentication": "true"
+        });
+        let os_profile: OsProfile = serde_json::from_value(os_profile).unwrap();
+        assert_eq!(os_profile.admin_username, None);
+    }
+
+    #[test]
+    fn os_profile_full() {
+        let os_profile = json!({
+            "adminUsername": "MinProvAgentUser",
+            "adminPassword": "hunter2",
+            "computerName": "AzTux-MinProvAgent-Test-0001",
+            "customData": "I2Nsb3VkLWNvbmZpZwo=",
+            "allowExtensionOperations": "false",
+            "disablePasswordAuthentication": "false",
+            "requireGuestProvisionSignal": "true",
+            "secrets": []
+        });
+        let os_profile: OsProfile = serde_json::from_value(os_profile).unwrap();
+        assert!(os_profile.has_admin_password());
+        assert_eq!(
+            os_profile.custom_data.as_deref(),
+            Some("I2Nsb3VkLWNvbmZpZwo=")
+        );
+        assert_eq!(os_profile.allow_extension_operations, Some(false));
+        assert!(!os_profile.disable_password_authentication);
+    }
+
+    #[test]
+    fn os_profile_minimal() {
+        let os_profile = json!({
+            "computerName": "AzTux-MinProvAgent-Test-0001",
+            "disablePasswordAuthentication": true,
+            "adminPassword": ""
+        });
+        let os_profile: OsProfile = serde_json::from_value(os_profile).unwrap();
+        assert!(!os_profile.has_admin_password());
+        assert_eq!(os_profile.custom_data, None);
+        assert_eq!(os_profile.allow_extension_operations, None);
+
+        let os_profile = json!({
+            "computerName": "AzTux-MinProvAgent-Test-0001",
+            "disablePasswordAuthentication": "true",
+            "allowExtensionOperations": "maybe"
+        });
+        assert!(serde_json::from_value::<OsProfile>(os_profile).is_err());
     }
 
     #[test]
@@ -197,4 +856,663 @@ mod tests {
             serde_json::from_value(os_profile);
         assert!(os_profile.is_err_and(|err| err.is_data()));
     }
+
+    /// The request lines a mock server received, in order.
+    type Requests = Arc<Mutex<Vec<String>>>;
+
+    /// Serve each of `responses`, a status line and body, to one connection
+    /// in turn on `bind`, then the last one to every later connection if
+    /// `repeat_last`.
+    async fn serve(
+        bind: &str,
+        responses: Vec<(&'static str, &'static str)>,
+        repeat_last: bool,
+    ) -> (std::net::SocketAddr, Requests) {
+        let listener = TcpListener::bind(bind).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Requests::default();
+        let received = requests.clone();
+        tokio::spawn(async move {
+            let last = responses.last().copied();
+            let repeated = last.filter(|_| repeat_last).into_iter().cycle();
+            for (status, body) in responses.into_iter().chain(repeated) {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let len = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..len]);
+                received.lock().unwrap().push(
+                    request.lines().next().unwrap_or_default().to_string(),
+                );
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nConnection: close\r\n\
+                     Content-Length: {}\r\n\r\n{body}",
+                    body.len(),
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (addr, requests)
+    }
+
+    /// Serve `body` to a single request.
+    async fn serve_once(
+        body: &'static str,
+    ) -> (std::net::SocketAddr, Requests) {
+        serve("127.0.0.1:0", vec![("200 OK", body)], false).await
+    }
+
+    /// Serve each of `responses`, a status line and body, to one connection
+    /// in turn.
+    async fn serve_sequence(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> std::net::SocketAddr {
+        serve("127.0.0.1:0", responses, false).await.0
+    }
+
+    #[tokio::test]
+    async fn reprovision_data_after_assignment() {
+        let ovf_body = r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure">
+            <wa:ProvisioningSection>
+                <wa:Version>1.0</wa:Version>
+                <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure">
+                    <UserName>assigneduser</UserName>
+                    <HostName>assigned-host</HostName>
+                </LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+            <wa:PlatformSettingsSection>
+                <wa:Version>1.0</wa:Version>
+                <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure">
+                    <PreprovisionedVm>false</PreprovisionedVm>
+                </PlatformSettings>
+            </wa:PlatformSettingsSection>
+        </Environment>"#;
+        let addr = serve_sequence(vec![
+            ("404 Not Found", ""),
+            ("404 Not Found", ""),
+            ("200 OK", ovf_body),
+        ])
+        .await;
+
+        let environment = wait_for_reprovision_data(
+            &Client::new(),
+            &format!("http://{addr}"),
+            Duration::from_secs(10),
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+
+        assert!(
+            !environment
+                .platform_settings_section
+                .platform_settings
+                .preprovisioned_vm
+        );
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .username,
+            "assigneduser"
+        );
+    }
+
+    #[tokio::test]
+    async fn reprovision_data_timeout() {
+        let addr = serve_sequence(vec![("404 Not Found", ""); 10]).await;
+
+        let result = wait_for_reprovision_data(
+            &Client::new(),
+            &format!("http://{addr}"),
+            Duration::from_millis(50),
+            Duration::from_millis(20),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::PreprovisionTimeout { .. })));
+    }
+
+    #[test]
+    fn builder_matches_deserialized() {
+        let body = r#"{
+            "compute": {
+                "osProfile": {
+                    "adminUsername": "MockUser",
+                    "computerName": "mock-host",
+                    "disablePasswordAuthentication": "false",
+                    "adminPassword": "hunter2"
+                },
+                "publicKeys": [
+                    {"keyData": "ssh-ed25519 first_key", "path": ""},
+                    {
+                        "keyData": "ssh-rsa second_key",
+                        "path": "/home/MockUser/.ssh/authorized_keys"
+                    }
+                ]
+            }
+        }"#;
+        let deserialized: InstanceMetadata =
+            serde_json::from_str(body).unwrap();
+
+        let built = InstanceMetadata::builder()
+            .admin_username("MockUser")
+            .computer_name("mock-host")
+            .public_key("ssh-ed25519 first_key", "")
+            .public_key(
+                "ssh-rsa second_key",
+                "/home/MockUser/.ssh/authorized_keys",
+            )
+            .disable_password_authentication(false)
+            .admin_password("hunter2")
+            .build();
+        assert_eq!(built, deserialized);
+    }
+
+    #[test]
+    fn builder_defaults() {
+        let metadata = InstanceMetadata::builder().build();
+        let os_profile = &metadata.compute.os_profile;
+        assert_eq!(os_profile.admin_username, None);
+        assert!(os_profile.disable_password_authentication);
+        assert!(!os_profile.has_admin_password());
+        assert!(metadata.compute.public_keys.is_empty());
+        assert!(metadata.tags().is_empty());
+    }
+
+    #[tokio::test]
+    async fn query_uses_endpoint() {
+        let body = r#"{
+            "compute": {
+                "osProfile": {
+                    "adminUsername": "MockUser",
+                    "computerName": "mock-host",
+                    "disablePasswordAuthentication": "true"
+                },
+                "publicKeys": []
+            }
+        }"#;
+        let (addr, requests) = serve_once(body).await;
+
+        let metadata = query(&Client::new(), &format!("http://{addr}/"))
+            .await
+            .unwrap();
+        assert_eq!(
+            metadata.compute.os_profile.admin_username.as_deref(),
+            Some("MockUser")
+        );
+        assert_eq!(metadata.compute.os_profile.computer_name, "mock-host");
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            "GET /metadata/instance?api-version=2021-02-01 HTTP/1.1"
+        );
+    }
+
+    /// An address which refuses connections.
+    async fn closed_addr() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn query_falls_back_to_ipv6() {
+        let body = r#"{
+            "compute": {
+                "osProfile": {
+                    "computerName": "ipv6-host",
+                    "disablePasswordAuthentication": "true"
+                },
+                "publicKeys": []
+            }
+        }"#;
+        let (ipv6, requests) =
+            serve("[::1]:0", vec![("200 OK", body)], false).await;
+
+        let metadata = query_with_fallback(
+            &Client::new(),
+            &format!("http://{}", closed_addr().await),
+            Some(&format!("http://{ipv6}")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata.compute.os_profile.computer_name, "ipv6-host");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn query_without_fallback_fails() {
+        let endpoint = format!("http://{}", closed_addr().await);
+        let error = query_with_fallback(&Client::new(), &endpoint, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::ImdsTimeout { .. }));
+
+        // A response, even an error, means IMDS was reached.
+        let addr =
+            serve_sequence(vec![("500 Internal Server Error", "")]).await;
+        let fallback = format!("http://{}", closed_addr().await);
+        let error = query_with_fallback(
+            &Client::new(),
+            &format!("http://{addr}"),
+            Some(&fallback),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, Error::ImdsHttp { .. }));
+    }
+
+    const PROXY_BODY: &str = r#"{
+        "compute": {
+            "osProfile": {
+                "computerName": "proxied-host",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": []
+        }
+    }"#;
+
+    const DIRECT_BODY: &str = r#"{
+        "compute": {
+            "osProfile": {
+                "computerName": "direct-host",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": []
+        }
+    }"#;
+
+    #[tokio::test]
+    async fn query_tries_proxy_first() {
+        let (proxy, proxy_requests) = serve_once(PROXY_BODY).await;
+        let (direct, direct_requests) = serve_once(DIRECT_BODY).await;
+
+        let metadata = query_via_proxy(
+            &Client::new(),
+            Some(&format!("http://{proxy}")),
+            &format!("http://{direct}"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata.compute.os_profile.computer_name, "proxied-host");
+        assert_eq!(
+            proxy_requests.lock().unwrap()[0],
+            "GET /metadata/instance?api-version=2021-02-01 HTTP/1.1"
+        );
+        // IMDS itself was never queried.
+        assert!(direct_requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn query_falls_back_from_proxy() {
+        let (direct, _) = serve_once(DIRECT_BODY).await;
+        let metadata = query_via_proxy(
+            &Client::new(),
+            Some(&format!("http://{}", closed_addr().await)),
+            &format!("http://{direct}"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata.compute.os_profile.computer_name, "direct-host");
+
+        // An error response from the proxy falls back too.
+        let proxy = serve_sequence(vec![("502 Bad Gateway", "")]).await;
+        let (direct, _) = serve_once(DIRECT_BODY).await;
+        let metadata = query_via_proxy(
+            &Client::new(),
+            Some(&format!("http://{proxy}")),
+            &format!("http://{direct}"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata.compute.os_profile.computer_name, "direct-host");
+    }
+
+    #[tokio::test]
+    async fn query_without_proxy_is_direct() {
+        let (direct, _) = serve_once(DIRECT_BODY).await;
+        let metadata = query_via_proxy(
+            &Client::new(),
+            None,
+            &format!("http://{direct}"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata.compute.os_profile.computer_name, "direct-host");
+    }
+
+    async fn query_error(status: &'static str) -> Error {
+        let addr = serve_sequence(vec![(status, "")]).await;
+        query(&Client::new(), &format!("http://{addr}"))
+            .await
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn query_not_found_is_disabled() {
+        let error = query_error("404 Not Found").await;
+        assert!(matches!(error, Error::ImdsDisabled { .. }));
+    }
+
+    #[tokio::test]
+    async fn query_server_error() {
+        let error = query_error("500 Internal Server Error").await;
+        match error {
+            Error::ImdsHttp { status, .. } => assert_eq!(status.as_u16(), 500),
+            _ => panic!("expected an HTTP error, got {error:?}"),
+        }
+    }
+
+    async fn query_with_retry(addr: std::net::SocketAddr) -> Error {
+        get_with_retry::<InstanceMetadata>(
+            &Client::new(),
+            &format!("http://{addr}"),
+            INSTANCE_PATH,
+            3,
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn query_bad_request_fails_immediately() {
+        let (addr, requests) =
+            serve("127.0.0.1:0", vec![("400 Bad Request", "")], true).await;
+        let error = query_with_retry(addr).await;
+        match error {
+            Error::ImdsHttp { status, .. } => assert_eq!(status.as_u16(), 400),
+            _ => panic!("expected an HTTP error, got {error:?}"),
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn query_unavailable_retried() {
+        let (addr, requests) =
+            serve("127.0.0.1:0", vec![("503 Service Unavailable", "")], true)
+                .await;
+        let error = query_with_retry(addr).await;
+        match error {
+            Error::ImdsHttp { status, .. } => assert_eq!(status.as_u16(), 503),
+            _ => panic!("expected an HTTP error, got {error:?}"),
+        }
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn query_succeeds_after_retry() {
+        let body = r#"{
+            "compute": {
+                "osProfile": {
+                    "computerName": "retried-host",
+                    "disablePasswordAuthentication": "true"
+                },
+                "publicKeys": []
+            }
+        }"#;
+        let addr = serve_sequence(vec![
+            ("429 Too Many Requests", ""),
+            ("503 Service Unavailable", ""),
+            ("200 OK", body),
+        ])
+        .await;
+        let metadata: InstanceMetadata = get_with_retry(
+            &Client::new(),
+            &format!("http://{addr}"),
+            INSTANCE_PATH,
+            3,
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(metadata.compute.os_profile.computer_name, "retried-host");
+    }
+
+    #[tokio::test]
+    async fn query_hang_times_out() {
+        // The kernel completes the connection, but nothing ever answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let error =
+            query(&client, &format!("http://{addr}")).await.unwrap_err();
+        assert!(matches!(error, Error::ImdsTimeout { .. }));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn attested_document_query() {
+        let body = r#"{
+            "encoding": "pkcs7",
+            "signature": "MIILPQYJKoZIhvcNAQcCoIILLjCCCyoCAQExDzANBgkqhkiG9w0BAQsFADCB"
+        }"#;
+        let (addr, requests) = serve_once(body).await;
+
+        let document =
+            query_attested(&Client::new(), &format!("http://{addr}"))
+                .await
+                .unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            "GET /metadata/attested/document?api-version=2021-02-01 HTTP/1.1"
+        );
+
+        assert_eq!(document.encoding, "pkcs7");
+        assert_eq!(
+            document.signature,
+            "MIILPQYJKoZIhvcNAQcCoIILLjCCCyoCAQExDzANBgkqhkiG9w0BAQsFADCB"
+        );
+    }
+
+    #[tokio::test]
+    async fn network_metadata_query() {
+        let body = r#"{
+            "interface": [
+                {
+                    "ipv4": {
+                        "ipAddress": [
+                            {
+                                "privateIpAddress": "10.0.0.4",
+                                "publicIpAddress": "20.51.1.10"
+                            },
+                            {
+                                "privateIpAddress": "10.0.0.5",
+                                "publicIpAddress": ""
+                            }
+                        ],
+                        "subnet": [
+                            { "address": "10.0.0.0", "prefix": "24" }
+                        ]
+                    },
+                    "ipv6": { "ipAddress": [] },
+                    "macAddress": "000D3A9B5C1E"
+                },
+                {
+                    "ipv4": {
+                        "ipAddress": [
+                            { "privateIpAddress": "10.1.0.4" }
+                        ],
+                        "subnet": [
+                            { "address": "10.1.0.0", "prefix": "24" }
+                        ]
+                    },
+                    "macAddress": "000D3A9B5C1F"
+                }
+            ]
+        }"#;
+        let (addr, requests) = serve_once(body).await;
+
+        let network = query_network(&Client::new(), &format!("http://{addr}"))
+            .await
+            .unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            "GET /metadata/instance/network?api-version=2021-02-01 HTTP/1.1"
+        );
+
+        assert_eq!(network.interface.len(), 2);
+        let primary = &network.interface[0];
+        assert_eq!(primary.mac_address, "000D3A9B5C1E");
+        assert_eq!(primary.ipv4.ip_address.len(), 2);
+        assert_eq!(primary.ipv4.ip_address[0].private_ip_address, "10.0.0.4");
+        assert_eq!(primary.ipv4.ip_address[0].public_ip_address, "20.51.1.10");
+        assert_eq!(primary.ipv4.ip_address[1].public_ip_address, "");
+        assert_eq!(primary.ipv4.subnet[0].prefix, "24");
+        assert!(primary.ipv6.ip_address.is_empty());
+
+        let secondary = &network.interface[1];
+        assert_eq!(secondary.ipv4.ip_address[0].private_ip_address, "10.1.0.4");
+        assert_eq!(secondary.ipv4.ip_address[0].public_ip_address, "");
+        assert_eq!(network.primary_private_ip(), Some("10.0.0.4"));
+        assert_eq!(Network::default().primary_private_ip(), None);
+    }
+
+    #[tokio::test]
+    async fn scheduled_events_query() {
+        let body = r#"{
+            "DocumentIncarnation": 3,
+            "Events": [
+                {
+                    "EventId": "C7061BAC-AFDC-4513-B24B-AA5F13A16123",
+                    "EventStatus": "Scheduled",
+                    "EventType": "Reboot",
+                    "ResourceType": "VirtualMachine",
+                    "Resources": ["myvm"],
+                    "NotBefore": "Mon, 19 Sep 2016 18:29:47 GMT",
+                    "Description": "Host server is undergoing maintenance.",
+                    "EventSource": "Platform",
+                    "DurationInSeconds": 9
+                },
+                {
+                    "EventId": "0D5C5A5B-6E8F-4B7A-9F0B-1E2D3C4B5A69",
+                    "EventStatus": "Started",
+                    "EventType": "Freeze",
+                    "Resources": ["myvm"],
+                    "NotBefore": ""
+                }
+            ]
+        }"#;
+        let (addr, requests) = serve_once(body).await;
+
+        let events =
+            query_scheduled_events(&Client::new(), &format!("http://{addr}"))
+                .await
+                .unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[0],
+            "GET /metadata/scheduledevents?api-version=2020-07-01 HTTP/1.1"
+        );
+
+        assert_eq!(events.document_incarnation, 3);
+        assert_eq!(events.events.len(), 2);
+        let reboot = &events.events[0];
+        assert_eq!(reboot.event_type, "Reboot");
+        assert_eq!(reboot.event_status, "Scheduled");
+        assert_eq!(reboot.resources, vec!["myvm"]);
+        assert_eq!(reboot.not_before, "Mon, 19 Sep 2016 18:29:47 GMT");
+        assert_eq!(reboot.event_source, "Platform");
+        assert_eq!(reboot.duration_in_seconds, 9);
+
+        let freeze = &events.events[1];
+        assert_eq!(freeze.event_status, "Started");
+        assert_eq!(freeze.resource_type, "");
+        assert_eq!(freeze.duration_in_seconds, -1);
+    }
+
+    #[test]
+    fn scheduled_events_empty() {
+        let events: ScheduledEvents =
+            serde_json::from_str(r#"{"DocumentIncarnation": 0, "Events": []}"#)
+                .unwrap();
+        assert_eq!(events, ScheduledEvents::default());
+    }
+
+    fn metadata_with_tags(tags: serde_json::Value) -> InstanceMetadata {
+        let mut compute = json!({
+            "osProfile": {
+                "adminUsername": "MinProvAgentUser",
+                "computerName": "AzTux-MinProvAgent-Test-0001",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": []
+        });
+        compute
+            .as_object_mut()
+            .unwrap()
+            .extend(tags.as_object().unwrap().clone());
+        serde_json::from_value(json!({ "compute": compute })).unwrap()
+    }
+
+    #[test]
+    fn tags_list_deserialization() {
+        let metadata = metadata_with_tags(json!({
+            "tags": "env:prod;team:core:infra",
+            "tagsList": [
+                { "name": "env", "value": "prod" },
+                { "name": "team", "value": "core:infra" }
+            ]
+        }));
+
+        let expected = vec![
+            Tag {
+                name: "env".to_string(),
+                value: "prod".to_string(),
+            },
+            Tag {
+                name: "team".to_string(),
+                value: "core:infra".to_string(),
+            },
+        ];
+        assert_eq!(metadata.compute.tags_list, expected);
+        assert_eq!(metadata.tags(), expected);
+    }
+
+    #[test]
+    fn tags_string_fallback() {
+        let metadata = metadata_with_tags(json!({
+            "tags": "env:prod;team:core:infra;flag",
+            "tagsList": []
+        }));
+
+        assert_eq!(
+            metadata.tags(),
+            vec![
+                Tag {
+                    name: "env".to_string(),
+                    value: "prod".to_string(),
+                },
+                Tag {
+                    name: "team".to_string(),
+                    value: "core:infra".to_string(),
+                },
+                Tag {
+                    name: "flag".to_string(),
+                    value: "".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tags_empty_or_missing() {
+        let metadata = metadata_with_tags(json!({
+            "tags": "",
+            "tagsList": []
+        }));
+        assert!(metadata.tags().is_empty());
+
+        let metadata = metadata_with_tags(json!({}));
+        assert_eq!(metadata.compute.tags, "");
+        assert!(metadata.tags().is_empty());
+    }
 }
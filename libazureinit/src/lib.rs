@@ -1,11 +1,24 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+pub mod cloud_init;
+pub mod config;
+pub mod container;
+pub mod custom_data;
+pub mod dhcp;
 pub mod distro;
 pub mod error;
 pub mod goalstate;
+mod http;
 pub mod imds;
+pub mod lock;
+pub mod machine_info;
 pub mod media;
+pub mod provision;
+mod rootfs;
+pub mod runner;
+pub mod selinux;
+pub mod status;
 pub mod user;
 
 // Re-export as the Client is used in our API.
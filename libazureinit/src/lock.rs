@@ -0,0 +1,71 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Making sure only one azure-init provisions the VM at a time.
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+
+use crate::error::Error;
+
+/// The lock held while provisioning. It's under `/run`, so a lock left
+/// behind by a crash doesn't outlive the boot.
+pub const PATH_LOCK: &str = "/run/azure-init.lock";
+
+/// An exclusive lock on a file, released when dropped.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _lock: Flock<File>,
+}
+
+/// Take the lock at `path`, creating the file if needed, without waiting.
+///
+/// Returns [`Error::AlreadyRunning`] if another instance holds the lock.
+pub fn try_lock(path: &Path) -> Result<InstanceLock, Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => Ok(InstanceLock { _lock: lock }),
+        Err((_, Errno::EWOULDBLOCK)) => Err(Error::AlreadyRunning {
+            path: path.to_path_buf(),
+        }),
+        Err((_, errno)) => Err(errno.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_instance_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init.lock");
+
+        let attempts: Vec<_> = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || try_lock(&path))
+            })
+            .collect();
+        // Each thread hands back the lock it took, so it's still held.
+        let attempts =
+            attempts.into_iter().map(|attempt| attempt.join().unwrap());
+        let (locked, running): (Vec<_>, Vec<_>) =
+            attempts.partition(Result::is_ok);
+        assert_eq!(locked.len(), 1);
+        assert!(matches!(
+            running.as_slice(),
+            [Err(Error::AlreadyRunning { .. })]
+        ));
+
+        drop(locked);
+        assert!(try_lock(&path).is_ok());
+    }
+}
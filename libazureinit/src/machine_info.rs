@@ -0,0 +1,125 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Writing Azure metadata to `/etc/machine-info`, as read by systemd's
+//! `hostnamectl`.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::imds::Compute;
+
+pub const PATH_MACHINE_INFO: &str = "/etc/machine-info";
+
+/// Set the chassis, deployment, location, and hardware model in the
+/// machine-info file at `path` from the instance's `compute` metadata.
+///
+/// Other settings in the file are preserved, and the file is left untouched
+/// when it already holds the values.
+pub fn write_machine_info(compute: &Compute, path: &Path) -> Result<(), Error> {
+    let existing = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let rendered = render(&existing, compute);
+    if rendered != existing {
+        tracing::info!(path = %path.display(), "Writing machine-info");
+        fs::write(path, rendered)?;
+    }
+
+    Ok(())
+}
+
+fn render(existing: &str, compute: &Compute) -> String {
+    let location = if compute.zone.is_empty() {
+        compute.location.clone()
+    } else {
+        format!("{} zone {}", compute.location, compute.zone)
+    };
+    let settings = [
+        ("CHASSIS", "vm".to_string()),
+        ("DEPLOYMENT", compute.az_environment.clone()),
+        ("LOCATION", location),
+        ("HARDWARE_MODEL", compute.vm_size.clone()),
+    ];
+    let ours = |line: &str| {
+        settings
+            .iter()
+            .any(|(key, _)| line.starts_with(&format!("{key}=")))
+    };
+
+    let mut rendered: String = existing
+        .lines()
+        .filter(|line| !ours(line))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    for (key, value) in settings {
+        if !value.is_empty() {
+            rendered
+                .push_str(&format!("{key}=\"{}\"\n", value.replace('"', "")));
+        }
+    }
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn compute(zone: &str) -> Compute {
+        serde_json::from_value(json!({
+            "azEnvironment": "AzurePublicCloud",
+            "location": "eastus",
+            "zone": zone,
+            "vmSize": "Standard_D2s_v3",
+            "osProfile": {
+                "adminUsername": "azureuser",
+                "computerName": "test-host",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": []
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn render_from_metadata() {
+        assert_eq!(
+            render("", &compute("1")),
+            "CHASSIS=\"vm\"\n\
+             DEPLOYMENT=\"AzurePublicCloud\"\n\
+             LOCATION=\"eastus zone 1\"\n\
+             HARDWARE_MODEL=\"Standard_D2s_v3\"\n"
+        );
+        assert!(render("", &compute("")).contains("LOCATION=\"eastus\"\n"));
+    }
+
+    #[test]
+    fn render_preserves_other_settings() {
+        let existing = "PRETTY_HOSTNAME=\"My VM\"\nLOCATION=\"westus\"\n";
+        let rendered = render(existing, &compute(""));
+
+        assert!(rendered.starts_with("PRETTY_HOSTNAME=\"My VM\"\n"));
+        assert!(rendered.contains("LOCATION=\"eastus\"\n"));
+        assert!(!rendered.contains("westus"));
+    }
+
+    #[test]
+    fn write_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("machine-info");
+
+        write_machine_info(&compute("2"), &path).unwrap();
+        let first = fs::read_to_string(&path).unwrap();
+        write_machine_info(&compute("2"), &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), first);
+        assert_eq!(first.matches("CHASSIS=").count(), 1);
+    }
+}
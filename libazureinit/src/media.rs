@@ -6,15 +6,20 @@ use std::fs::create_dir_all;
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use serde::Deserialize;
 use serde_xml_rs::from_str;
 
 use tracing;
 
+use crate::config;
 use crate::error::Error;
+use crate::runner::{run, run_with_timeout, CommandRunner, SystemRunner};
 
 #[derive(Debug, Default, Deserialize, PartialEq, Clone)]
 pub struct Environment {
@@ -40,6 +45,13 @@ pub struct LinuxProvisioningConfigurationSet {
     pub password: String,
     #[serde(rename = "HostName")]
     pub hostname: String,
+    /// Whether SSH password authentication should be disabled, which is
+    /// assumed not to be if the element is missing.
+    #[serde(default, rename = "DisableSshPasswordAuthentication")]
+    pub disable_ssh_password_authentication: bool,
+    /// SSH public keys for the user, which only NoCloud media provide.
+    #[serde(skip)]
+    pub ssh_authorized_keys: Vec<String>,
 }
 
 #[derive(Debug, Default, Deserialize, PartialEq, Clone)]
@@ -58,6 +70,36 @@ pub struct PlatformSettings {
     pub preprovisioned_vm_type: String,
 }
 
+/// NoCloud `meta-data`.
+#[derive(Debug, Default, Deserialize)]
+struct NoCloudMetaData {
+    #[serde(default, rename = "local-hostname")]
+    local_hostname: Option<String>,
+}
+
+/// The subset of a NoCloud `user-data` cloud-config which is supported.
+#[derive(Debug, Default, Deserialize)]
+struct CloudConfig {
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    users: Vec<CloudConfigUser>,
+    #[serde(default)]
+    ssh_authorized_keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum CloudConfigUser {
+    /// A bare name, usually `default` for the distribution's default user.
+    Name(String),
+    User {
+        name: String,
+        #[serde(default)]
+        ssh_authorized_keys: Vec<String>,
+    },
+}
+
 fn default_password() -> String {
     "".to_owned()
 }
@@ -75,27 +117,88 @@ pub const PATH_MOUNT_POINT: &str = "/run/azure-init/media/";
 
 const CDROM_VALID_FS: &[&str] = &["iso9660", "udf"];
 
-// Get a mounted device with any filesystem for CDROM
-pub fn get_mount_device() -> Result<Vec<String>, Error> {
-    let mut list_devices: Vec<String> = Vec::new();
+/// Filesystem labels of the Azure provisioning media, most preferred first.
+pub const AZURE_MEDIA_LABELS: &[&str] = &["OVF-ENV", "CDROM"];
+
+/// How long to wait for `mount` unless configured otherwise.
+pub const DEFAULT_MOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const PATH_DEVICES_BY_LABEL: &str = "/dev/disk/by-label";
 
-    while let Some(device) = block_utils::get_mounted_devices()?
+// Get the mounted devices with a CDROM filesystem, in the order they should
+// be tried.
+pub fn get_mount_device() -> Result<Vec<String>, Error> {
+    let devices: Vec<String> = block_utils::get_mounted_devices()?
         .into_iter()
-        .find(|dev| CDROM_VALID_FS.contains(&dev.fs_type.to_str()))
-    {
-        list_devices.push(device.name);
-    }
+        .filter(|dev| CDROM_VALID_FS.contains(&dev.fs_type.to_str()))
+        .map(|dev| format!("/dev/{}", dev.name))
+        .collect();
+
+    let by_label = Path::new(PATH_DEVICES_BY_LABEL);
+    Ok(prioritize_devices(devices, |device| {
+        device_label(by_label, Path::new(device))
+    }))
+}
+
+/// Order `devices` so those labelled with one of [`AZURE_MEDIA_LABELS`] come
+/// first, in the order of the labels, followed by the rest in their original
+/// order.
+pub fn prioritize_devices(
+    mut devices: Vec<String>,
+    label: impl Fn(&str) -> Option<String>,
+) -> Vec<String> {
+    devices.sort_by_cached_key(|device| {
+        label(device)
+            .and_then(|label| {
+                AZURE_MEDIA_LABELS.iter().position(|known| *known == label)
+            })
+            .unwrap_or(AZURE_MEDIA_LABELS.len())
+    });
 
-    Ok(list_devices)
+    devices
+}
+
+/// Find the filesystem label of `device` among the symlinks in `by_label`.
+fn device_label(by_label: &Path, device: &Path) -> Option<String> {
+    let device = fs::canonicalize(device).ok()?;
+
+    fs::read_dir(by_label)
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|entry| {
+            fs::canonicalize(entry.path()).ok().as_ref() == Some(&device)
+        })
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+}
+
+/// Parse the environment from each of `devices` in turn, returning the first
+/// which succeeds.
+pub fn first_environment(
+    devices: Vec<String>,
+    mut parse: impl FnMut(String) -> Result<Environment, Error>,
+) -> Option<Environment> {
+    devices.into_iter().find_map(|device| {
+        parse(device.clone())
+            .map_err(|error| {
+                tracing::warn!(%device, ?error, "No usable OVF environment")
+            })
+            .ok()
+    })
 }
 
 // Some zero-sized structs that just provide states for our state machine
 pub struct Mounted;
 pub struct Unmounted;
 
+/// Provisioning media, which is unmounted and ejected when dropped if it
+/// was left mounted, e.g. on an error path.
 pub struct Media<State = Unmounted> {
     device_path: PathBuf,
     mount_path: PathBuf,
+    runner: Arc<dyn CommandRunner>,
+    mount_timeout: Duration,
+    /// Whether the media still needs unmounting when dropped.
+    mounted: bool,
     state: std::marker::PhantomData<State>,
 }
 
@@ -104,11 +207,67 @@ impl Media<Unmounted> {
         Media {
             device_path,
             mount_path,
+            runner: Arc::new(SystemRunner),
+            mount_timeout: DEFAULT_MOUNT_TIMEOUT,
+            mounted: false,
             state: std::marker::PhantomData,
         }
     }
 
+    /// Run the mount commands with `runner` rather than on the host.
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Give up on an attempt to mount the media after `timeout`, rather
+    /// than [`DEFAULT_MOUNT_TIMEOUT`], e.g. when the device is stuck.
+    pub fn with_mount_timeout(mut self, timeout: Duration) -> Self {
+        self.mount_timeout = timeout;
+        self
+    }
+
     pub fn mount(self) -> Result<Media<Mounted>, Error> {
+        self.mount_with_retries(0, Duration::ZERO)
+    }
+
+    /// Mount the media, trying up to `retries` more times, `interval`
+    /// apart, if it fails.
+    ///
+    /// Early in boot the device may not be mountable yet, e.g. while udev
+    /// is still processing it.
+    pub fn mount_with_retries(
+        mut self,
+        retries: u32,
+        interval: Duration,
+    ) -> Result<Media<Mounted>, Error> {
+        let mut attempt = 0;
+        while let Err(error) = self.try_mount() {
+            if attempt == retries {
+                return Err(error);
+            }
+            attempt += 1;
+            tracing::warn!(
+                device = %self.device_path.display(),
+                ?error,
+                attempt,
+                retries,
+                "Failed to mount media, retrying"
+            );
+            thread::sleep(interval);
+        }
+
+        Ok(Media {
+            device_path: std::mem::take(&mut self.device_path),
+            mount_path: std::mem::take(&mut self.mount_path),
+            runner: self.runner.clone(),
+            mount_timeout: self.mount_timeout,
+            mounted: true,
+            state: std::marker::PhantomData,
+        })
+    }
+
+    fn try_mount(&self) -> Result<(), Error> {
         create_dir_all(&self.mount_path)?;
 
         let metadata = fs::metadata(&self.mount_path)?;
@@ -117,49 +276,53 @@ impl Media<Unmounted> {
         new_permissions.set_mode(0o700);
         fs::set_permissions(&self.mount_path, new_permissions)?;
 
-        let mount_status = Command::new("mount")
+        let mut command = Command::new("mount");
+        command
             .arg("-o")
             .arg("ro")
             .arg(&self.device_path)
-            .arg(&self.mount_path)
-            .status()?;
+            .arg(&self.mount_path);
+        run_with_timeout(self.runner.as_ref(), command, self.mount_timeout)?;
 
-        if !mount_status.success() {
-            Err(Error::SubprocessFailed {
-                command: "mount".to_string(),
-                status: mount_status,
-            })
-        } else {
-            Ok(Media {
-                device_path: self.device_path,
-                mount_path: self.mount_path,
-                state: std::marker::PhantomData,
-            })
-        }
+        Ok(())
     }
 }
 
 impl Media<Mounted> {
-    pub fn unmount(self) -> Result<(), Error> {
-        let umount_status =
-            Command::new("umount").arg(self.mount_path).status()?;
-        if !umount_status.success() {
-            return Err(Error::SubprocessFailed {
-                command: "umount".to_string(),
-                status: umount_status,
-            });
-        }
+    /// Unmount and eject the media.
+    ///
+    /// Only a failure to unmount is an error; ejecting is best-effort since
+    /// some devices (e.g. virtio-blk) can't be ejected, and the provisioning
+    /// data has already been read by this point.
+    pub fn unmount(mut self) -> Result<(), Error> {
+        // Whether or not this succeeds, don't try again on drop.
+        self.mounted = false;
+        unmount_and_eject(
+            self.runner.as_ref(),
+            &self.device_path,
+            &self.mount_path,
+        )
+    }
 
-        let eject_status =
-            Command::new("eject").arg(self.device_path).status()?;
-        if !eject_status.success() {
-            Err(Error::SubprocessFailed {
-                command: "eject".to_string(),
-                status: eject_status,
-            })
-        } else {
-            Ok(())
-        }
+    /// Leave the media mounted, returning where it's mounted.
+    pub fn keep_mounted(mut self) -> PathBuf {
+        self.mounted = false;
+        std::mem::take(&mut self.mount_path)
+    }
+
+    /// Read the NoCloud `meta-data` and, if present, `user-data`.
+    pub fn read_nocloud(&self) -> Result<Environment, Error> {
+        let meta_data = fs::read_to_string(self.mount_path.join("meta-data"))?;
+        let user_data =
+            match fs::read_to_string(self.mount_path.join("user-data")) {
+                Ok(user_data) => user_data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    String::new()
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+        parse_nocloud(&meta_data, &user_data)
     }
 
     pub fn read_ovf_env_to_string(&self) -> Result<String, Error> {
@@ -174,8 +337,144 @@ impl Media<Mounted> {
     }
 }
 
+impl<State> Drop for Media<State> {
+    fn drop(&mut self) {
+        if !self.mounted {
+            return;
+        }
+
+        tracing::warn!(
+            mount_path = %self.mount_path.display(),
+            "Media dropped while mounted, unmounting it"
+        );
+        if let Err(error) = unmount_and_eject(
+            self.runner.as_ref(),
+            &self.device_path,
+            &self.mount_path,
+        ) {
+            tracing::error!(?error, "Failed to remove media.");
+        }
+    }
+}
+
+fn unmount_and_eject(
+    runner: &dyn CommandRunner,
+    device_path: &Path,
+    mount_path: &Path,
+) -> Result<(), Error> {
+    let mut command = Command::new("umount");
+    command.arg(mount_path);
+    run(runner, command)?;
+
+    eject(runner, device_path);
+
+    Ok(())
+}
+
+fn eject(runner: &dyn CommandRunner, device_path: &Path) {
+    let mut command = Command::new("eject");
+    command.arg(device_path);
+    if let Err(e) = run(runner, command) {
+        tracing::warn!(?device_path, error = ?e, "Failed to eject media");
+    }
+}
+
+/// Just enough of an OVF environment to find its schema version.
+#[derive(Debug, Default, Deserialize)]
+struct OvfVersion {
+    #[serde(default, rename = "ProvisioningSection")]
+    provisioning_section: OvfSectionVersion,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OvfSectionVersion {
+    #[serde(default, rename = "Version")]
+    version: String,
+}
+
+/// An OVF environment in the 2.0 shape, which names the user and host after
+/// the VM's OS profile.
+#[derive(Debug, Deserialize)]
+struct EnvironmentV2 {
+    #[serde(rename = "ProvisioningSection")]
+    provisioning_section: ProvisioningSectionV2,
+    #[serde(default, rename = "PlatformSettingsSection")]
+    platform_settings_section: PlatformSettingsSection,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProvisioningSectionV2 {
+    #[serde(rename = "Version")]
+    version: String,
+    #[serde(rename = "LinuxProvisioningConfigurationSet")]
+    linux_prov_conf_set: LinuxProvisioningConfigurationSetV2,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinuxProvisioningConfigurationSetV2 {
+    #[serde(rename = "AdminUsername")]
+    admin_username: String,
+    #[serde(default, rename = "AdminPassword")]
+    admin_password: String,
+    #[serde(rename = "ComputerName")]
+    computer_name: String,
+    #[serde(default, rename = "DisableSshPasswordAuthentication")]
+    disable_ssh_password_authentication: bool,
+}
+
+impl From<EnvironmentV2> for Environment {
+    fn from(environment: EnvironmentV2) -> Self {
+        let section = environment.provisioning_section;
+        Environment {
+            provisioning_section: ProvisioningSection {
+                version: section.version,
+                linux_prov_conf_set: LinuxProvisioningConfigurationSet {
+                    username: section.linux_prov_conf_set.admin_username,
+                    password: section.linux_prov_conf_set.admin_password,
+                    hostname: section.linux_prov_conf_set.computer_name,
+                    disable_ssh_password_authentication: section
+                        .linux_prov_conf_set
+                        .disable_ssh_password_authentication,
+                    ssh_authorized_keys: vec![],
+                },
+            },
+            platform_settings_section: environment.platform_settings_section,
+        }
+    }
+}
+
+type OvfParser = fn(&str) -> Result<Environment, Error>;
+
+/// The parser for each major version of the OVF schema.
+const OVF_PARSERS: &[(&str, OvfParser)] =
+    &[("1", parse_ovf_v1), ("2", parse_ovf_v2)];
+
+fn parse_ovf_v1(ovf_body: &str) -> Result<Environment, Error> {
+    Ok(from_str(ovf_body)?)
+}
+
+fn parse_ovf_v2(ovf_body: &str) -> Result<Environment, Error> {
+    let environment: EnvironmentV2 = from_str(ovf_body)?;
+    Ok(environment.into())
+}
+
+/// Parse an OVF environment, with the parser for the schema version its
+/// provisioning section declares.
+///
+/// Only the major version is considered, and unknown versions are parsed as
+/// 1.0.
 pub fn parse_ovf_env(ovf_body: &str) -> Result<Environment, Error> {
-    let environment: Environment = from_str(ovf_body)?;
+    let declared: OvfVersion = from_str(ovf_body)?;
+    let version = declared.provisioning_section.version;
+    let major = version.trim().split('.').next().unwrap_or_default();
+    let parse = match OVF_PARSERS.iter().find(|(known, _)| *known == major) {
+        Some((_, parse)) => *parse,
+        None => {
+            tracing::warn!(%version, "Unknown OVF schema version, parsing as 1.0");
+            parse_ovf_v1
+        }
+    };
+    let environment = parse(ovf_body)?;
 
     if !environment
         .provisioning_section
@@ -189,29 +488,267 @@ pub fn parse_ovf_env(ovf_body: &str) -> Result<Environment, Error> {
     }
 }
 
+/// Build an [`Environment`] from NoCloud `meta-data` and `user-data`.
+///
+/// The hostname in the user-data takes precedence over the meta-data's
+/// `local-hostname`. The first named user is provisioned, with its keys and
+/// any top-level `ssh_authorized_keys`.
+pub fn parse_nocloud(
+    meta_data: &str,
+    user_data: &str,
+) -> Result<Environment, Error> {
+    let meta_data: NoCloudMetaData = if meta_data.trim().is_empty() {
+        NoCloudMetaData::default()
+    } else {
+        serde_yaml::from_str(meta_data)?
+    };
+    let cloud_config: CloudConfig = if user_data.trim().is_empty() {
+        CloudConfig::default()
+    } else {
+        serde_yaml::from_str(user_data)?
+    };
+
+    let (username, mut keys) = cloud_config
+        .users
+        .into_iter()
+        .find_map(|user| match user {
+            CloudConfigUser::User {
+                name,
+                ssh_authorized_keys,
+            } => Some((name, ssh_authorized_keys)),
+            CloudConfigUser::Name(name) => {
+                tracing::debug!(%name, "Skipping NoCloud user without settings");
+                None
+            }
+        })
+        .ok_or(Error::NoCloudUserMissing)?;
+    keys.extend(cloud_config.ssh_authorized_keys);
+
+    let mut environment = Environment::default();
+    let linux_prov_conf_set =
+        &mut environment.provisioning_section.linux_prov_conf_set;
+    linux_prov_conf_set.username = username;
+    linux_prov_conf_set.hostname = cloud_config
+        .hostname
+        .or(meta_data.local_hostname)
+        .unwrap_or_default();
+    linux_prov_conf_set.ssh_authorized_keys = keys;
+
+    Ok(environment)
+}
+
 // Mount the given device, get OVF environment data, return it.
 pub fn mount_parse_ovf_env(dev: String) -> Result<Environment, Error> {
-    let mount_media =
-        Media::new(PathBuf::from(dev), PathBuf::from(PATH_MOUNT_POINT));
-    let mounted = mount_media.mount().map_err(|e| {
-        tracing::error!(error = ?e, "Failed to mount media.");
-        e
-    })?;
+    mount_parse_ovf_env_at(dev, Path::new(PATH_MOUNT_POINT))
+}
+
+/// Mount the given device at `mount_path` and parse its OVF environment.
+///
+/// Unlike [`mount_parse_ovf_env`], several devices can be probed at once by
+/// giving each its own mount point.
+pub fn mount_parse_ovf_env_at(
+    dev: String,
+    mount_path: &Path,
+) -> Result<Environment, Error> {
+    parse_media(
+        Media::new(PathBuf::from(dev), mount_path.into()),
+        &config::ProvisioningMedia::default(),
+    )
+}
+
+/// Mount the given device and read its provisioning data, as configured by
+/// `settings`.
+///
+/// Mounting is retried and timed out as configured. If `settings.nocloud` is set, NoCloud
+/// data is read when the media has no OVF environment.
+pub fn mount_parse_environment(
+    dev: String,
+    settings: &config::ProvisioningMedia,
+) -> Result<Environment, Error> {
+    parse_media(
+        Media::new(PathBuf::from(dev), PathBuf::from(PATH_MOUNT_POINT)),
+        settings,
+    )
+}
+
+fn parse_media(
+    mount_media: Media<Unmounted>,
+    settings: &config::ProvisioningMedia,
+) -> Result<Environment, Error> {
+    let mounted = mount_media
+        .with_mount_timeout(Duration::from_secs_f64(
+            settings.mount_timeout_secs,
+        ))
+        .mount_with_retries(
+            settings.mount_retries,
+            Duration::from_millis(settings.mount_retry_interval_ms),
+        )
+        .map_err(|e| {
+            tracing::error!(error = ?e, "Failed to mount media.");
+            e
+        })?;
+
+    let environment = match mounted.read_ovf_env_to_string() {
+        Ok(ovf_body) => parse_ovf_env(ovf_body.as_str()),
+        Err(Error::Io(e))
+            if settings.nocloud && e.kind() == std::io::ErrorKind::NotFound =>
+        {
+            tracing::info!("No OVF environment, reading NoCloud data");
+            mounted.read_nocloud()
+        }
+        Err(e) => Err(e),
+    };
 
-    let ovf_body = mounted.read_ovf_env_to_string()?;
-    let environment = parse_ovf_env(ovf_body.as_str())?;
+    if settings.keep_mounted {
+        let mount_path = mounted.keep_mounted();
+        tracing::info!(
+            mount_path = %mount_path.display(),
+            "Leaving the provisioning media mounted"
+        );
+        return environment;
+    }
 
     mounted.unmount().map_err(|e| {
         tracing::error!(error = ?e, "Failed to remove media.");
         e
     })?;
 
-    Ok(environment)
+    environment
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+
     use super::*;
+    use crate::runner::mock::MockRunner;
+
+    #[test]
+    fn nocloud_parsing() {
+        let meta_data = "instance-id: iid-local01\nlocal-hostname: meta-host\n";
+        let user_data = r#"#cloud-config
+hostname: user-host
+users:
+  - default
+  - name: clouduser
+    sudo: ALL=(ALL) NOPASSWD:ALL
+    ssh_authorized_keys:
+      - ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHN1 clouduser@host
+ssh_authorized_keys:
+  - ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ shared@host
+"#;
+
+        let environment = parse_nocloud(meta_data, user_data).unwrap();
+        let linux_prov_conf_set =
+            environment.provisioning_section.linux_prov_conf_set;
+        assert_eq!(linux_prov_conf_set.username, "clouduser");
+        assert_eq!(linux_prov_conf_set.hostname, "user-host");
+        assert_eq!(linux_prov_conf_set.password, "");
+        assert_eq!(
+            linux_prov_conf_set.ssh_authorized_keys,
+            vec![
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHN1 clouduser@host",
+                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ shared@host",
+            ]
+        );
+    }
+
+    #[test]
+    fn nocloud_hostname_from_meta_data() {
+        let environment = parse_nocloud(
+            "local-hostname: meta-host\n",
+            "#cloud-config\nusers:\n  - name: clouduser\n",
+        )
+        .unwrap();
+        let linux_prov_conf_set =
+            environment.provisioning_section.linux_prov_conf_set;
+        assert_eq!(linux_prov_conf_set.hostname, "meta-host");
+        assert!(linux_prov_conf_set.ssh_authorized_keys.is_empty());
+    }
+
+    #[test]
+    fn nocloud_requires_user() {
+        let result = parse_nocloud("local-hostname: meta-host\n", "");
+        assert!(matches!(result, Err(Error::NoCloudUserMissing)));
+
+        let result = parse_nocloud("", "#cloud-config\nusers:\n  - default\n");
+        assert!(matches!(result, Err(Error::NoCloudUserMissing)));
+    }
+
+    #[test]
+    fn devices_prioritized_by_label() {
+        let devices = vec![
+            "/dev/sr2".to_string(),
+            "/dev/sr1".to_string(),
+            "/dev/sr0".to_string(),
+            "/dev/sr3".to_string(),
+        ];
+        let label = |device: &str| match device {
+            "/dev/sr0" => Some("CDROM".to_string()),
+            "/dev/sr1" => Some("OVF-ENV".to_string()),
+            "/dev/sr3" => Some("DATA".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            prioritize_devices(devices, label),
+            vec!["/dev/sr1", "/dev/sr0", "/dev/sr2", "/dev/sr3"]
+        );
+    }
+
+    #[test]
+    fn first_valid_environment_wins() {
+        let attempts = RefCell::new(vec![]);
+        let devices = prioritize_devices(
+            vec![
+                "/dev/sr0".to_string(),
+                "/dev/sr1".to_string(),
+                "/dev/sr2".to_string(),
+            ],
+            |device| (device == "/dev/sr2").then(|| "OVF-ENV".to_string()),
+        );
+
+        let environment = first_environment(devices, |device| {
+            attempts.borrow_mut().push(device.clone());
+            match device.as_str() {
+                "/dev/sr2" => Err(Error::NonEmptyPassword),
+                _ => {
+                    let mut environment = Environment::default();
+                    environment
+                        .provisioning_section
+                        .linux_prov_conf_set
+                        .hostname = device;
+                    Ok(environment)
+                }
+            }
+        })
+        .unwrap();
+
+        // The labelled device is tried first; the next valid one is used and
+        // nothing after it is tried.
+        assert_eq!(attempts.into_inner(), vec!["/dev/sr2", "/dev/sr0"]);
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .hostname,
+            "/dev/sr0"
+        );
+    }
+
+    #[test]
+    fn label_from_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let by_label = dir.path().join("by-label");
+        fs::create_dir(&by_label).unwrap();
+        let device = dir.path().join("sr0");
+        File::create(&device).unwrap();
+        std::os::unix::fs::symlink(&device, by_label.join("CDROM")).unwrap();
+
+        assert_eq!(device_label(&by_label, &device), Some("CDROM".into()));
+        assert_eq!(device_label(&by_label, &dir.path().join("sr1")), None);
+        assert_eq!(device_label(&dir.path().join("missing"), &device), None);
+    }
 
     #[test]
     fn test_get_ovf_env_none_missing() {
@@ -270,12 +807,11 @@ mod tests {
                 .hostname,
             "myhostname"
         );
-        assert_eq!(
-            environment
+        assert!(
+            !environment
                 .platform_settings_section
                 .platform_settings
-                .preprovisioned_vm,
-            false
+                .preprovisioned_vm
         );
         assert_eq!(
             environment
@@ -343,12 +879,11 @@ mod tests {
                 .hostname,
             "myhostname"
         );
-        assert_eq!(
-            environment
+        assert!(
+            !environment
                 .platform_settings_section
                 .platform_settings
-                .preprovisioned_vm,
-            false
+                .preprovisioned_vm
         );
         assert_eq!(
             environment
@@ -396,4 +931,448 @@ mod tests {
             _ => panic!("Non-empty passwords aren't allowed"),
         };
     }
+
+    #[test]
+    fn ovf_schema_v2() {
+        let ovf_body = r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure">
+            <wa:ProvisioningSection>
+                <wa:Version>2.0</wa:Version>
+                <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure">
+                    <AdminUsername>myusername</AdminUsername>
+                    <ComputerName>myhostname</ComputerName>
+                </LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+        </Environment>"#;
+
+        let environment = parse_ovf_env(ovf_body).unwrap();
+        let linux_prov_conf_set =
+            environment.provisioning_section.linux_prov_conf_set;
+        assert_eq!(linux_prov_conf_set.username, "myusername");
+        assert_eq!(linux_prov_conf_set.hostname, "myhostname");
+        assert_eq!(linux_prov_conf_set.password, "");
+        assert!(!linux_prov_conf_set.disable_ssh_password_authentication);
+        assert!(
+            !environment
+                .platform_settings_section
+                .platform_settings
+                .preprovisioned_vm
+        );
+
+        let password_auth_disabled = ovf_body.replace(
+            "<ComputerName>",
+            "<DisableSshPasswordAuthentication>true</DisableSshPasswordAuthentication><ComputerName>",
+        );
+        assert!(
+            parse_ovf_env(&password_auth_disabled)
+                .unwrap()
+                .provisioning_section
+                .linux_prov_conf_set
+                .disable_ssh_password_authentication
+        );
+
+        let with_password = ovf_body.replace(
+            "<ComputerName>",
+            "<AdminPassword>secret</AdminPassword><ComputerName>",
+        );
+        assert!(matches!(
+            parse_ovf_env(&with_password),
+            Err(Error::NonEmptyPassword)
+        ));
+    }
+
+    #[test]
+    fn ovf_schema_version_tolerated() {
+        let ovf_body = r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure">
+            <wa:ProvisioningSection>
+                <wa:Version>VERSION</wa:Version>
+                <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure">
+                    <UserName>myusername</UserName>
+                    <HostName>myhostname</HostName>
+                </LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+            <wa:PlatformSettingsSection>
+                <wa:Version>1.0</wa:Version>
+                <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure">
+                </PlatformSettings>
+            </wa:PlatformSettingsSection>
+        </Environment>"#;
+
+        // A newer minor version, or an unknown one, is parsed as 1.0.
+        for version in ["1.1", "9.0"] {
+            let environment =
+                parse_ovf_env(&ovf_body.replace("VERSION", version)).unwrap();
+            let linux_prov_conf_set =
+                environment.provisioning_section.linux_prov_conf_set;
+            assert_eq!(linux_prov_conf_set.username, "myusername");
+            assert_eq!(linux_prov_conf_set.hostname, "myhostname");
+        }
+    }
+
+    #[test]
+    fn eject_failure_is_not_fatal() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = Arc::new(MockRunner::failing("eject", 1));
+
+        let media = Media::new(PathBuf::from("/dev/sr0"), dir.path().into())
+            .with_runner(runner.clone())
+            .mount()
+            .unwrap();
+        media.unmount().unwrap();
+
+        let mount_path = dir.path().to_str().unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                vec!["mount", "-o", "ro", "/dev/sr0", mount_path],
+                vec!["umount", mount_path],
+                vec!["eject", "/dev/sr0"],
+            ]
+        );
+    }
+
+    #[test]
+    fn umount_failure_is_fatal() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = Arc::new(MockRunner::failing("umount", 32));
+
+        let media = Media::new(PathBuf::from("/dev/sr0"), dir.path().into())
+            .with_runner(runner.clone())
+            .mount()
+            .unwrap();
+        let result = media.unmount();
+
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+        // Nothing is ejected if the media is still mounted.
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn mount_retried() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let dir = tempfile::tempdir().unwrap();
+        // Fail the first two mounts, as if the device weren't ready yet.
+        let failures = AtomicU32::new(2);
+        let runner = Arc::new(MockRunner::new(move |argv| {
+            let failing = argv[0] == "mount"
+                && failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        n.checked_sub(1)
+                    })
+                    .is_ok();
+            let code = if failing { 32 } else { 0 };
+            Ok(crate::runner::mock::output(code, ""))
+        }));
+
+        let media = Media::new(PathBuf::from("/dev/sr0"), dir.path().into())
+            .with_runner(runner.clone())
+            .mount_with_retries(2, Duration::ZERO)
+            .unwrap();
+        media.unmount().unwrap();
+
+        let programs: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| argv[0].clone())
+            .collect();
+        assert_eq!(
+            programs,
+            vec!["mount", "mount", "mount", "umount", "eject"]
+        );
+    }
+
+    #[test]
+    fn mount_retries_exhausted() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = Arc::new(MockRunner::failing("mount", 32));
+
+        let result = Media::new(PathBuf::from("/dev/sr0"), dir.path().into())
+            .with_runner(runner.clone())
+            .mount_with_retries(2, Duration::ZERO);
+
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+        assert_eq!(runner.calls().len(), 3);
+    }
+
+    #[test]
+    fn hung_mount_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        // The first device never finishes mounting.
+        let runner = Arc::new(MockRunner::new(|argv| {
+            if argv[0] == "mount" && argv[3] == "/dev/sr0" {
+                thread::sleep(Duration::from_secs(10));
+            }
+            Ok(crate::runner::mock::output(0, ""))
+        }));
+        let sr1 = dir.path().join("sr1");
+        fs::create_dir(&sr1).unwrap();
+        fs::write(
+            sr1.join("ovf-env.xml"),
+            r#"<Environment>
+                <ProvisioningSection>
+                    <Version>1.0</Version>
+                    <LinuxProvisioningConfigurationSet>
+                        <UserName>myusername</UserName>
+                        <HostName>myhostname</HostName>
+                    </LinuxProvisioningConfigurationSet>
+                </ProvisioningSection>
+                <PlatformSettingsSection>
+                    <Version>1.0</Version>
+                    <PlatformSettings>
+                        <ProvisionGuestAgent>false</ProvisionGuestAgent>
+                    </PlatformSettings>
+                </PlatformSettingsSection>
+            </Environment>"#,
+        )
+        .unwrap();
+        let settings = config::ProvisioningMedia {
+            mount_retries: 0,
+            mount_timeout_secs: 0.1,
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        let devices = vec!["/dev/sr0".to_string(), "/dev/sr1".to_string()];
+        let environment = first_environment(devices, |device| {
+            let mount_path = dir.path().join(&device[5..]);
+            let media = Media::new(PathBuf::from(device), mount_path)
+                .with_runner(runner.clone());
+            parse_media(media, &settings)
+        })
+        .unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .hostname,
+            "myhostname"
+        );
+        let mounted: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .filter(|argv| argv[0] == "mount")
+            .map(|argv| argv[3].clone())
+            .collect();
+        assert_eq!(mounted, vec!["/dev/sr0", "/dev/sr1"]);
+    }
+
+    #[test]
+    fn mount_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = Arc::new(MockRunner::failing("mount", 32));
+
+        let result =
+            Media::new(PathBuf::from("/dev/sr0"), dir.path().join("media"))
+                .with_runner(runner)
+                .mount();
+
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+    }
+
+    #[test]
+    fn ovf_env_parsed_at_mount_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount_path = dir.path().join("sr0");
+        // The mock "mount" leaves the staged file in place, as if it were
+        // on the media.
+        fs::create_dir(&mount_path).unwrap();
+        fs::write(
+            mount_path.join("ovf-env.xml"),
+            r#"<Environment>
+                <ProvisioningSection>
+                    <Version>1.0</Version>
+                    <LinuxProvisioningConfigurationSet>
+                        <UserName>myusername</UserName>
+                        <HostName>myhostname</HostName>
+                    </LinuxProvisioningConfigurationSet>
+                </ProvisioningSection>
+                <PlatformSettingsSection>
+                    <Version>1.0</Version>
+                    <PlatformSettings>
+                        <ProvisionGuestAgent>false</ProvisionGuestAgent>
+                    </PlatformSettings>
+                </PlatformSettingsSection>
+            </Environment>"#,
+        )
+        .unwrap();
+        let runner = Arc::new(MockRunner::succeeding());
+
+        let media = Media::new(PathBuf::from("/dev/sr0"), mount_path.clone())
+            .with_runner(runner.clone());
+        let environment =
+            parse_media(media, &config::ProvisioningMedia::default()).unwrap();
+
+        let linux_prov_conf_set =
+            environment.provisioning_section.linux_prov_conf_set;
+        assert_eq!(linux_prov_conf_set.username, "myusername");
+        assert_eq!(linux_prov_conf_set.hostname, "myhostname");
+        let mount_path = mount_path.to_str().unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                vec!["mount", "-o", "ro", "/dev/sr0", mount_path],
+                vec!["umount", mount_path],
+                vec!["eject", "/dev/sr0"],
+            ]
+        );
+    }
+
+    #[test]
+    fn unmounted_on_parse_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount_path = dir.path().join("sr0");
+        fs::create_dir(&mount_path).unwrap();
+        fs::write(mount_path.join("ovf-env.xml"), "<Environment>").unwrap();
+        let runner = Arc::new(MockRunner::succeeding());
+
+        let media = Media::new(PathBuf::from("/dev/sr0"), mount_path.clone())
+            .with_runner(runner.clone());
+        assert!(
+            parse_media(media, &config::ProvisioningMedia::default()).is_err()
+        );
+
+        let mount_path = mount_path.to_str().unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                vec!["mount", "-o", "ro", "/dev/sr0", mount_path],
+                vec!["umount", mount_path],
+                vec!["eject", "/dev/sr0"],
+            ]
+        );
+    }
+
+    #[test]
+    fn media_kept_mounted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount_path = dir.path().join("sr0");
+        fs::create_dir(&mount_path).unwrap();
+        fs::write(mount_path.join("ovf-env.xml"), "<Environment>").unwrap();
+        let mount_path_str = mount_path.to_str().unwrap();
+
+        let runner = Arc::new(MockRunner::succeeding());
+        let media = Media::new(PathBuf::from("/dev/sr0"), mount_path.clone())
+            .with_runner(runner.clone());
+        let settings = config::ProvisioningMedia {
+            keep_mounted: true,
+            ..Default::default()
+        };
+        assert!(parse_media(media, &settings).is_err());
+        assert_eq!(
+            runner.calls(),
+            vec![vec!["mount", "-o", "ro", "/dev/sr0", mount_path_str]]
+        );
+
+        let runner = Arc::new(MockRunner::succeeding());
+        let media = Media::new(PathBuf::from("/dev/sr0"), mount_path.clone())
+            .with_runner(runner.clone());
+        assert!(
+            parse_media(media, &config::ProvisioningMedia::default()).is_err()
+        );
+        let programs: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| argv[0].clone())
+            .collect();
+        assert_eq!(programs, vec!["mount", "umount", "eject"]);
+    }
+
+    #[test]
+    fn unmounted_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = Arc::new(MockRunner::succeeding());
+
+        let media = Media::new(PathBuf::from("/dev/sr0"), dir.path().into())
+            .with_runner(runner.clone())
+            .mount()
+            .unwrap();
+        let read: Result<String, Error> = (|| {
+            let ovf_body = media.read_ovf_env_to_string()?;
+            media.unmount()?;
+            Ok(ovf_body)
+        })();
+        assert!(read.is_err());
+
+        let mount_path = dir.path().to_str().unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                vec!["mount", "-o", "ro", "/dev/sr0", mount_path],
+                vec!["umount", mount_path],
+                vec!["eject", "/dev/sr0"],
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_unmount_not_retried_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = Arc::new(MockRunner::failing("umount", 32));
+
+        let media = Media::new(PathBuf::from("/dev/sr0"), dir.path().into())
+            .with_runner(runner.clone())
+            .mount()
+            .unwrap();
+        assert!(media.unmount().is_err());
+
+        let programs: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| argv[0].clone())
+            .collect();
+        assert_eq!(programs, vec!["mount", "umount"]);
+    }
+
+    #[test]
+    fn disable_ssh_password_authentication() {
+        let ovf_body = |element: &str| {
+            format!(
+                r#"<Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+                    xmlns:wa="http://schemas.microsoft.com/windowsazure">
+                    <wa:ProvisioningSection>
+                        <wa:Version>1.0</wa:Version>
+                        <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure">
+                            <UserName>myusername</UserName>
+                            {element}
+                            <HostName>myhostname</HostName>
+                        </LinuxProvisioningConfigurationSet>
+                    </wa:ProvisioningSection>
+                    <wa:PlatformSettingsSection>
+                        <wa:Version>1.0</wa:Version>
+                        <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure">
+                            <PreprovisionedVm>false</PreprovisionedVm>
+                        </PlatformSettings>
+                    </wa:PlatformSettingsSection>
+                </Environment>"#
+            )
+        };
+
+        for (element, expected) in [
+            (
+                "<DisableSshPasswordAuthentication>true</DisableSshPasswordAuthentication>",
+                true,
+            ),
+            (
+                "<DisableSshPasswordAuthentication>false</DisableSshPasswordAuthentication>",
+                false,
+            ),
+            ("", false),
+        ] {
+            let environment = parse_ovf_env(&ovf_body(element)).unwrap();
+            assert_eq!(
+                environment
+                    .provisioning_section
+                    .linux_prov_conf_set
+                    .disable_ssh_password_authentication,
+                expected,
+                "{element}"
+            );
+        }
+    }
 }
@@ -0,0 +1,1923 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Applying the provisioning metadata to the VM.
+
+pub mod hooks;
+pub mod hosts;
+pub mod progress;
+pub mod sshd;
+pub mod sudoers;
+pub mod system;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{self, Config};
+use crate::distro::{self, HostnameProvisioner, PasswordProvisioner};
+use crate::error::{Error, ProvisionError};
+use crate::imds::PublicKeys;
+use crate::runner::{CommandRunner, SystemRunner};
+use crate::selinux;
+use crate::user::{self, User};
+
+/// The configuration to apply to the VM.
+#[derive(Debug)]
+pub struct Provision {
+    hostname: String,
+    user: User,
+    settings: Settings,
+}
+
+/// Everything a [`Provision`] applies besides the hostname and user, each
+/// with a default, set through [`ProvisionBuilder`].
+#[derive(Debug)]
+struct Settings {
+    keys: Vec<PublicKeys>,
+    private_ip: Option<String>,
+    hostname_backends: Vec<HostnameProvisioner>,
+    password_backends: Vec<PasswordProvisioner>,
+    runner: Arc<dyn CommandRunner>,
+    restore_selinux_contexts: bool,
+    hooks: config::Hooks,
+    post_provision: config::PostProvision,
+    root_keys_path: Option<PathBuf>,
+    authorized_keys_path: Option<PathBuf>,
+    hostname_enabled: bool,
+    map_private_ip: bool,
+    write_etc_hostname: bool,
+    system: config::System,
+    extra_keys: Vec<String>,
+    sshd_settings: BTreeMap<String, String>,
+    trusted_user_ca_keys: Option<String>,
+    disable_password_auth: bool,
+    honor_key_paths: bool,
+    always_create_ssh_dir: bool,
+    wait_for_home: Option<Duration>,
+    security: config::Security,
+    grant_sudo: bool,
+    update_existing_user: bool,
+    extra_users: Vec<config::ExtraUser>,
+    etc_hostname_path: PathBuf,
+    etc_hosts_path: PathBuf,
+    sshd_config_path: PathBuf,
+    status_path: Option<PathBuf>,
+    trusted_user_ca_keys_path: PathBuf,
+    sudoers_dir: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            keys: vec![],
+            private_ip: None,
+            hostname_backends: distro::DEFAULT_HOSTNAME_PROVISIONERS.to_vec(),
+            password_backends: distro::DEFAULT_PASSWORD_PROVISIONERS.to_vec(),
+            runner: Arc::new(SystemRunner),
+            restore_selinux_contexts: false,
+            hooks: config::Hooks::default(),
+            post_provision: config::PostProvision::default(),
+            root_keys_path: None,
+            authorized_keys_path: None,
+            hostname_enabled: true,
+            map_private_ip: false,
+            write_etc_hostname: false,
+            system: config::System::default(),
+            extra_keys: vec![],
+            sshd_settings: BTreeMap::new(),
+            trusted_user_ca_keys: None,
+            disable_password_auth: false,
+            honor_key_paths: false,
+            always_create_ssh_dir: false,
+            wait_for_home: None,
+            security: config::Security::default(),
+            grant_sudo: config::UserProvisioners::default().grant_sudo,
+            update_existing_user: false,
+            extra_users: vec![],
+            etc_hostname_path: PathBuf::from(distro::PATH_ETC_HOSTNAME),
+            etc_hosts_path: PathBuf::from(hosts::PATH_ETC_HOSTS),
+            sshd_config_path: PathBuf::from(sshd::PATH_SSHD_DROP_IN),
+            status_path: None,
+            trusted_user_ca_keys_path: PathBuf::from(
+                sshd::PATH_TRUSTED_USER_CA_KEYS,
+            ),
+            sudoers_dir: PathBuf::from(sudoers::PATH_SUDOERS_DIR),
+        }
+    }
+}
+
+/// The outcome of each provisioning step.
+#[derive(Debug)]
+pub struct ProvisionSummary {
+    pub hostname: Result<(), Error>,
+    pub system: Result<(), Error>,
+    pub user: Result<(), Error>,
+    pub groups: Result<(), Error>,
+    pub password: Result<(), Error>,
+    pub sudo: Result<(), Error>,
+    pub ssh_keys: Result<(), Error>,
+    pub extra_users: Result<(), Error>,
+    pub sshd: Result<(), Error>,
+    pub hooks: Result<(), Error>,
+    pub post_provision: Result<(), Error>,
+}
+
+/// Each step's name and `$borrow` of its result in `$summary`, in the order
+/// the steps run.
+macro_rules! steps {
+    ($summary:expr, $($borrow:tt)+) => {
+        [
+            ("hostname", $($borrow)+ $summary.hostname),
+            ("system", $($borrow)+ $summary.system),
+            ("user", $($borrow)+ $summary.user),
+            ("groups", $($borrow)+ $summary.groups),
+            ("password", $($borrow)+ $summary.password),
+            ("sudo", $($borrow)+ $summary.sudo),
+            ("ssh_keys", $($borrow)+ $summary.ssh_keys),
+            ("extra_users", $($borrow)+ $summary.extra_users),
+            ("sshd", $($borrow)+ $summary.sshd),
+            ("hooks", $($borrow)+ $summary.hooks),
+            ("post_provision", $($borrow)+ $summary.post_provision),
+        ]
+    };
+}
+
+impl ProvisionSummary {
+    /// Each step's name and result, in the order the steps run.
+    pub fn steps(&self) -> [(&'static str, &Result<(), Error>); 11] {
+        steps!(self, &)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.steps().iter().all(|(_, result)| result.is_ok())
+    }
+
+    /// Reduce the summary to the first error, if any step failed, along
+    /// with the step's name.
+    pub fn into_result(mut self) -> Result<(), ProvisionError> {
+        for (step, result) in steps!(self, &mut) {
+            std::mem::replace(result, Ok(()))
+                .map_err(|source| ProvisionError { step, source })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A required [`ProvisionBuilder`] field which hasn't been set yet.
+#[derive(Debug, Default)]
+pub struct Missing;
+
+/// Builds a [`Provision`].
+///
+/// [`build`](ProvisionBuilder::build) is only available once both the
+/// hostname and the user have been set.
+#[derive(Debug)]
+pub struct ProvisionBuilder<Hostname = Missing, U = Missing> {
+    hostname: Hostname,
+    user: U,
+    settings: Settings,
+    user_defaults: UserDefaults,
+}
+
+/// The configured defaults for the user, applied when it's built.
+#[derive(Debug, Default)]
+struct UserDefaults {
+    shell: Option<PathBuf>,
+    groups: Vec<String>,
+    system_account: bool,
+}
+
+impl<Hostname, U> ProvisionBuilder<Hostname, U> {
+    pub fn hostname(
+        self,
+        hostname: impl Into<String>,
+    ) -> ProvisionBuilder<String, U> {
+        ProvisionBuilder {
+            hostname: hostname.into(),
+            user: self.user,
+            settings: self.settings,
+            user_defaults: self.user_defaults,
+        }
+    }
+
+    pub fn user(self, user: User) -> ProvisionBuilder<Hostname, User> {
+        ProvisionBuilder {
+            hostname: self.hostname,
+            user,
+            settings: self.settings,
+            user_defaults: self.user_defaults,
+        }
+    }
+
+    fn with(mut self, set: impl FnOnce(&mut Settings)) -> Self {
+        set(&mut self.settings);
+        self
+    }
+
+    /// The SSH public keys to authorize for the user; none by default.
+    pub fn keys(self, keys: Vec<PublicKeys>) -> Self {
+        self.with(|settings| settings.keys = keys)
+    }
+
+    /// The VM's primary private IP, to resolve the hostname to if configured;
+    /// none by default.
+    pub fn private_ip(self, private_ip: Option<String>) -> Self {
+        self.with(|settings| settings.private_ip = private_ip)
+    }
+
+    /// Disable SSH password authentication by enforcing
+    /// `PasswordAuthentication no` in the sshd drop-in, unless the setting
+    /// is configured explicitly. sshd is left alone by default.
+    pub fn disable_password_auth(self, disable: bool) -> Self {
+        self.with(|settings| settings.disable_password_auth = disable)
+    }
+
+    /// Apply the settings from `config`:
+    ///
+    /// - the user's default shell and groups, whether it's a system account
+    ///   and granted sudo, and whether an existing user's shell is updated
+    /// - the extra users to create
+    /// - whether SELinux contexts are restored, if SELinux is enabled
+    /// - the hooks and post-provision command
+    /// - whether the hostname is set, mapped to the private IP in
+    ///   `/etc/hosts`, and written to `/etc/hostname` whatever the backend
+    /// - the locale, keymap, and timezone
+    /// - the extra SSH keys, where keys are written, whether `.ssh` is
+    ///   created without keys, and whether to wait for the home directory
+    /// - the sshd settings to enforce and the SSH CA to trust
+    /// - the file and directory modes
+    pub fn config(self, config: &Config) -> Self {
+        let ssh = &config.ssh;
+        let hostname = &config.hostname_provisioners;
+        let users = &config.user_provisioners;
+        Self {
+            user_defaults: UserDefaults {
+                shell: users.default_shell.clone(),
+                groups: users.groups.clone(),
+                system_account: users.system_account,
+            },
+            ..self
+        }
+        .with(|settings| {
+            settings.restore_selinux_contexts =
+                config.selinux.restore_contexts && selinux::is_enabled();
+            settings.grant_sudo = users.grant_sudo;
+            settings.update_existing_user = users.update_existing;
+            settings.extra_users = config.users.clone();
+            settings.hooks = config.hooks.clone();
+            settings.post_provision = config.post_provision.clone();
+            settings.hostname_enabled = hostname.enable;
+            settings.map_private_ip = hostname.map_private_ip;
+            settings.write_etc_hostname = hostname.write_etc_hostname;
+            settings.system = config.system.clone();
+            settings.root_keys_path = ssh.root_keys_path.clone();
+            settings.authorized_keys_path = ssh.authorized_keys_path.clone();
+            settings.extra_keys = ssh.extra_authorized_keys.clone();
+            settings.sshd_settings = ssh.enforce_settings.clone();
+            settings.trusted_user_ca_keys = ssh.trusted_user_ca_keys.clone();
+            settings.honor_key_paths = ssh.honor_key_paths;
+            settings.always_create_ssh_dir = ssh.always_create_ssh_dir;
+            settings.wait_for_home = ssh.wait_for_home.then(|| {
+                Duration::from_secs_f64(ssh.wait_for_home_timeout_secs)
+            });
+            settings.security = config.security.clone();
+        })
+    }
+
+    /// Set the hostname backends to try, in order.
+    pub fn hostname_backends(
+        self,
+        hostname_backends: Vec<HostnameProvisioner>,
+    ) -> Self {
+        self.with(|settings| settings.hostname_backends = hostname_backends)
+    }
+
+    /// Set the password backends to try, in order.
+    pub fn password_backends(
+        self,
+        password_backends: Vec<PasswordProvisioner>,
+    ) -> Self {
+        self.with(|settings| settings.password_backends = password_backends)
+    }
+
+    /// Run commands with `runner` rather than on the host.
+    pub fn runner(self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.with(|settings| settings.runner = runner)
+    }
+
+    /// Restore the SELinux contexts of the files written while provisioning.
+    pub fn selinux_contexts(self, restore_selinux_contexts: bool) -> Self {
+        self.with(|settings| {
+            settings.restore_selinux_contexts = restore_selinux_contexts
+        })
+    }
+
+    /// Whether to set the hostname, which is done by default.
+    pub fn hostname_enabled(self, hostname_enabled: bool) -> Self {
+        self.with(|settings| settings.hostname_enabled = hostname_enabled)
+    }
+
+    /// Set the locale, keymap, and timezone configured in `system`.
+    pub fn system(self, system: config::System) -> Self {
+        self.with(|settings| settings.system = system)
+    }
+
+    /// Run the hooks configured by `hooks` at each phase.
+    pub fn hooks(self, hooks: config::Hooks) -> Self {
+        self.with(|settings| settings.hooks = hooks)
+    }
+
+    /// Run the command configured by `post_provision` once every other step
+    /// has succeeded.
+    pub fn post_provision(self, post_provision: config::PostProvision) -> Self {
+        self.with(|settings| settings.post_provision = post_provision)
+    }
+
+    /// Write the static hostname to `path` rather than
+    /// [`distro::PATH_ETC_HOSTNAME`], e.g. to test against a temporary tree.
+    pub fn etc_hostname_path(self, path: impl Into<PathBuf>) -> Self {
+        self.with(|settings| settings.etc_hostname_path = path.into())
+    }
+
+    /// Map the hostname in the hosts file at `path` rather than
+    /// [`hosts::PATH_ETC_HOSTS`], e.g. to test against a temporary tree.
+    pub fn etc_hosts_path(self, path: impl Into<PathBuf>) -> Self {
+        self.with(|settings| settings.etc_hosts_path = path.into())
+    }
+
+    /// Enforce the sshd settings in `path` rather than
+    /// [`sshd::PATH_SSHD_DROP_IN`], e.g. to test against a temporary tree.
+    pub fn sshd_config_path(self, path: impl Into<PathBuf>) -> Self {
+        self.with(|settings| settings.sshd_config_path = path.into())
+    }
+
+    /// Write the trusted user CA keys to `path` rather than
+    /// [`sshd::PATH_TRUSTED_USER_CA_KEYS`], e.g. to test against a temporary
+    /// tree.
+    pub fn trusted_user_ca_keys_path(self, path: impl Into<PathBuf>) -> Self {
+        self.with(|settings| settings.trusted_user_ca_keys_path = path.into())
+    }
+
+    /// Whether to grant the user sudo, which is done by default.
+    pub fn grant_sudo(self, grant_sudo: bool) -> Self {
+        self.with(|settings| settings.grant_sudo = grant_sudo)
+    }
+
+    /// Write sudo rules to `dir` rather than [`sudoers::PATH_SUDOERS_DIR`],
+    /// e.g. to test against a temporary tree.
+    pub fn sudoers_dir(self, dir: impl Into<PathBuf>) -> Self {
+        self.with(|settings| settings.sudoers_dir = dir.into())
+    }
+
+    /// Keep a status file at `path`, e.g. [`progress::PATH_STATUS_FILE`],
+    /// up to date as each step starts and finishes; none is written by
+    /// default.
+    pub fn status_path(self, path: impl Into<PathBuf>) -> Self {
+        self.with(|settings| settings.status_path = Some(path.into()))
+    }
+}
+
+impl ProvisionBuilder<String, User> {
+    pub fn build(mut self) -> Provision {
+        let defaults = self.user_defaults;
+        for extra in &mut self.settings.extra_users {
+            if extra.shell.is_none() {
+                extra.shell = defaults.shell.clone();
+            }
+        }
+        let mut user = match (self.user.shell.is_some(), defaults.shell) {
+            (false, Some(shell)) => self.user.with_shell(shell),
+            _ => self.user,
+        };
+        if user.groups.is_empty() {
+            user.groups = defaults.groups;
+        }
+        user.system |= defaults.system_account;
+
+        Provision {
+            hostname: self.hostname,
+            user,
+            settings: self.settings,
+        }
+    }
+}
+
+impl Provision {
+    pub fn builder() -> ProvisionBuilder {
+        ProvisionBuilder {
+            hostname: Missing,
+            user: Missing,
+            settings: Settings::default(),
+            user_defaults: UserDefaults::default(),
+        }
+    }
+
+    pub fn new(
+        hostname: impl Into<String>,
+        user: User,
+        keys: Vec<PublicKeys>,
+    ) -> Self {
+        Self::builder()
+            .hostname(hostname)
+            .user(user)
+            .keys(keys)
+            .build()
+    }
+
+    /// The user to provision, with any configured defaults applied.
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    /// The SSH keys that will be authorized for the user: the metadata's
+    /// keys merged with the configured extra keys, without duplicates.
+    pub fn authorized_keys(&self) -> Vec<PublicKeys> {
+        merge_keys(
+            &self.settings.keys,
+            &self.settings.extra_keys,
+            &self.authorized_keys_path().unwrap_or_default(),
+        )
+    }
+
+    /// Provision the VM, returning the first error encountered.
+    pub async fn provision(self) -> Result<(), ProvisionError> {
+        self.provision_async().await.into_result()
+    }
+
+    /// Attempt every provisioning step, even after one fails, and report the
+    /// result of each.
+    ///
+    /// If a status file is configured, it follows each step and hook phase.
+    pub async fn provision_async(&self) -> ProvisionSummary {
+        let runner = self.settings.runner.as_ref();
+        let mut progress =
+            progress::Progress::new(self.settings.status_path.as_deref());
+        let mut hooks = vec![];
+
+        hooks.push(self.run_hooks(&mut progress, hooks::Phase::PreProvision));
+        let hostname = progress.step("hostname", || self.provision_hostname());
+        let system = progress
+            .step("system", || system::apply(runner, &self.settings.system));
+        let user = progress.step("user", || {
+            distro::create_or_update_user(
+                runner,
+                &self.user,
+                self.settings.update_existing_user,
+            )
+        });
+        hooks.push(self.run_hooks(&mut progress, hooks::Phase::PostUser));
+        let groups = progress.step("groups", || {
+            distro::reconcile_groups_with_usermod(
+                runner,
+                &self.user.name,
+                &self.user.groups,
+            )
+            .map(|_| ())
+        });
+        let password = progress.step("password", || self.provision_password());
+        let sudo = progress.step("sudo", || self.provision_sudo());
+        progress.start("ssh_keys");
+        let ssh_keys = self.provision_ssh_keys().await;
+        progress.finish("ssh_keys", &ssh_keys);
+        let extra_users =
+            progress.step("extra_users", || self.provision_extra_users());
+        let sshd = progress.step("sshd", || self.provision_sshd());
+        hooks.push(self.run_hooks(&mut progress, hooks::Phase::PostProvision));
+
+        let mut summary = ProvisionSummary {
+            hostname,
+            system,
+            user,
+            groups,
+            password,
+            sudo,
+            ssh_keys,
+            extra_users,
+            sshd,
+            hooks: hooks.into_iter().collect(),
+            post_provision: Ok(()),
+        };
+        if summary.is_success() {
+            summary.post_provision = progress.step("post_provision", || {
+                hooks::run_post_provision(runner, &self.settings.post_provision)
+            });
+        } else if self.settings.post_provision.command.is_some() {
+            tracing::warn!(
+                "Provisioning failed, skipping the post-provision command"
+            );
+        }
+        progress.done(summary.is_success());
+
+        summary
+    }
+
+    fn provision_hostname(&self) -> Result<(), Error> {
+        if !self.settings.hostname_enabled {
+            tracing::info!("Hostname provisioning is disabled, skipping");
+            return Ok(());
+        }
+        if self.hostname.is_empty() {
+            tracing::warn!("No hostname was provided, not setting it");
+            return Ok(());
+        }
+
+        let backend = distro::set_hostname_at(
+            self.settings.runner.as_ref(),
+            &self.hostname,
+            &self.settings.hostname_backends,
+            &self.settings.etc_hostname_path,
+        )?;
+        // hostnamectl writes the file itself, with the right context, though
+        // on some images it doesn't persist.
+        let wrote_etc_hostname = match backend {
+            HostnameProvisioner::Hostname
+            | HostnameProvisioner::Sethostname => true,
+            _ if self.settings.write_etc_hostname => {
+                distro::write_etc_hostname(
+                    &self.hostname,
+                    &self.settings.etc_hostname_path,
+                )?
+            }
+            _ => false,
+        };
+        if wrote_etc_hostname {
+            self.restore_contexts(&self.settings.etc_hostname_path)?;
+        }
+
+        if self.settings.map_private_ip {
+            match &self.settings.private_ip {
+                Some(ip) => {
+                    hosts::map_hostname(
+                        &self.settings.etc_hosts_path,
+                        ip,
+                        &self.hostname,
+                    )?;
+                    self.restore_contexts(&self.settings.etc_hosts_path)?;
+                }
+                None => tracing::warn!(
+                    "IMDS did not provide a private IP, not mapping the hostname"
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn provision_password(&self) -> Result<(), Error> {
+        if self.user.system {
+            tracing::info!(
+                user = self.user.name,
+                "Not setting a password for a system account"
+            );
+            return Ok(());
+        }
+
+        // always pass an empty password
+        distro::set_password(
+            self.settings.runner.as_ref(),
+            &self.user.name,
+            "",
+            &self.settings.password_backends,
+        )?;
+
+        Ok(())
+    }
+
+    fn provision_sudo(&self) -> Result<(), Error> {
+        if !self.settings.grant_sudo {
+            return Ok(());
+        }
+
+        sudoers::grant_sudo_in(
+            self.settings.runner.as_ref(),
+            &self.user.name,
+            &self.settings.sudoers_dir,
+        )?;
+        self.restore_contexts(&self.settings.sudoers_dir)
+    }
+
+    /// Create each of the configured extra users with its groups, keys, and
+    /// sudo grant. Unlike the provisioned user, they get no password.
+    fn provision_extra_users(&self) -> Result<(), Error> {
+        let runner = self.settings.runner.as_ref();
+        for extra in &self.settings.extra_users {
+            // Only the admin user is created in the admin groups; these get
+            // just the groups they're configured with.
+            let mut user = User::new(&extra.name)
+                .with_groups(&extra.groups)
+                .with_admin(false);
+            if let Some(shell) = &extra.shell {
+                user = user.with_shell(shell);
+            }
+            tracing::info!(user = user.name, "Provisioning configured user");
+            distro::create_or_update_user(
+                runner,
+                &user,
+                self.settings.update_existing_user,
+            )?;
+            distro::reconcile_groups_with_usermod(
+                runner,
+                &user.name,
+                &user.groups,
+            )?;
+            if extra.sudo {
+                sudoers::grant_sudo_in(
+                    runner,
+                    &user.name,
+                    &self.settings.sudoers_dir,
+                )?;
+                self.restore_contexts(&self.settings.sudoers_dir)?;
+            }
+            if extra.ssh_authorized_keys.is_empty() {
+                continue;
+            }
+
+            let keys_path = user.authorized_keys_path(None);
+            let ssh_dir = keys_path.parent().unwrap_or(Path::new("/"));
+            log_extra_keys(&extra.ssh_authorized_keys);
+            let keys = merge_keys(&[], &extra.ssh_authorized_keys, &keys_path);
+            user::create_ssh_directory_with_mode(
+                &user.name,
+                ssh_dir,
+                self.settings.security.dir_mode,
+            )?;
+            user::write_authorized_keys_with_mode(
+                keys,
+                &user.name,
+                &keys_path,
+                self.settings.security.file_mode,
+            )?;
+            self.restore_contexts(ssh_dir)?;
+        }
+
+        Ok(())
+    }
+
+    fn run_hooks(
+        &self,
+        progress: &mut progress::Progress,
+        phase: hooks::Phase,
+    ) -> Result<(), Error> {
+        progress.step(phase.as_str(), || {
+            hooks::run_hooks(
+                self.settings.runner.as_ref(),
+                &self.settings.hooks,
+                phase,
+            )
+        })
+    }
+
+    async fn provision_ssh_keys(&self) -> Result<(), Error> {
+        let home = self.user.home();
+        if let Some(timeout) = self.settings.wait_for_home {
+            user::wait_for_home(&home, timeout, user::HOME_POLL_INTERVAL)
+                .await?;
+        }
+        if !self.user.create_home && !home.is_dir() {
+            if !self.settings.keys.is_empty() {
+                tracing::warn!(
+                    user = self.user.name,
+                    home = %home.display(),
+                    "Home directory does not exist, not writing SSH keys"
+                );
+            }
+            return Ok(());
+        }
+
+        let keys_path = self.authorized_keys_path()?;
+        let ssh_dir = keys_path.parent().unwrap_or(Path::new("/"));
+        log_extra_keys(&self.settings.extra_keys);
+        let keys = merge_keys(
+            &self.settings.keys,
+            &self.settings.extra_keys,
+            &keys_path,
+        );
+        if keys.is_empty() && !self.settings.always_create_ssh_dir {
+            tracing::debug!(user = self.user.name, "No SSH keys to write");
+            return Ok(());
+        }
+
+        self.create_ssh_directory(ssh_dir)?;
+        if keys.is_empty() {
+            return self.restore_contexts(ssh_dir);
+        }
+        if !self.settings.honor_key_paths {
+            self.write_authorized_keys(keys, &keys_path)?;
+            return self.restore_contexts(ssh_dir);
+        }
+
+        for (path, keys) in group_keys_by_path(keys, &self.user, &keys_path) {
+            let dir = path.parent().unwrap_or(Path::new("/"));
+            // Only directories created here are handed to the user; others,
+            // like /etc/ssh, keep their ownership.
+            if !dir.exists() {
+                self.create_ssh_directory(dir)?;
+            }
+            tracing::info!(
+                path = %path.display(),
+                keys = keys.len(),
+                "Writing SSH keys to the path given in the metadata"
+            );
+            self.write_authorized_keys(keys, &path)?;
+            self.restore_contexts(dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Root's configured keys path, else the configured path in the home
+    /// directory, else `.ssh/authorized_keys`.
+    fn authorized_keys_path(&self) -> Result<PathBuf, Error> {
+        match &self.settings.authorized_keys_path {
+            Some(path)
+                if !(self.user.is_root()
+                    && self.settings.root_keys_path.is_some()) =>
+            {
+                self.user.keys_path_in_home(path)
+            }
+            _ => Ok(self
+                .user
+                .authorized_keys_path(self.settings.root_keys_path.as_deref())),
+        }
+    }
+
+    fn create_ssh_directory(&self, dir: &Path) -> Result<(), Error> {
+        user::create_ssh_directory_with_mode(
+            &self.user.name,
+            dir,
+            self.settings.security.dir_mode,
+        )
+    }
+
+    fn write_authorized_keys(
+        &self,
+        keys: Vec<PublicKeys>,
+        path: &Path,
+    ) -> Result<(), Error> {
+        user::write_authorized_keys_with_mode(
+            keys,
+            &self.user.name,
+            path,
+            self.settings.security.file_mode,
+        )
+    }
+
+    fn provision_sshd(&self) -> Result<(), Error> {
+        let mut settings = self.settings.sshd_settings.clone();
+        if let Some(keys) = &self.settings.trusted_user_ca_keys {
+            let path = &self.settings.trusted_user_ca_keys_path;
+            sshd::write_trusted_user_ca_keys(path, keys)?;
+            self.restore_contexts(path)?;
+            // An explicitly configured directive wins.
+            settings
+                .entry("TrustedUserCAKeys".to_string())
+                .or_insert_with(|| path.display().to_string());
+        }
+        if self.settings.disable_password_auth {
+            settings
+                .entry("PasswordAuthentication".to_string())
+                .or_insert_with(|| "no".to_string());
+        }
+        if settings.is_empty() {
+            return Ok(());
+        }
+
+        sshd::enforce_settings(&self.settings.sshd_config_path, &settings)?;
+        self.restore_contexts(&self.settings.sshd_config_path)
+    }
+
+    fn restore_contexts(&self, path: &Path) -> Result<(), Error> {
+        if self.settings.restore_selinux_contexts {
+            selinux::restore_contexts(self.settings.runner.as_ref(), &[path])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Combine the metadata's `keys` with the `extra` keys from the
+/// configuration, dropping duplicates.
+fn merge_keys(
+    keys: &[PublicKeys],
+    extra: &[String],
+    keys_path: &Path,
+) -> Vec<PublicKeys> {
+    let extra = extra.iter().map(|key_data| PublicKeys {
+        key_data: key_data.clone(),
+        path: keys_path.to_string_lossy().into_owned(),
+    });
+
+    let mut merged: Vec<PublicKeys> = vec![];
+    for key in keys.iter().cloned().chain(extra) {
+        if !merged
+            .iter()
+            .any(|existing| existing.key_data.trim() == key.key_data.trim())
+        {
+            merged.push(key);
+        }
+    }
+
+    merged
+}
+
+/// Log each of the `extra` keys from the configuration being authorized.
+fn log_extra_keys(extra: &[String]) {
+    for key_data in extra {
+        tracing::info!(
+            source = "config",
+            key_type = key_data.split_whitespace().next().unwrap_or_default(),
+            "Authorizing SSH key from the configuration"
+        );
+    }
+}
+
+/// Group `keys` by the file each should be written to, using `default` for
+/// those without an absolute path in `user`'s home directory.
+///
+/// `default` is always included, so the user's own file is written even if
+/// every key names another.
+fn group_keys_by_path(
+    keys: Vec<PublicKeys>,
+    user: &User,
+    default: &Path,
+) -> BTreeMap<PathBuf, Vec<PublicKeys>> {
+    let mut groups = BTreeMap::from([(default.to_path_buf(), vec![])]);
+    for key in keys {
+        let path = Path::new(&key.path);
+        let path = if path == default {
+            default.to_path_buf()
+        } else if path.is_absolute() {
+            // The file is handed to the user, so it can't be anywhere else,
+            // like /etc/sudoers or another user's keys.
+            let in_home = path
+                .strip_prefix(user.home())
+                .map_err(|_| Error::KeysPathOutsideHome {
+                    path: path.to_path_buf(),
+                })
+                .and_then(|relative| user.keys_path_in_home(relative));
+            match in_home {
+                Ok(path) => path,
+                Err(error) => {
+                    tracing::warn!(
+                        path = key.path,
+                        ?error,
+                        "Ignoring SSH key path outside the home directory"
+                    );
+                    default.to_path_buf()
+                }
+            }
+        } else {
+            if !key.path.is_empty() {
+                tracing::warn!(
+                    path = key.path,
+                    "Ignoring relative SSH key path"
+                );
+            }
+            default.to_path_buf()
+        };
+        groups.entry(path).or_default().push(key);
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::*;
+    use crate::imds;
+    use crate::runner::mock::MockRunner;
+
+    /// A builder for a user who doesn't exist, with `runner` standing in for
+    /// every command, the hostname set by a fake backend, and no sudo rule
+    /// written. Tests override only what they check.
+    fn test_builder(
+        runner: Arc<dyn CommandRunner>,
+    ) -> ProvisionBuilder<String, User> {
+        Provision::builder()
+            .hostname("test-host")
+            .user(User::new("azure-init-test-no-such-user"))
+            .hostname_backends(vec![HostnameProvisioner::FakeSuccess])
+            .runner(runner)
+            .grant_sudo(false)
+    }
+
+    #[tokio::test]
+    async fn key_paths_honored() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+        let first = home.join(".ssh").join("authorized_keys");
+        let second = home.join("keys").join("authorized_keys2");
+        let outside = dir.path().join("etc").join("sudoers");
+        let key = |key_data: &str, path: &Path| PublicKeys {
+            key_data: key_data.to_string(),
+            path: path.to_string_lossy().into_owned(),
+        };
+
+        let mut config = Config::default();
+        config.ssh.honor_key_paths = true;
+        let provision = Provision::builder()
+            .hostname("test-host")
+            .user(User::new(current.name).with_home(&home))
+            .keys(vec![
+                key("ssh-rsa first_key", &first),
+                key("ssh-rsa second_key", &second),
+                key("ssh-rsa third_key", &second),
+                key("ssh-rsa outside_key", &outside),
+            ])
+            .config(&config)
+            .selinux_contexts(false)
+            .build();
+        provision.provision_ssh_keys().await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&first).unwrap(),
+            "ssh-rsa first_key\nssh-rsa outside_key\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&second).unwrap(),
+            "ssh-rsa second_key\nssh-rsa third_key\n"
+        );
+        assert!(!outside.exists());
+        assert!(!outside.parent().unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn provision_from_built_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+        let metadata = imds::InstanceMetadata::builder()
+            .admin_username(current.name)
+            .computer_name("built-host")
+            .public_key("ssh-ed25519 built_key", "")
+            .build();
+        let os_profile = metadata.compute.os_profile;
+
+        let provision = Provision::builder()
+            .hostname(os_profile.computer_name)
+            .user(
+                User::new(os_profile.admin_username.unwrap()).with_home(&home),
+            )
+            .keys(metadata.compute.public_keys)
+            .config(&Config::default())
+            .selinux_contexts(false)
+            .build();
+        provision.provision_ssh_keys().await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(home.join(".ssh/authorized_keys")).unwrap(),
+            "ssh-ed25519 built_key\n"
+        );
+    }
+
+    /// Run every step with its real implementation, only with commands
+    /// mocked and files written to a temporary tree.
+    #[tokio::test]
+    async fn provision_in_temp_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+        let etc_hostname = dir.path().join("etc/hostname");
+        std::fs::create_dir(etc_hostname.parent().unwrap()).unwrap();
+        let sshd_config =
+            dir.path().join("etc/ssh/sshd_config.d/50-azure-init.conf");
+
+        let mut config = Config::default();
+        config.user_provisioners.grant_sudo = false;
+        config.ssh.enforce_settings = BTreeMap::from([(
+            "PasswordAuthentication".to_string(),
+            "no".to_string(),
+        )]);
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .user(User::new(current.name).with_home(&home))
+            .keys(vec![PublicKeys {
+                key_data: "ssh-ed25519 test_key".to_string(),
+                path: String::new(),
+            }])
+            .config(&config)
+            .hostname_backends(vec![HostnameProvisioner::Hostname])
+            .selinux_contexts(false)
+            .etc_hostname_path(&etc_hostname)
+            .sshd_config_path(&sshd_config)
+            .build()
+            .provision_async()
+            .await;
+
+        for (step, result) in summary.steps() {
+            assert!(result.is_ok(), "{step}: {result:?}");
+        }
+        assert_eq!(
+            std::fs::read_to_string(&etc_hostname).unwrap(),
+            "test-host\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&sshd_config).unwrap(),
+            "PasswordAuthentication no\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(home.join(".ssh/authorized_keys")).unwrap(),
+            "ssh-ed25519 test_key\n"
+        );
+        assert_eq!(runner.calls()[0], vec!["hostname", "test-host"]);
+    }
+
+    #[tokio::test]
+    async fn status_file_follows_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        let status = dir.path().join("azure-init/status.json");
+        let read_status = {
+            let status = status.clone();
+            move || -> serde_json::Value {
+                let contents = std::fs::read_to_string(&status).unwrap();
+                serde_json::from_str(&contents).unwrap()
+            }
+        };
+        // Note the step in the status file as each command runs.
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let runner = {
+            let seen = seen.clone();
+            let read_status = read_status.clone();
+            MockRunner::new(move |argv| {
+                let status = read_status();
+                seen.lock().unwrap().push((
+                    argv[0].clone(),
+                    status["state"].clone(),
+                    status["step"].clone(),
+                ));
+                Ok(crate::runner::mock::output(0, ""))
+            })
+        };
+
+        let summary = test_builder(Arc::new(runner))
+            .user(User::new("azure-init-test-user"))
+            .hostname_backends(vec![HostnameProvisioner::Hostname])
+            .etc_hostname_path(dir.path().join("hostname"))
+            .status_path(&status)
+            .build()
+            .provision_async()
+            .await;
+
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(
+            seen[0],
+            ("hostname".to_string(), "running".into(), "hostname".into())
+        );
+        assert!(seen.contains(&(
+            "useradd".to_string(),
+            "running".into(),
+            "user".into()
+        )));
+        let status = read_status();
+        assert!(summary.is_success());
+        assert_eq!(status["state"], "succeeded");
+        assert_eq!(status["step"], serde_json::Value::Null);
+        assert_eq!(status["completed"][0], "pre-provision");
+        assert_eq!(status["completed"][1], "hostname");
+    }
+
+    #[test]
+    fn hostname_mapped_to_private_ip() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc_hostname = dir.path().join("hostname");
+        let etc_hosts = dir.path().join("hosts");
+        std::fs::write(&etc_hosts, "127.0.0.1 localhost\n").unwrap();
+        let network: imds::Network = serde_json::from_str(
+            r#"{
+                "interface": [{
+                    "ipv4": {
+                        "ipAddress": [{
+                            "privateIpAddress": "10.0.0.4",
+                            "publicIpAddress": "20.51.1.10"
+                        }],
+                        "subnet": [{ "address": "10.0.0.0", "prefix": "24" }]
+                    },
+                    "macAddress": "000D3A9B5C1E"
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        config.hostname_provisioners.map_private_ip = true;
+        let provision = test_builder(Arc::new(MockRunner::succeeding()))
+            .user(User::new("azureuser"))
+            .private_ip(network.primary_private_ip().map(str::to_string))
+            .config(&config)
+            .hostname_backends(vec![HostnameProvisioner::Hostname])
+            .selinux_contexts(false)
+            .etc_hostname_path(&etc_hostname)
+            .etc_hosts_path(&etc_hosts)
+            .build();
+
+        provision.provision_hostname().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&etc_hosts).unwrap(),
+            "127.0.0.1 localhost\n10.0.0.4 test-host # managed by azure-init\n"
+        );
+    }
+
+    #[test]
+    fn etc_hostname_written_alongside_hostnamectl() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc_hostname = dir.path().join("hostname");
+        std::fs::write(&etc_hostname, "stale-host\n").unwrap();
+        let provision = |write_etc_hostname| {
+            let mut config = Config::default();
+            config.hostname_provisioners.write_etc_hostname =
+                write_etc_hostname;
+            test_builder(Arc::new(MockRunner::succeeding()))
+                .user(User::new("azureuser"))
+                .config(&config)
+                .hostname_backends(vec![HostnameProvisioner::Hostnamectl])
+                .selinux_contexts(false)
+                .etc_hostname_path(&etc_hostname)
+                .build()
+        };
+
+        provision(false).provision_hostname().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&etc_hostname).unwrap(),
+            "stale-host\n"
+        );
+
+        provision(true).provision_hostname().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&etc_hostname).unwrap(),
+            "test-host\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn trusted_user_ca_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let sshd_config = dir.path().join("50-azure-init.conf");
+        let ca_keys = dir.path().join("trusted_user_ca_keys");
+
+        let mut config = Config::default();
+        config.ssh.trusted_user_ca_keys =
+            Some("ssh-ed25519 AAAA ca@example.com".to_string());
+        let provision = Provision::builder()
+            .hostname("test-host")
+            .user(User::new("azureuser"))
+            .config(&config)
+            .selinux_contexts(false)
+            .sshd_config_path(&sshd_config)
+            .trusted_user_ca_keys_path(&ca_keys)
+            .build();
+
+        provision.provision_sshd().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&ca_keys).unwrap(),
+            "ssh-ed25519 AAAA ca@example.com\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&sshd_config).unwrap(),
+            format!("TrustedUserCAKeys {}\n", ca_keys.display())
+        );
+    }
+
+    #[test]
+    fn password_auth_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let sshd_config = dir.path().join("50-azure-init.conf");
+        let provision = |config: &Config, disable: bool| {
+            Provision::builder()
+                .hostname("test-host")
+                .user(User::new("azureuser"))
+                .config(config)
+                .disable_password_auth(disable)
+                .selinux_contexts(false)
+                .sshd_config_path(&sshd_config)
+                .build()
+        };
+
+        let mut config = Config::default();
+        provision(&config, false).provision_sshd().unwrap();
+        assert!(!sshd_config.exists());
+
+        provision(&config, true).provision_sshd().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&sshd_config).unwrap(),
+            "PasswordAuthentication no\n"
+        );
+
+        // An explicitly configured setting wins.
+        config.ssh.enforce_settings = BTreeMap::from([(
+            "PasswordAuthentication".to_string(),
+            "yes".to_string(),
+        )]);
+        provision(&config, true).provision_sshd().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&sshd_config).unwrap(),
+            "PasswordAuthentication yes\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn ssh_dir_without_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let ssh_dir = dir.path().join(".ssh");
+        let provision = |config: &Config| {
+            Provision::builder()
+                .hostname("test-host")
+                .user(User::new(current.name.clone()).with_home(dir.path()))
+                .config(config)
+                .selinux_contexts(false)
+                .build()
+        };
+
+        let mut config = Config::default();
+        provision(&config).provision_ssh_keys().await.unwrap();
+        assert!(!ssh_dir.exists());
+
+        config.ssh.always_create_ssh_dir = true;
+        provision(&config).provision_ssh_keys().await.unwrap();
+        let metadata = std::fs::metadata(&ssh_dir).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        assert_eq!(metadata.uid(), current.uid.as_raw());
+        assert!(!ssh_dir.join("authorized_keys").exists());
+    }
+
+    #[tokio::test]
+    async fn authorized_keys_path_confined_to_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        std::fs::create_dir(&home).unwrap();
+        let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let provision = |path: &str| {
+            let mut config = Config::default();
+            config.ssh.authorized_keys_path = Some(PathBuf::from(path));
+            Provision::builder()
+                .hostname("test-host")
+                .user(User::new(current.name.clone()).with_home(&home))
+                .keys(vec![PublicKeys {
+                    key_data: "ssh-ed25519 test_key".to_string(),
+                    path: String::new(),
+                }])
+                .config(&config)
+                .selinux_contexts(false)
+                .build()
+        };
+
+        let result = provision("../passwd").provision_ssh_keys().await;
+        assert!(matches!(result, Err(Error::KeysPathOutsideHome { .. })));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        provision(".ssh/authorized_keys2")
+            .provision_ssh_keys()
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(home.join(".ssh/authorized_keys2"))
+                .unwrap(),
+            "ssh-ed25519 test_key\n"
+        );
+    }
+
+    #[test]
+    fn keys_grouped_by_path() {
+        let default = Path::new("/home/azureuser/.ssh/authorized_keys");
+        let key = |key_data: &str, path: &str| PublicKeys {
+            key_data: key_data.to_string(),
+            path: path.to_string(),
+        };
+        let groups = group_keys_by_path(
+            vec![
+                key("ssh-rsa a", "/home/azureuser/.ssh/authorized_keys2"),
+                key("ssh-rsa b", ""),
+                key("ssh-rsa c", "relative/keys"),
+            ],
+            &User::new("azureuser"),
+            default,
+        );
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[default].len(), 2);
+        assert_eq!(
+            groups[Path::new("/home/azureuser/.ssh/authorized_keys2")].len(),
+            1
+        );
+    }
+
+    #[test]
+    fn key_paths_outside_home_ignored() {
+        let default = Path::new("/home/azureuser/.ssh/authorized_keys");
+        let key = |path: &str| PublicKeys {
+            key_data: "ssh-rsa key".to_string(),
+            path: path.to_string(),
+        };
+        let groups = group_keys_by_path(
+            vec![
+                key("/etc/sudoers"),
+                key("/etc/ssh/keys/azureuser"),
+                key("/home/other/.ssh/authorized_keys"),
+                key("/home/azureuser/../other/.ssh/authorized_keys"),
+            ],
+            &User::new("azureuser"),
+            default,
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[default].len(), 4);
+    }
+
+    #[test]
+    fn extra_keys_merged() {
+        let key = |key_data: &str| PublicKeys {
+            key_data: key_data.to_string(),
+            path: "/home/azureuser/.ssh/authorized_keys".to_string(),
+        };
+        let keys = vec![key("ssh-rsa imds_key"), key("ssh-rsa shared_key")];
+        let extra = vec![
+            "ssh-ed25519 break_glass".to_string(),
+            "ssh-rsa shared_key".to_string(),
+            "ssh-ed25519 break_glass".to_string(),
+        ];
+
+        let merged = merge_keys(
+            &keys,
+            &extra,
+            Path::new("/home/azureuser/.ssh/authorized_keys"),
+        );
+        let key_data: Vec<&str> =
+            merged.iter().map(|key| key.key_data.as_str()).collect();
+        assert_eq!(
+            key_data,
+            vec![
+                "ssh-rsa imds_key",
+                "ssh-rsa shared_key",
+                "ssh-ed25519 break_glass"
+            ]
+        );
+    }
+
+    #[test]
+    fn authorized_keys_include_extra_keys() {
+        let mut config = Config::default();
+        config.ssh.extra_authorized_keys = vec![
+            "ssh-ed25519 break_glass".to_string(),
+            "ssh-rsa imds_key".to_string(),
+        ];
+        let provision = Provision::builder()
+            .hostname("test-host")
+            .user(User::new("azureuser"))
+            .keys(vec![PublicKeys {
+                key_data: "ssh-rsa imds_key".to_string(),
+                path: String::new(),
+            }])
+            .config(&config)
+            .build();
+
+        let key_data: Vec<String> = provision
+            .authorized_keys()
+            .into_iter()
+            .map(|key| key.key_data)
+            .collect();
+        assert_eq!(
+            key_data,
+            vec!["ssh-rsa imds_key", "ssh-ed25519 break_glass"]
+        );
+    }
+
+    #[tokio::test]
+    async fn steps_attempted_after_hostname_failure() {
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .keys(vec![PublicKeys {
+                key_data: "ssh-rsa test_key".to_string(),
+                path: String::new(),
+            }])
+            .hostname_backends(vec![HostnameProvisioner::FakeFailure])
+            .build()
+            .provision_async()
+            .await;
+
+        assert!(matches!(
+            summary.hostname,
+            Err(Error::NoHostnameProvisioner)
+        ));
+        assert!(summary.user.is_ok());
+        assert!(summary.groups.is_ok());
+        assert!(summary.password.is_ok());
+        // The user doesn't really exist, so there's no home to write to.
+        assert!(summary.ssh_keys.is_err());
+        assert!(!summary.is_success());
+
+        let programs: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| argv[0].clone())
+            .collect();
+        assert_eq!(programs, vec!["useradd", "passwd"]);
+
+        let steps: Vec<&str> =
+            summary.steps().iter().map(|(step, _)| *step).collect();
+        assert_eq!(
+            steps,
+            vec![
+                "hostname",
+                "system",
+                "user",
+                "groups",
+                "password",
+                "sudo",
+                "ssh_keys",
+                "extra_users",
+                "sshd",
+                "hooks",
+                "post_provision"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn selinux_contexts_restored() {
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .selinux_contexts(true)
+            .build()
+            .provision_async()
+            .await;
+        assert!(summary.hostname.is_ok());
+
+        // Only the `hostname` backend writes the file itself.
+        assert!(!runner.calls().iter().any(|argv| argv[0] == "restorecon"));
+
+        let provision =
+            test_builder(runner.clone()).selinux_contexts(true).build();
+        provision
+            .restore_contexts(Path::new(distro::PATH_ETC_HOSTNAME))
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &vec!["restorecon", "-R", "/etc/hostname"]
+        );
+    }
+
+    #[tokio::test]
+    async fn selinux_contexts_skipped_when_disabled() {
+        let runner = Arc::new(MockRunner::succeeding());
+        let provision = test_builder(runner.clone()).build();
+
+        provision
+            .restore_contexts(Path::new(distro::PATH_ETC_HOSTNAME))
+            .unwrap();
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn builder_matches_new() {
+        let keys = vec![PublicKeys {
+            key_data: "ssh-rsa test_key".to_string(),
+            path: "/home/azureuser/.ssh/authorized_keys".to_string(),
+        }];
+        let built = Provision::builder()
+            .user(User::new("azureuser"))
+            .keys(keys.clone())
+            .hostname("test-host")
+            .build();
+        let new = Provision::new("test-host", User::new("azureuser"), keys);
+
+        assert_eq!(format!("{built:?}"), format!("{new:?}"));
+    }
+
+    #[tokio::test]
+    async fn sudo_granted_by_default() {
+        assert_eq!(
+            Settings::default().grant_sudo,
+            config::UserProvisioners::default().grant_sudo
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = Provision::builder()
+            .hostname("test-host")
+            .user(User::new("azure-init-test-no-such-user"))
+            .hostname_backends(vec![HostnameProvisioner::FakeSuccess])
+            .runner(runner.clone())
+            .sudoers_dir(dir.path())
+            .build()
+            .provision_async()
+            .await;
+        assert!(summary.sudo.is_ok());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(runner
+            .calls()
+            .iter()
+            .any(|argv| argv[0] == env!("PATH_VISUDO")));
+    }
+
+    #[test]
+    fn builder_applies_config() {
+        let mut config = Config::default();
+        config.user_provisioners.default_shell = Some("/bin/zsh".into());
+        config.selinux.restore_contexts = false;
+
+        let provision = Provision::builder()
+            .hostname("test-host")
+            .user(User::new("azureuser"))
+            .config(&config)
+            .build();
+        assert_eq!(provision.user.shell(), Some(Path::new("/bin/zsh")));
+        assert!(!provision.settings.restore_selinux_contexts);
+        assert!(provision.user.groups().is_empty());
+
+        config.user_provisioners.groups = vec!["adm".to_string()];
+        config.system.timezone = Some("UTC".to_string());
+        let provision = Provision::builder()
+            .hostname("test-host")
+            .user(User::new("azureuser"))
+            .config(&config)
+            .build();
+        assert_eq!(provision.user.groups(), ["adm"]);
+        assert_eq!(provision.settings.system.timezone.as_deref(), Some("UTC"));
+
+        // An explicitly chosen shell wins over the configured default.
+        let provision = Provision::builder()
+            .hostname("test-host")
+            .user(User::new("azureuser").with_shell("/bin/sh"))
+            .config(&config)
+            .build();
+        assert_eq!(provision.user.shell(), Some(Path::new("/bin/sh")));
+    }
+
+    #[tokio::test]
+    async fn groups_reconciled_after_useradd() {
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .user(
+                User::new("azure-init-test-no-such-user").with_groups(["adm"]),
+            )
+            .build()
+            .provision_async()
+            .await;
+        assert!(summary.groups.is_ok());
+
+        let programs: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| argv[0].clone())
+            .collect();
+        assert_eq!(
+            programs,
+            vec!["useradd", "id", "getent", "usermod", "passwd"]
+        );
+    }
+
+    #[tokio::test]
+    async fn extra_users_created() {
+        let extra = |name: &str, groups: &[&str]| config::ExtraUser {
+            name: name.to_string(),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+            ssh_authorized_keys: vec![],
+            shell: None,
+            sudo: false,
+        };
+        let mut config = Config::default();
+        config.user_provisioners.grant_sudo = false;
+        config.user_provisioners.default_shell = Some("/bin/zsh".into());
+        config.users = vec![
+            extra("azure-init-test-no-such-operator", &["adm"]),
+            config::ExtraUser {
+                shell: Some("/bin/sh".into()),
+                ..extra("azure-init-test-no-such-backup", &[])
+            },
+        ];
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .config(&config)
+            .selinux_contexts(false)
+            .build()
+            .provision_async()
+            .await;
+        assert!(summary.extra_users.is_ok(), "{:?}", summary.extra_users);
+
+        let useradds: Vec<Vec<String>> = runner
+            .calls()
+            .into_iter()
+            .filter(|argv| argv[0] == "useradd")
+            .collect();
+        assert_eq!(useradds.len(), 3);
+        // Only the admin user is created in the admin groups.
+        assert!(useradds[0].contains(&distro::ADMIN_GROUPS.to_string()));
+        for argv in &useradds[1..] {
+            assert!(!argv.contains(&"--groups".to_string()), "{argv:?}");
+        }
+        let created = |argv: &[String], name: &str, shell: &str| {
+            argv.contains(&name.to_string())
+                && argv.ends_with(&["--shell".to_string(), shell.to_string()])
+        };
+        assert!(created(
+            &useradds[1],
+            "azure-init-test-no-such-operator",
+            "/bin/zsh"
+        ));
+        assert!(runner.calls().iter().any(|argv| argv[0] == "usermod"
+            && argv.iter().any(|arg| arg.contains("adm"))
+            && argv.contains(&"azure-init-test-no-such-operator".to_string())));
+        assert!(created(
+            &useradds[2],
+            "azure-init-test-no-such-backup",
+            "/bin/sh"
+        ));
+    }
+
+    #[tokio::test]
+    async fn keys_skipped_without_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("app");
+        let keys = vec![PublicKeys {
+            key_data: "ssh-rsa test_key".to_string(),
+            path: "/opt/app/.ssh/authorized_keys".to_string(),
+        }];
+        let summary = test_builder(Arc::new(MockRunner::succeeding()))
+            .user(
+                User::new("azure-init-test-no-such-user")
+                    .with_home(&home)
+                    .with_create_home(false),
+            )
+            .keys(keys)
+            .build()
+            .provision_async()
+            .await;
+
+        assert!(summary.is_success());
+        assert!(!home.exists());
+    }
+
+    #[tokio::test]
+    async fn keys_wait_for_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let current = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let mut config = Config::default();
+        config.ssh.wait_for_home = true;
+        config.ssh.wait_for_home_timeout_secs = 0.05;
+        let provision = |home: &Path| {
+            Provision::builder()
+                .hostname("test-host")
+                .user(User::new(current.name.clone()).with_home(home))
+                .keys(vec![PublicKeys {
+                    key_data: "ssh-rsa test_key".to_string(),
+                    path: String::new(),
+                }])
+                .config(&config)
+                .selinux_contexts(false)
+                .build()
+        };
+
+        let missing = dir.path().join("missing");
+        let error = provision(&missing).provision_ssh_keys().await.unwrap_err();
+        assert!(matches!(error, Error::HomeNotReady { .. }));
+        assert!(!missing.exists());
+
+        provision(dir.path()).provision_ssh_keys().await.unwrap();
+        assert!(dir.path().join(".ssh/authorized_keys").exists());
+    }
+
+    #[tokio::test]
+    async fn hooks_run_at_each_phase() {
+        let dir = tempfile::tempdir().unwrap();
+        let hook = dir.path().join("10-hook");
+        std::fs::write(&hook, "").unwrap();
+        std::fs::set_permissions(
+            &hook,
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .unwrap();
+
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .hooks(config::Hooks {
+                enable: true,
+                dir: dir.path().to_path_buf(),
+                fatal: true,
+            })
+            .build()
+            .provision_async()
+            .await;
+        assert!(summary.hooks.is_ok());
+
+        let hook = hook.to_string_lossy();
+        let calls: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| {
+                // Record hooks by the phase they ran in.
+                if argv[0] == hook {
+                    argv[1].clone()
+                } else {
+                    argv[0].clone()
+                }
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                "pre-provision",
+                "useradd",
+                "post-user",
+                "passwd",
+                "post-provision"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn post_provision_runs_only_on_success() {
+        let post_provision = config::PostProvision {
+            command: Some("systemctl enable ssh".to_string()),
+            fatal: true,
+        };
+
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .post_provision(post_provision.clone())
+            .build()
+            .provision_async()
+            .await;
+        assert!(summary.is_success());
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &vec!["sh", "-c", "systemctl enable ssh"]
+        );
+
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .hostname_backends(vec![HostnameProvisioner::FakeFailure])
+            .post_provision(post_provision)
+            .build()
+            .provision_async()
+            .await;
+        assert!(summary.hostname.is_err());
+        assert!(summary.post_provision.is_ok());
+        assert!(runner.calls().iter().all(|argv| argv[0] != "sh"));
+    }
+
+    #[tokio::test]
+    async fn post_provision_failure_fatal_only_when_configured() {
+        for fatal in [false, true] {
+            let summary = test_builder(Arc::new(MockRunner::failing("sh", 1)))
+                .post_provision(config::PostProvision {
+                    command: Some("false".to_string()),
+                    fatal,
+                })
+                .build()
+                .provision_async()
+                .await;
+            assert_eq!(summary.is_success(), !fatal);
+        }
+    }
+
+    #[tokio::test]
+    async fn first_error_names_its_step() {
+        let summary = test_builder(Arc::new(MockRunner::failing("useradd", 9)))
+            .build()
+            .provision_async()
+            .await;
+
+        let error = summary.into_result().unwrap_err();
+        assert_eq!(error.step, "user");
+        let message = error.to_string();
+        assert!(message.starts_with("step=user: executing "), "{message}");
+        assert!(message.contains("useradd"), "{message}");
+
+        // Callers handling the library's errors still get the cause.
+        let error: Error = error.into();
+        assert!(matches!(error, Error::SubprocessFailed { .. }));
+    }
+
+    #[tokio::test]
+    async fn password_backends_fall_back() {
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .password_backends(vec![
+                PasswordProvisioner::FakeFailure,
+                PasswordProvisioner::Chpasswd,
+            ])
+            .build()
+            .provision_async()
+            .await;
+
+        assert!(summary.password.is_ok());
+        let programs: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| argv[0].clone())
+            .collect();
+        assert_eq!(programs, vec!["useradd", "chpasswd"]);
+        assert_eq!(
+            runner.stdin(),
+            vec![b"azure-init-test-no-such-user:\n".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_user_provisioned() {
+        let existing = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .user(User::new(&existing.name).with_create_home(false))
+            .build()
+            .provision_async()
+            .await;
+
+        assert!(summary.user.is_ok());
+        assert!(runner.calls().iter().all(|argv| argv[0] != "useradd"));
+    }
+
+    #[tokio::test]
+    async fn system_account_has_no_password() {
+        let mut config = Config::default();
+        config.user_provisioners.system_account = true;
+        config.user_provisioners.grant_sudo = false;
+        let runner = Arc::new(MockRunner::succeeding());
+        let provision = test_builder(runner.clone()).config(&config).build();
+        assert!(provision.user().is_system());
+
+        let summary = provision.provision_async().await;
+        assert!(summary.user.is_ok());
+        assert!(summary.password.is_ok());
+        let programs: Vec<String> = runner
+            .calls()
+            .into_iter()
+            .map(|argv| argv[0].clone())
+            .collect();
+        assert_eq!(programs, vec!["useradd"]);
+        assert!(runner.calls()[0].contains(&"--system".to_string()));
+    }
+
+    #[tokio::test]
+    async fn empty_hostname_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc_hostname = dir.path().join("hostname");
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .hostname("")
+            .hostname_backends(vec![HostnameProvisioner::FakeFailure])
+            .etc_hostname_path(&etc_hostname)
+            .build()
+            .provision_async()
+            .await;
+
+        assert!(summary.hostname.is_ok());
+        assert!(!etc_hostname.exists());
+    }
+
+    #[tokio::test]
+    async fn hostname_skipped_when_disabled() {
+        let runner = Arc::new(MockRunner::succeeding());
+        let summary = test_builder(runner.clone())
+            .hostname_backends(vec![
+                HostnameProvisioner::Hostnamectl,
+                HostnameProvisioner::FakeFailure,
+            ])
+            .hostname_enabled(false)
+            .build()
+            .provision_async()
+            .await;
+
+        assert!(summary.hostname.is_ok());
+        assert!(!runner
+            .calls()
+            .iter()
+            .any(|argv| argv[0] == "hostnamectl" || argv[0] == "hostname"));
+    }
+
+    #[tokio::test]
+    async fn provision_returns_first_error() {
+        let runner = Arc::new(MockRunner::failing("useradd", 9));
+        let result = test_builder(runner)
+            .build()
+            .provision()
+            .await
+            .map_err(Error::from);
+
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+    }
+}
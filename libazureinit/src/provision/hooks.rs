@@ -0,0 +1,306 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Running operator-provided scripts at points during provisioning.
+//!
+//! Every executable file in the hooks directory is run, in order of file
+//! name, with the name of the [`Phase`] as its only argument. A single
+//! post-provision command can also be configured without any scripts.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config;
+use crate::error::Error;
+use crate::runner::{run, CommandRunner};
+
+/// The directory hooks are read from by default.
+pub const PATH_HOOKS_DIR: &str = "/etc/azure-init/hooks.d";
+
+/// A point during provisioning at which hooks run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Before anything is provisioned.
+    PreProvision,
+    /// After the user is created.
+    PostUser,
+    /// After every provisioning step has been attempted.
+    PostProvision,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::PreProvision => "pre-provision",
+            Phase::PostUser => "post-user",
+            Phase::PostProvision => "post-provision",
+        }
+    }
+}
+
+/// Find the executable files in `dir`, sorted by name.
+///
+/// A missing directory has no hooks. An entry which can't be read, like a
+/// broken symlink, is an error if `fatal` is set, and is otherwise logged and
+/// skipped.
+pub fn discover(dir: &Path, fatal: bool) -> Result<Vec<PathBuf>, Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut hooks = vec![];
+    for entry in entries {
+        let entry = entry.and_then(|entry| {
+            let path = entry.path();
+            fs::metadata(&path).map(|metadata| (path, metadata))
+        });
+        let (path, metadata) = match entry {
+            Ok(entry) => entry,
+            Err(error) if !fatal => {
+                tracing::warn!(
+                    dir = %dir.display(),
+                    ?error,
+                    "Skipping a hook which can't be read"
+                );
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+        if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+            hooks.push(path);
+        } else {
+            tracing::debug!(path = %path.display(), "Skipping non-executable hook");
+        }
+    }
+    hooks.sort();
+
+    Ok(hooks)
+}
+
+/// Run the hooks for `phase`, if enabled by `hooks`.
+///
+/// Every hook runs even if an earlier one fails. Failures, including a hooks
+/// directory which can't be read, are logged, and the first is returned if
+/// `hooks.fatal` is set.
+pub fn run_hooks(
+    runner: &dyn CommandRunner,
+    hooks: &config::Hooks,
+    phase: Phase,
+) -> Result<(), Error> {
+    if !hooks.enable {
+        return Ok(());
+    }
+
+    let found = match discover(&hooks.dir, hooks.fatal) {
+        Ok(found) => found,
+        Err(error) if !hooks.fatal => {
+            tracing::error!(
+                dir = %hooks.dir.display(),
+                ?error,
+                "Failed to read the hooks directory"
+            );
+            return Ok(());
+        }
+        Err(error) => return Err(error),
+    };
+
+    let mut first_error = None;
+    for hook in found {
+        tracing::info!(hook = %hook.display(), phase = phase.as_str(), "Running hook");
+        let mut command = Command::new(&hook);
+        command.arg(phase.as_str());
+        if let Err(error) = run(runner, command) {
+            tracing::error!(hook = %hook.display(), ?error, "Hook failed");
+            first_error.get_or_insert(error);
+        }
+    }
+
+    match first_error {
+        Some(error) if hooks.fatal => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Run the configured post-provision command, if any, with `sh -c`.
+///
+/// Its output is logged either way. A failure is only returned if
+/// `post_provision.fatal` is set.
+pub fn run_post_provision(
+    runner: &dyn CommandRunner,
+    post_provision: &config::PostProvision,
+) -> Result<(), Error> {
+    let Some(script) = &post_provision.command else {
+        return Ok(());
+    };
+
+    tracing::info!(command = script, "Running the post-provision command");
+    let path_sh = env!("PATH_SH");
+    let mut command = Command::new(path_sh);
+    command.arg("-c").arg(script);
+    match run(runner, command) {
+        Ok(output) => {
+            tracing::info!(
+                command = script,
+                stdout = %String::from_utf8_lossy(&output.stdout),
+                stderr = %String::from_utf8_lossy(&output.stderr),
+                "Post-provision command succeeded"
+            );
+            Ok(())
+        }
+        Err(error) => {
+            tracing::error!(
+                command = script,
+                ?error,
+                "Post-provision command failed"
+            );
+            if post_provision.fatal {
+                return Err(error);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::MockRunner;
+    use crate::runner::SystemRunner;
+
+    fn write_hook(dir: &Path, name: &str, script: &str, mode: u32) {
+        let path = dir.join(name);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn enabled(dir: &Path, fatal: bool) -> config::Hooks {
+        config::Hooks {
+            enable: true,
+            dir: dir.to_path_buf(),
+            fatal,
+        }
+    }
+
+    #[test]
+    fn hooks_run_in_order_with_phase() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let hooks = dir.path().join("hooks.d");
+        fs::create_dir(&hooks).unwrap();
+        for name in ["20-second", "10-first", "30-third"] {
+            let script =
+                format!("#!/bin/sh\necho \"{name} $1\" >> {}\n", log.display());
+            write_hook(&hooks, name, &script, 0o755);
+        }
+        write_hook(&hooks, "15-disabled", "#!/bin/sh\nexit 1\n", 0o644);
+
+        run_hooks(&SystemRunner, &enabled(&hooks, true), Phase::PostUser)
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "10-first post-user\n20-second post-user\n30-third post-user\n"
+        );
+    }
+
+    #[test]
+    fn failures_fatal_only_when_configured() {
+        let dir = tempfile::tempdir().unwrap();
+        write_hook(dir.path(), "10-fails", "", 0o755);
+        write_hook(dir.path(), "20-runs", "", 0o755);
+        let runner = MockRunner::new(|argv| {
+            let code = if argv[0].ends_with("10-fails") { 1 } else { 0 };
+            Ok(crate::runner::mock::output(code, ""))
+        });
+
+        run_hooks(&runner, &enabled(dir.path(), false), Phase::PreProvision)
+            .unwrap();
+        assert_eq!(runner.calls().len(), 2);
+
+        let result =
+            run_hooks(&runner, &enabled(dir.path(), true), Phase::PreProvision);
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+        // The remaining hooks still ran.
+        assert_eq!(runner.calls().len(), 4);
+        assert_eq!(runner.calls()[3][1], "pre-provision");
+    }
+
+    #[test]
+    fn post_provision_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("provisioned");
+        let post_provision = config::PostProvision {
+            command: Some(format!("touch {}", marker.display())),
+            fatal: true,
+        };
+
+        run_post_provision(&SystemRunner, &post_provision).unwrap();
+        assert!(marker.exists());
+
+        let runner = MockRunner::succeeding();
+        run_post_provision(&runner, &config::PostProvision::default()).unwrap();
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn post_provision_failure_fatal_only_when_configured() {
+        let runner = MockRunner::failing("sh", 1);
+        let mut post_provision = config::PostProvision {
+            command: Some("systemctl enable ssh".to_string()),
+            fatal: false,
+        };
+
+        run_post_provision(&runner, &post_provision).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![vec!["sh", "-c", "systemctl enable ssh"]]
+        );
+
+        post_provision.fatal = true;
+        let result = run_post_provision(&runner, &post_provision);
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+    }
+
+    #[test]
+    fn unreadable_hook_fatal_only_when_configured() {
+        let dir = tempfile::tempdir().unwrap();
+        write_hook(dir.path(), "10-runs", "", 0o755);
+        std::os::unix::fs::symlink(
+            dir.path().join("missing"),
+            dir.path().join("20-broken"),
+        )
+        .unwrap();
+        let runner = MockRunner::succeeding();
+
+        run_hooks(&runner, &enabled(dir.path(), false), Phase::PostUser)
+            .unwrap();
+        assert_eq!(runner.calls().len(), 1);
+        assert!(runner.calls()[0][0].ends_with("10-runs"));
+
+        let result =
+            run_hooks(&runner, &enabled(dir.path(), true), Phase::PostUser);
+        assert!(matches!(result, Err(Error::Io(_))));
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn disabled_or_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        write_hook(dir.path(), "10-hook", "", 0o755);
+        let runner = MockRunner::succeeding();
+
+        let mut hooks = enabled(dir.path(), true);
+        hooks.enable = false;
+        run_hooks(&runner, &hooks, Phase::PostProvision).unwrap();
+        assert!(runner.calls().is_empty());
+
+        let hooks = enabled(&dir.path().join("missing"), true);
+        run_hooks(&runner, &hooks, Phase::PostProvision).unwrap();
+        assert!(runner.calls().is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resolving the hostname to the VM's private IP with an `/etc/hosts` line.
+//!
+//! The line is marked as managed by azure-init, and only that line is
+//! replaced, so other entries are left alone.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::error::Error;
+
+/// The static table of hostnames.
+pub const PATH_ETC_HOSTS: &str = "/etc/hosts";
+
+/// Marks the line azure-init manages.
+const MARKER: &str = "# managed by azure-init";
+
+/// Map `hostname` to `ip` with a managed line in the hosts file at `path`.
+///
+/// The file is only written when it changes.
+pub fn map_hostname(
+    path: &Path,
+    ip: &str,
+    hostname: &str,
+) -> Result<(), Error> {
+    let existing = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let rendered = render(&existing, ip, hostname);
+    if rendered != existing {
+        tracing::info!(path = %path.display(), ip, hostname, "Mapping the hostname");
+        fs::write(path, rendered)?;
+    }
+
+    Ok(())
+}
+
+fn render(existing: &str, ip: &str, hostname: &str) -> String {
+    let mut rendered: String = existing
+        .lines()
+        .filter(|line| !line.ends_with(MARKER))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    rendered.push_str(&format!("{ip} {hostname} {MARKER}\n"));
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTS: &str = "127.0.0.1 localhost\n::1 localhost ip6-localhost\n";
+
+    #[test]
+    fn line_added() {
+        assert_eq!(
+            render(HOSTS, "10.0.0.4", "test-host"),
+            format!("{HOSTS}10.0.0.4 test-host # managed by azure-init\n")
+        );
+    }
+
+    #[test]
+    fn line_updated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts");
+        fs::write(&path, HOSTS).unwrap();
+
+        map_hostname(&path, "10.0.0.4", "test-host").unwrap();
+        map_hostname(&path, "10.0.0.4", "test-host").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{HOSTS}10.0.0.4 test-host # managed by azure-init\n")
+        );
+
+        map_hostname(&path, "10.0.0.5", "new-host").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{HOSTS}10.0.0.5 new-host # managed by azure-init\n")
+        );
+    }
+}
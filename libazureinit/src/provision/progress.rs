@@ -0,0 +1,175 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Reporting provisioning's progress in a status file, so monitoring can
+//! follow along while it's running rather than only see the outcome.
+//!
+//! The file is rewritten as each step starts and finishes, by renaming a
+//! complete file into place, so a reader never sees a partial one.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// Where the status is written while provisioning.
+pub const PATH_STATUS_FILE: &str = "/run/azure-init/status.json";
+
+/// Whether provisioning is still running, and how it ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// The contents of the status file.
+#[derive(Debug, Serialize)]
+struct Status {
+    state: State,
+    /// The step in progress, if any.
+    step: Option<&'static str>,
+    completed: Vec<&'static str>,
+    failed: Vec<&'static str>,
+}
+
+/// Keeps the status file at a path up to date, or does nothing without one.
+///
+/// Failing to write the file is logged rather than failing provisioning.
+#[derive(Debug)]
+pub struct Progress {
+    path: Option<PathBuf>,
+    status: Status,
+}
+
+impl Progress {
+    /// Start reporting progress to `path`, if given.
+    pub fn new(path: Option<&Path>) -> Self {
+        let progress = Self {
+            path: path.map(Path::to_path_buf),
+            status: Status {
+                state: State::Running,
+                step: None,
+                completed: vec![],
+                failed: vec![],
+            },
+        };
+        progress.write();
+        progress
+    }
+
+    /// Note that `step` has started.
+    pub fn start(&mut self, step: &'static str) {
+        self.status.step = Some(step);
+        self.write();
+    }
+
+    /// Note that `step` has finished with `result`.
+    pub fn finish<T>(&mut self, step: &'static str, result: &Result<T, Error>) {
+        self.status.step = None;
+        match result {
+            Ok(_) => self.status.completed.push(step),
+            Err(_) => self.status.failed.push(step),
+        }
+        self.write();
+    }
+
+    /// Run `step` with `f`, noting when it starts and finishes.
+    pub fn step<T>(
+        &mut self,
+        step: &'static str,
+        f: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.start(step);
+        let result = f();
+        self.finish(step, &result);
+        result
+    }
+
+    /// Note that provisioning is over, having succeeded or not.
+    pub fn done(&mut self, success: bool) {
+        self.status.step = None;
+        self.status.state = if success {
+            State::Succeeded
+        } else {
+            State::Failed
+        };
+        self.write();
+    }
+
+    fn write(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(error) = write_status(path, &self.status) {
+            tracing::warn!(
+                ?error,
+                path = %path.display(),
+                "Failed to write the status file"
+            );
+        }
+    }
+}
+
+fn write_status(path: &Path, status: &Status) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let staging = path.with_extension("json.tmp");
+    fs::write(&staging, serde_json::to_vec(status)?)?;
+    fs::rename(&staging, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(path: &Path) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn status_transitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init/status.json");
+
+        let mut progress = Progress::new(Some(&path));
+        assert_eq!(
+            read(&path),
+            serde_json::json!({
+                "state": "running",
+                "step": null,
+                "completed": [],
+                "failed": []
+            })
+        );
+
+        progress.start("hostname");
+        assert_eq!(read(&path)["step"], "hostname");
+        progress.finish("hostname", &Ok(()));
+        progress.finish("user", &Err::<(), _>(Error::UsernameFailure));
+        progress.done(false);
+        assert_eq!(
+            read(&path),
+            serde_json::json!({
+                "state": "failed",
+                "step": null,
+                "completed": ["hostname"],
+                "failed": ["user"]
+            })
+        );
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn nothing_written_without_path() {
+        let mut progress = Progress::new(None);
+        progress.step("hostname", || Ok(())).unwrap();
+        progress.done(true);
+    }
+}
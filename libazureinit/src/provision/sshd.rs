@@ -0,0 +1,185 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Enforcing sshd settings with a configuration drop-in.
+//!
+//! Each setting replaces an existing, possibly commented out, directive for
+//! the same keyword in the drop-in, or is appended if there is none. Other
+//! lines are left alone, and the file is only written when it changes.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::error::Error;
+
+/// The drop-in holding the settings azure-init enforces.
+pub const PATH_SSHD_DROP_IN: &str = "/etc/ssh/sshd_config.d/50-azure-init.conf";
+
+/// The file holding the certificate authorities trusted to sign user
+/// certificates, named by the `TrustedUserCAKeys` directive.
+pub const PATH_TRUSTED_USER_CA_KEYS: &str = "/etc/ssh/trusted_user_ca_keys";
+
+/// Set each of `settings`, keyword to value, in the sshd drop-in at `path`.
+///
+/// Nothing is written if there are no settings.
+pub fn enforce_settings(
+    path: &Path,
+    settings: &BTreeMap<String, String>,
+) -> Result<(), Error> {
+    if settings.is_empty() {
+        return Ok(());
+    }
+
+    let existing = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let rendered = render(&existing, settings);
+    if rendered != existing {
+        tracing::info!(
+            path = %path.display(),
+            settings = ?settings.keys().collect::<Vec<_>>(),
+            "Writing sshd settings"
+        );
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, rendered)?;
+    }
+
+    Ok(())
+}
+
+/// Write the CA public `keys` to `path`, readable by everyone but only
+/// writable by its owner, as sshd expects.
+///
+/// The file is only written when it changes.
+pub fn write_trusted_user_ca_keys(
+    path: &Path,
+    keys: &str,
+) -> Result<(), Error> {
+    let contents = format!("{}\n", keys.trim());
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => Some(existing),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if existing.as_ref() != Some(&contents) {
+        tracing::info!(path = %path.display(), "Writing trusted user CA keys");
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, &contents)?;
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(0o644))?;
+
+    Ok(())
+}
+
+fn render(existing: &str, settings: &BTreeMap<String, String>) -> String {
+    let mut rendered = existing.to_string();
+    for (keyword, value) in settings {
+        let directive = format!("{keyword} {value}");
+        let pattern =
+            format!(r"(?mi)^[ \t]*#?[ \t]*{}\b.*$", regex::escape(keyword));
+        let re =
+            Regex::new(&pattern).expect("escaped keyword is a valid regex");
+        if re.is_match(&rendered) {
+            rendered = re
+                .replace_all(&rendered, regex::NoExpand(&directive))
+                .into_owned();
+        } else {
+            if !rendered.is_empty() && !rendered.ends_with('\n') {
+                rendered.push('\n');
+            }
+            rendered.push_str(&directive);
+            rendered.push('\n');
+        }
+    }
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("PasswordAuthentication".to_string(), "no".to_string()),
+            ("PermitRootLogin".to_string(), "no".to_string()),
+        ])
+    }
+
+    #[test]
+    fn settings_replaced_or_appended() {
+        let existing = "# Managed by the image\n\
+                        #PasswordAuthentication yes\n\
+                        ClientAliveInterval 120\n";
+
+        assert_eq!(
+            render(existing, &settings()),
+            "# Managed by the image\n\
+             PasswordAuthentication no\n\
+             ClientAliveInterval 120\n\
+             PermitRootLogin no\n"
+        );
+    }
+
+    #[test]
+    fn keyword_matched_whole() {
+        let settings =
+            BTreeMap::from([("Port".to_string(), "2222".to_string())]);
+        assert_eq!(
+            render("PortForwarding no\n", &settings),
+            "PortForwarding no\nPort 2222\n"
+        );
+    }
+
+    #[test]
+    fn enforce_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sshd_config.d").join("50-azure-init.conf");
+
+        enforce_settings(&path, &settings()).unwrap();
+        let first = fs::read_to_string(&path).unwrap();
+        assert!(first.contains("PasswordAuthentication no\n"));
+        assert!(first.contains("PermitRootLogin no\n"));
+
+        enforce_settings(&path, &settings()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), first);
+    }
+
+    #[test]
+    fn trusted_user_ca_keys_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ssh").join("trusted_user_ca_keys");
+
+        write_trusted_user_ca_keys(&path, "ssh-ed25519 AAAA ca\n\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ssh-ed25519 AAAA ca\n");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+
+        // A too permissive file is fixed even when it holds the keys.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+        write_trusted_user_ca_keys(&path, "ssh-ed25519 AAAA ca").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+
+    #[test]
+    fn nothing_written_without_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("50-azure-init.conf");
+
+        enforce_settings(&path, &BTreeMap::new()).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Granting the provisioned user passwordless sudo.
+//!
+//! The rule is written to a staging file, checked with `visudo`, and only
+//! then renamed into place, so a bad rule can't lock everyone out of sudo.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::Error;
+use crate::runner::{run, CommandRunner};
+
+/// The directory sudo reads drop-in rules from.
+pub const PATH_SUDOERS_DIR: &str = "/etc/sudoers.d";
+
+/// Grant `username` passwordless sudo with a rule in [`PATH_SUDOERS_DIR`].
+pub fn grant_sudo(
+    runner: &dyn CommandRunner,
+    username: &str,
+) -> Result<(), Error> {
+    grant_sudo_in(runner, username, Path::new(PATH_SUDOERS_DIR))
+}
+
+/// Like [`grant_sudo`], with the rule in `dir`.
+pub fn grant_sudo_in(
+    runner: &dyn CommandRunner,
+    username: &str,
+    dir: &Path,
+) -> Result<(), Error> {
+    let path = sudoers_path(dir, username);
+    let contents = rule(username);
+    match fs::read_to_string(&path) {
+        Ok(existing) if existing == contents => return Ok(()),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // sudo skips files with a dot in their name, so the staging file is
+    // never read even if it's left behind.
+    let staging = path.with_extension("azure-init");
+    fs::create_dir_all(dir)?;
+    fs::write(&staging, &contents)?;
+    fs::set_permissions(&staging, fs::Permissions::from_mode(0o440))?;
+
+    let path_visudo = env!("PATH_VISUDO");
+    let mut command = Command::new(path_visudo);
+    command.arg("-c").arg("-q").arg("-f").arg(&staging);
+    if let Err(error) = run(runner, command) {
+        fs::remove_file(&staging)?;
+        return Err(error);
+    }
+
+    tracing::info!(
+        user = username,
+        path = %path.display(),
+        "Granting passwordless sudo"
+    );
+    fs::rename(&staging, &path)?;
+
+    Ok(())
+}
+
+/// The drop-in for `username`, with any dots replaced since sudo ignores
+/// files whose names contain one.
+fn sudoers_path(dir: &Path, username: &str) -> PathBuf {
+    dir.join(format!("90-azure-init-{}", username.replace('.', "_")))
+}
+
+fn rule(username: &str) -> String {
+    format!("{username} ALL=(ALL) NOPASSWD:ALL\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::MockRunner;
+
+    #[test]
+    fn sudoers_contents() {
+        assert_eq!(rule("azureuser"), "azureuser ALL=(ALL) NOPASSWD:ALL\n");
+        assert_eq!(
+            sudoers_path(Path::new("/etc/sudoers.d"), "first.last"),
+            Path::new("/etc/sudoers.d/90-azure-init-first_last")
+        );
+    }
+
+    #[test]
+    fn rule_validated_and_installed() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::succeeding();
+
+        grant_sudo_in(&runner, "azureuser", dir.path()).unwrap();
+
+        let path = dir.path().join("90-azure-init-azureuser");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "azureuser ALL=(ALL) NOPASSWD:ALL\n"
+        );
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o440);
+        let staging = dir.path().join("90-azure-init-azureuser.azure-init");
+        assert_eq!(
+            runner.calls(),
+            vec![vec!["visudo", "-c", "-q", "-f", staging.to_str().unwrap()]]
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // An existing, identical rule isn't checked or written again.
+        grant_sudo_in(&runner, "azureuser", dir.path()).unwrap();
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn invalid_rule_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::failing("visudo", 1);
+
+        let result = grant_sudo_in(&runner, "azureuser", dir.path());
+
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Setting the system locale, keyboard layout, and timezone.
+//!
+//! Each setting is only applied when configured. The commands used are
+//! idempotent, and the `/etc/localtime` fallback leaves a correct link alone.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path};
+use std::process::Command;
+
+use crate::config;
+use crate::error::Error;
+use crate::runner::{run, CommandRunner};
+
+/// Where the timezone database is installed.
+pub const PATH_ZONEINFO: &str = "/usr/share/zoneinfo";
+
+/// The link to the local timezone's file in the timezone database.
+pub const PATH_LOCALTIME: &str = "/etc/localtime";
+
+/// Apply each of the configured locale, keymap, and timezone.
+///
+/// Every setting is attempted even if an earlier one fails, and the first
+/// error is returned.
+pub fn apply(
+    runner: &dyn CommandRunner,
+    system: &config::System,
+) -> Result<(), Error> {
+    let mut results = vec![];
+    if let Some(locale) = &system.locale {
+        results.push(set_locale(runner, locale));
+    }
+    if let Some(keymap) = &system.keymap {
+        results.push(set_keymap(runner, keymap));
+    }
+    if let Some(timezone) = &system.timezone {
+        results.push(set_timezone(runner, timezone));
+    }
+
+    results.into_iter().collect()
+}
+
+/// Set the system locale, e.g. `en_US.UTF-8`, with `localectl`.
+pub fn set_locale(
+    runner: &dyn CommandRunner,
+    locale: &str,
+) -> Result<(), Error> {
+    let path_localectl = env!("PATH_LOCALECTL");
+
+    let mut command = Command::new(path_localectl);
+    command.arg("set-locale").arg(format!("LANG={locale}"));
+    run(runner, command)?;
+
+    Ok(())
+}
+
+/// Set the console keyboard layout, e.g. `us`, with `localectl`.
+pub fn set_keymap(
+    runner: &dyn CommandRunner,
+    keymap: &str,
+) -> Result<(), Error> {
+    let path_localectl = env!("PATH_LOCALECTL");
+
+    let mut command = Command::new(path_localectl);
+    command.arg("set-keymap").arg(keymap);
+    run(runner, command)?;
+
+    Ok(())
+}
+
+/// Set the timezone, e.g. `Europe/Berlin`, with `timedatectl`.
+///
+/// Without a working `timedatectl`, e.g. when systemd isn't running yet,
+/// `/etc/localtime` is linked to the timezone's file instead.
+pub fn set_timezone(
+    runner: &dyn CommandRunner,
+    timezone: &str,
+) -> Result<(), Error> {
+    set_timezone_at(
+        runner,
+        timezone,
+        Path::new(PATH_ZONEINFO),
+        Path::new(PATH_LOCALTIME),
+    )
+}
+
+fn set_timezone_at(
+    runner: &dyn CommandRunner,
+    timezone: &str,
+    zoneinfo: &Path,
+    localtime: &Path,
+) -> Result<(), Error> {
+    let relative = Path::new(timezone);
+    if timezone.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(Error::InvalidTimezone {
+            timezone: timezone.to_string(),
+        });
+    }
+
+    let path_timedatectl = env!("PATH_TIMEDATECTL");
+    let mut command = Command::new(path_timedatectl);
+    command.arg("set-timezone").arg(timezone);
+    match run(runner, command) {
+        Ok(_) => return Ok(()),
+        Err(error) => tracing::warn!(
+            ?error,
+            "timedatectl failed, linking {} instead",
+            localtime.display()
+        ),
+    }
+
+    let target = zoneinfo.join(relative);
+    if !target.is_file() {
+        return Err(Error::InvalidTimezone {
+            timezone: timezone.to_string(),
+        });
+    }
+    match fs::read_link(localtime) {
+        Ok(existing) if existing == target => return Ok(()),
+        Ok(_) => {}
+        Err(e)
+            if e.kind() == ErrorKind::NotFound
+                || e.kind() == ErrorKind::InvalidInput => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // Link beside the file and rename it into place, so /etc/localtime is
+    // never missing.
+    let staging = localtime.with_extension("azure-init");
+    match fs::remove_file(&staging) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    symlink(&target, &staging)?;
+    fs::rename(&staging, localtime)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::MockRunner;
+
+    #[test]
+    fn commands() {
+        let runner = MockRunner::succeeding();
+        let system = config::System {
+            locale: Some("en_US.UTF-8".to_string()),
+            timezone: Some("Europe/Berlin".to_string()),
+            keymap: Some("de".to_string()),
+        };
+        apply(&runner, &system).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![
+                vec!["localectl", "set-locale", "LANG=en_US.UTF-8"],
+                vec!["localectl", "set-keymap", "de"],
+                vec!["timedatectl", "set-timezone", "Europe/Berlin"],
+            ]
+        );
+    }
+
+    #[test]
+    fn unset_fields_skipped() {
+        let runner = MockRunner::succeeding();
+        let system = config::System {
+            keymap: Some("us".to_string()),
+            ..Default::default()
+        };
+        apply(&runner, &system).unwrap();
+
+        assert_eq!(runner.calls(), vec![vec!["localectl", "set-keymap", "us"]]);
+    }
+
+    #[test]
+    fn localtime_linked_without_timedatectl() {
+        let dir = tempfile::tempdir().unwrap();
+        let zoneinfo = dir.path().join("zoneinfo");
+        fs::create_dir_all(zoneinfo.join("Europe")).unwrap();
+        fs::write(zoneinfo.join("Europe/Berlin"), "TZif").unwrap();
+        fs::write(zoneinfo.join("UTC"), "TZif").unwrap();
+        let localtime = dir.path().join("localtime");
+        symlink(zoneinfo.join("UTC"), &localtime).unwrap();
+
+        let runner = MockRunner::failing("timedatectl", 1);
+        set_timezone_at(&runner, "Europe/Berlin", &zoneinfo, &localtime)
+            .unwrap();
+        assert_eq!(
+            fs::read_link(&localtime).unwrap(),
+            zoneinfo.join("Europe/Berlin")
+        );
+
+        // Running again leaves the link alone.
+        set_timezone_at(&runner, "Europe/Berlin", &zoneinfo, &localtime)
+            .unwrap();
+        assert_eq!(
+            fs::read_link(&localtime).unwrap(),
+            zoneinfo.join("Europe/Berlin")
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn invalid_timezone() {
+        let dir = tempfile::tempdir().unwrap();
+        let localtime = dir.path().join("localtime");
+        let runner = MockRunner::failing("timedatectl", 1);
+
+        for timezone in ["", "../etc/passwd", "/etc/passwd", "Mars/Olympus"] {
+            let result =
+                set_timezone_at(&runner, timezone, dir.path(), &localtime);
+            assert!(
+                matches!(result, Err(Error::InvalidTimezone { .. })),
+                "{timezone}"
+            );
+        }
+        assert!(!localtime.exists());
+    }
+}
@@ -0,0 +1,12 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Finding system paths in a tree other than `/`, so that detection can be
+//! tested against a temporary one.
+
+use std::path::{Path, PathBuf};
+
+/// The absolute `path` on the system rooted at `root`.
+pub(crate) fn under(root: &Path, path: &str) -> PathBuf {
+    root.join(Path::new(path).strip_prefix("/").unwrap_or(Path::new(path)))
+}
@@ -0,0 +1,483 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Execution of external commands.
+//!
+//! Provisioning steps run their commands through a [`CommandRunner`] so that
+//! tests can substitute one which records the command line rather than
+//! executing it.
+
+use std::fmt::Debug;
+use std::io::{ErrorKind, Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+
+/// Something able to run a command to completion.
+pub trait CommandRunner: Debug + Send + Sync {
+    /// Run `command`, capturing its output.
+    fn output(&self, command: &mut Command) -> Result<Output, std::io::Error>;
+
+    /// Run `command` with `stdin` as its standard input, capturing its
+    /// output.
+    fn output_with_stdin(
+        &self,
+        command: &mut Command,
+        stdin: &[u8],
+    ) -> Result<Output, std::io::Error>;
+
+    /// Run `command`, capturing its output, and kill it if it hasn't exited
+    /// within `timeout`, failing with [`ErrorKind::TimedOut`].
+    fn output_with_timeout(
+        &self,
+        command: &mut Command,
+        timeout: Duration,
+    ) -> Result<Output, std::io::Error>;
+}
+
+/// Runs commands on the host.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, command: &mut Command) -> Result<Output, std::io::Error> {
+        command.output()
+    }
+
+    fn output_with_stdin(
+        &self,
+        command: &mut Command,
+        stdin: &[u8],
+    ) -> Result<Output, std::io::Error> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Dropping the handle closes stdin, so the command sees its end.
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(stdin)?;
+        child.wait_with_output()
+    }
+
+    fn output_with_timeout(
+        &self,
+        command: &mut Command,
+        timeout: Duration,
+    ) -> Result<Output, std::io::Error> {
+        let deadline = Instant::now() + timeout;
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Drain the pipes while waiting, so a full one can't stall the
+        // command.
+        let stdout = read_to_end(child.stdout.take().expect("stdout is piped"));
+        let stderr = read_to_end(child.stderr.take().expect("stderr is piped"));
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                // A command stuck in uninterruptible sleep, like a hung
+                // mount, only exits once it wakes, if ever, so it's reaped
+                // in the background rather than waited for.
+                thread::spawn(move || child.wait());
+                return Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("the command did not exit within {timeout:?}"),
+                ));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().expect("reader doesn't panic")?,
+            stderr: stderr.join().expect("reader doesn't panic")?,
+        })
+    }
+}
+
+fn read_to_end(
+    mut pipe: impl Read + Send + 'static,
+) -> JoinHandle<Result<Vec<u8>, std::io::Error>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        pipe.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+/// Options whose value is a password, and so is left out of logs.
+const SECRET_OPTIONS: &[&str] = &["-p", "--password"];
+
+/// The program and arguments of `command`, with the values of any password
+/// options replaced by `<redacted>`.
+pub(crate) fn redacted_argv(command: &Command) -> Vec<String> {
+    let mut argv = vec![command.get_program().to_string_lossy().into_owned()];
+    let mut secret = false;
+    for arg in command.get_args() {
+        let arg = arg.to_string_lossy().into_owned();
+        let value = if secret {
+            "<redacted>".to_string()
+        } else {
+            match arg.split_once('=') {
+                Some((option, _)) if SECRET_OPTIONS.contains(&option) => {
+                    format!("{option}=<redacted>")
+                }
+                _ => arg.clone(),
+            }
+        };
+        secret = !secret && SECRET_OPTIONS.contains(&arg.as_str());
+        argv.push(value);
+    }
+    argv
+}
+
+/// Run `command` with `runner`, failing unless it exits successfully.
+pub(crate) fn run(
+    runner: &dyn CommandRunner,
+    mut command: Command,
+) -> Result<Output, Error> {
+    tracing::debug!(argv = ?redacted_argv(&command), "Running command");
+    let output = runner.output(&mut command)?;
+    check(&command, output)
+}
+
+/// Like [`run`], with `stdin` as the command's standard input.
+///
+/// The input isn't logged, since it may hold secrets.
+pub(crate) fn run_with_stdin(
+    runner: &dyn CommandRunner,
+    mut command: Command,
+    stdin: &[u8],
+) -> Result<Output, Error> {
+    tracing::debug!(
+        argv = ?redacted_argv(&command),
+        "Running command with input"
+    );
+    let output = runner.output_with_stdin(&mut command, stdin)?;
+    check(&command, output)
+}
+
+/// Like [`run`], failing with [`Error::SubprocessTimeout`] if the command
+/// hasn't exited within `timeout`.
+pub(crate) fn run_with_timeout(
+    runner: &dyn CommandRunner,
+    mut command: Command,
+    timeout: Duration,
+) -> Result<Output, Error> {
+    tracing::debug!(
+        argv = ?redacted_argv(&command),
+        ?timeout,
+        "Running command"
+    );
+    let output = match runner.output_with_timeout(&mut command, timeout) {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::TimedOut => {
+            let program = command.get_program().to_string_lossy().into_owned();
+            tracing::error!(%program, ?timeout, "Command timed out");
+            return Err(Error::SubprocessTimeout {
+                command: program,
+                timeout,
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    check(&command, output)
+}
+
+fn check(command: &Command, output: Output) -> Result<Output, Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        tracing::debug!(%program, %stdout, %stderr, "Command succeeded");
+        Ok(output)
+    } else {
+        tracing::error!(
+            %program,
+            status = %output.status,
+            %stdout,
+            %stderr,
+            "Command failed"
+        );
+        Err(Error::SubprocessFailed {
+            command: program,
+            status: output.status,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Command, ExitStatus, Output};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    use super::CommandRunner;
+
+    type Handler =
+        Arc<dyn Fn(&[String]) -> Result<Output, std::io::Error> + Send + Sync>;
+
+    /// A [`CommandRunner`] which records each command line and answers with
+    /// the output produced by a handler instead of running anything.
+    pub(crate) struct MockRunner {
+        calls: Mutex<Vec<Vec<String>>>,
+        stdin: Mutex<Vec<Vec<u8>>>,
+        handler: Handler,
+    }
+
+    impl std::fmt::Debug for MockRunner {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("MockRunner")
+                .field("calls", &self.calls)
+                .finish()
+        }
+    }
+
+    impl MockRunner {
+        pub(crate) fn new(
+            handler: impl Fn(&[String]) -> Result<Output, std::io::Error>
+                + Send
+                + Sync
+                + 'static,
+        ) -> Self {
+            Self {
+                calls: Mutex::new(vec![]),
+                stdin: Mutex::new(vec![]),
+                handler: Arc::new(handler),
+            }
+        }
+
+        /// A runner for which every command succeeds.
+        pub(crate) fn succeeding() -> Self {
+            Self::new(|_| Ok(output(0, "")))
+        }
+
+        /// A runner for which every invocation of `program` exits with
+        /// `code`, and everything else succeeds.
+        pub(crate) fn failing(program: &'static str, code: i32) -> Self {
+            Self::new(move |argv| {
+                if argv[0] == program {
+                    Ok(output(code, ""))
+                } else {
+                    Ok(output(0, ""))
+                }
+            })
+        }
+
+        /// The argv of each command run so far.
+        pub(crate) fn calls(&self) -> Vec<Vec<String>> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        /// The input given to each command run with stdin so far.
+        pub(crate) fn stdin(&self) -> Vec<Vec<u8>> {
+            self.stdin.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn output(
+            &self,
+            command: &mut Command,
+        ) -> Result<Output, std::io::Error> {
+            let argv: Vec<String> = std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            self.calls.lock().unwrap().push(argv.clone());
+            (self.handler)(&argv)
+        }
+
+        fn output_with_stdin(
+            &self,
+            command: &mut Command,
+            stdin: &[u8],
+        ) -> Result<Output, std::io::Error> {
+            self.stdin.lock().unwrap().push(stdin.to_vec());
+            self.output(command)
+        }
+
+        /// Runs the handler on another thread, so a handler which sleeps
+        /// stands in for a command which hangs.
+        fn output_with_timeout(
+            &self,
+            command: &mut Command,
+            timeout: Duration,
+        ) -> Result<Output, std::io::Error> {
+            let argv: Vec<String> = std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            self.calls.lock().unwrap().push(argv.clone());
+            let handler = self.handler.clone();
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || sender.send(handler(&argv)));
+            receiver
+                .recv_timeout(timeout)
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+        }
+    }
+
+    /// Build the output of a command exiting with `code`.
+    pub(crate) fn output(code: i32, stdout: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::mock::MockRunner;
+    use super::*;
+    use crate::http::events::Events;
+
+    #[test]
+    fn run_success() {
+        let runner = MockRunner::succeeding();
+        let mut command = Command::new("true");
+        command.arg("--flag");
+
+        run(&runner, command).unwrap();
+        assert_eq!(runner.calls(), vec![vec!["true", "--flag"]]);
+    }
+
+    #[test]
+    fn passwords_redacted() {
+        let mut command = Command::new("useradd");
+        command
+            .args(["-p", "secret", "--password=secret", "-m"])
+            .args(["--password", "secret", "user"]);
+
+        assert_eq!(
+            redacted_argv(&command),
+            vec![
+                "useradd",
+                "-p",
+                "<redacted>",
+                "--password=<redacted>",
+                "-m",
+                "--password",
+                "<redacted>",
+                "user",
+            ]
+        );
+    }
+
+    #[test]
+    fn passwords_not_logged() {
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let runner = MockRunner::succeeding();
+        let command = || {
+            let mut command = Command::new("useradd");
+            command.args(["--password", "secret", "user"]);
+            command
+        };
+
+        run(&runner, command()).unwrap();
+        run_with_stdin(&runner, command(), b"").unwrap();
+        run_with_timeout(&runner, command(), Duration::from_secs(5)).unwrap();
+
+        let events = events.0.lock().unwrap();
+        let argvs: Vec<&str> = events
+            .iter()
+            .flat_map(|(_, fields)| fields)
+            .filter(|(field, _)| field == "argv")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(argvs.len(), 3);
+        for argv in argvs {
+            assert!(argv.contains("<redacted>"), "{argv}");
+            assert!(!argv.contains("secret"), "{argv}");
+        }
+    }
+
+    #[test]
+    fn run_failure() {
+        let runner = MockRunner::failing("false", 1);
+
+        let result = run(&runner, Command::new("false"));
+        match result {
+            Err(Error::SubprocessFailed { command, status }) => {
+                assert_eq!(command, "false");
+                assert_eq!(status.code(), Some(1));
+            }
+            _ => panic!("expected the command to fail"),
+        }
+    }
+
+    #[test]
+    fn system_runner_captures_output() {
+        let mut command = Command::new("echo");
+        command.arg("hello");
+
+        let output = run(&SystemRunner, command).unwrap();
+        assert_eq!(output.stdout, b"hello\n");
+    }
+
+    #[test]
+    fn system_runner_passes_stdin() {
+        let output =
+            run_with_stdin(&SystemRunner, Command::new("cat"), b"user:\n")
+                .unwrap();
+        assert_eq!(output.stdout, b"user:\n");
+    }
+
+    #[test]
+    fn system_runner_kills_on_timeout() {
+        let mut command = Command::new("sleep");
+        command.arg("10");
+
+        let start = Instant::now();
+        let result = run_with_timeout(
+            &SystemRunner,
+            command,
+            Duration::from_millis(100),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::SubprocessTimeout { command, .. }) if command == "sleep"
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let mut command = Command::new("echo");
+        command.arg("hello");
+        let output =
+            run_with_timeout(&SystemRunner, command, Duration::from_secs(5))
+                .unwrap();
+        assert_eq!(output.stdout, b"hello\n");
+    }
+
+    #[test]
+    fn stdin_recorded() {
+        let runner = MockRunner::failing("chpasswd", 1);
+        let mut command = Command::new("chpasswd");
+        command.arg("-e");
+
+        let result = run_with_stdin(&runner, command, b"azureuser:\n");
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+        assert_eq!(runner.calls(), vec![vec!["chpasswd", "-e"]]);
+        assert_eq!(runner.stdin(), vec![b"azureuser:\n".to_vec()]);
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Restoring the SELinux contexts of files azure-init writes.
+//!
+//! Files created by azure-init may not get the context the policy expects,
+//! which on an enforcing system can stop sshd reading `authorized_keys`.
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::Error;
+use crate::runner::{run, CommandRunner};
+
+/// The selinuxfs mount point, which only exists when SELinux is enabled.
+pub const PATH_SELINUX_FS: &str = "/sys/fs/selinux";
+
+/// Whether SELinux is enabled on this system.
+pub fn is_enabled() -> bool {
+    enabled_at(Path::new(PATH_SELINUX_FS))
+}
+
+fn enabled_at(selinux_fs: &Path) -> bool {
+    selinux_fs.is_dir()
+}
+
+/// Reset the SELinux context of each of `paths`, and everything beneath
+/// them, to the policy default.
+pub fn restore_contexts(
+    runner: &dyn CommandRunner,
+    paths: &[&Path],
+) -> Result<(), Error> {
+    let path_restorecon = env!("PATH_RESTORECON");
+
+    let mut command = Command::new(path_restorecon);
+    command.arg("-R").args(paths);
+    run(runner, command)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::MockRunner;
+
+    #[test]
+    fn detection() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(enabled_at(dir.path()));
+        assert!(!enabled_at(&dir.path().join("selinux")));
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(!enabled_at(&file));
+    }
+
+    #[test]
+    fn restorecon_argv() {
+        let runner = MockRunner::succeeding();
+        restore_contexts(
+            &runner,
+            &[
+                Path::new("/home/azureuser/.ssh"),
+                Path::new("/etc/hostname"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            vec![vec![
+                "restorecon",
+                "-R",
+                "/home/azureuser/.ssh",
+                "/etc/hostname"
+            ]]
+        );
+    }
+
+    #[test]
+    fn restorecon_failure() {
+        let runner = MockRunner::failing("restorecon", 1);
+        let result = restore_contexts(&runner, &[Path::new("/etc/hostname")]);
+        assert!(matches!(result, Err(Error::SubprocessFailed { .. })));
+    }
+}
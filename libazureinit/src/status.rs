@@ -0,0 +1,167 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Identifying the VM, so that a reboot can be told apart from a new VM
+//! created from the same disk.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::runner::{run, CommandRunner, SystemRunner};
+
+/// The SMBIOS system UUID, from which the VM ID is derived.
+pub const PATH_PRODUCT_UUID: &str = "/sys/devices/virtual/dmi/id/product_uuid";
+
+/// Only present on UEFI (Generation 2) VMs.
+pub const PATH_EFI: &str = "/sys/firmware/efi";
+
+/// The ID Azure assigned to this VM, or `None` if it can't be read.
+///
+/// The system UUID is read from sysfs, or from `dmidecode` where sysfs
+/// can't be read. Generation 1 VMs report the first three fields of the
+/// UUID in little-endian byte order, so they are swapped to match the ID
+/// Azure shows.
+pub fn get_vm_id() -> Option<String> {
+    private_get_vm_id(
+        &SystemRunner,
+        Path::new(PATH_PRODUCT_UUID),
+        Path::new(PATH_EFI),
+    )
+}
+
+fn private_get_vm_id(
+    runner: &dyn CommandRunner,
+    product_uuid: &Path,
+    efi: &Path,
+) -> Option<String> {
+    let uuid = match fs::read_to_string(product_uuid) {
+        Ok(uuid) => uuid,
+        Err(error) => {
+            tracing::warn!(
+                ?error,
+                path = %product_uuid.display(),
+                "Unable to read the system UUID, trying dmidecode"
+            );
+            dmidecode_uuid(runner)?
+        }
+    };
+    let uuid = uuid.trim().to_lowercase();
+
+    let fields: Vec<&str> = uuid.split('-').collect();
+    let valid = fields.len() == 5
+        && fields.iter().zip([8, 4, 4, 4, 12]).all(|(field, len)| {
+            field.len() == len && field.chars().all(|c| c.is_ascii_hexdigit())
+        });
+    if !valid {
+        tracing::warn!(%uuid, "The system UUID is malformed");
+        return None;
+    }
+
+    if efi.is_dir() {
+        return Some(uuid);
+    }
+
+    let mut swapped: Vec<String> =
+        fields[..3].iter().map(|field| swap_bytes(field)).collect();
+    swapped.extend(fields[3..].iter().map(|field| field.to_string()));
+    Some(swapped.join("-"))
+}
+
+fn dmidecode_uuid(runner: &dyn CommandRunner) -> Option<String> {
+    let path_dmidecode = env!("PATH_DMIDECODE");
+
+    let mut command = Command::new(path_dmidecode);
+    command.arg("-s").arg("system-uuid");
+    match run(runner, command) {
+        Ok(output) => {
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Err(error) => {
+            tracing::warn!(?error, "Unable to read the system UUID");
+            None
+        }
+    }
+}
+
+/// Reverse the order of the bytes in a hex string.
+fn swap_bytes(field: &str) -> String {
+    field
+        .as_bytes()
+        .chunks(2)
+        .rev()
+        .map(|byte| std::str::from_utf8(byte).expect("hex digits are ASCII"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::{output, MockRunner};
+
+    const UUID: &str = "12345678-9abc-def0-1234-56789abcdef0";
+
+    #[test]
+    fn gen2_vm_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let product_uuid = dir.path().join("product_uuid");
+        fs::write(&product_uuid, format!("{}\n", UUID.to_uppercase())).unwrap();
+        let efi = dir.path().join("efi");
+        fs::create_dir(&efi).unwrap();
+
+        assert_eq!(
+            private_get_vm_id(&MockRunner::succeeding(), &product_uuid, &efi)
+                .as_deref(),
+            Some(UUID)
+        );
+    }
+
+    #[test]
+    fn gen1_vm_id_byte_swapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let product_uuid = dir.path().join("product_uuid");
+        fs::write(&product_uuid, UUID).unwrap();
+
+        assert_eq!(
+            private_get_vm_id(
+                &MockRunner::succeeding(),
+                &product_uuid,
+                &dir.path().join("efi")
+            )
+            .as_deref(),
+            Some("78563412-bc9a-f0de-1234-56789abcdef0")
+        );
+    }
+
+    #[test]
+    fn vm_id_unavailable() {
+        let dir = tempfile::tempdir().unwrap();
+        let product_uuid = dir.path().join("product_uuid");
+        let efi = dir.path().join("efi");
+        let runner = MockRunner::failing("dmidecode", 1);
+        assert_eq!(private_get_vm_id(&runner, &product_uuid, &efi), None);
+
+        fs::write(&product_uuid, "not-a-uuid").unwrap();
+        assert_eq!(private_get_vm_id(&runner, &product_uuid, &efi), None);
+    }
+
+    #[test]
+    fn dmidecode_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = MockRunner::new(|_| Ok(output(0, &format!("{UUID}\n"))));
+
+        let vm_id = private_get_vm_id(
+            &runner,
+            &dir.path().join("product_uuid"),
+            &dir.path().join("efi"),
+        );
+        assert_eq!(
+            vm_id.as_deref(),
+            Some("78563412-bc9a-f0de-1234-56789abcdef0")
+        );
+        assert_eq!(
+            runner.calls(),
+            vec![vec!["dmidecode", "-s", "system-uuid"]]
+        );
+    }
+}
@@ -3,49 +3,287 @@
 
 use std::fs;
 use std::fs::create_dir;
-use std::fs::File;
+use std::fs::Permissions;
 use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 
-use nix::unistd::{Gid, Uid};
-use std::ffi::CString;
-use std::os::unix::fs::PermissionsExt;
+use nix::unistd::{access, AccessFlags};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use tempfile::NamedTempFile;
 
 use crate::error::Error;
 use crate::imds::PublicKeys;
 
+/// A user account to provision.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub(crate) name: String,
+    pub(crate) shell: Option<PathBuf>,
+    pub(crate) groups: Vec<String>,
+    pub(crate) home: Option<PathBuf>,
+    pub(crate) create_home: bool,
+    pub(crate) system: bool,
+    pub(crate) admin: bool,
+}
+
+impl User {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            shell: None,
+            groups: vec![],
+            home: None,
+            create_home: true,
+            system: false,
+            admin: true,
+        }
+    }
+
+    /// Set the login shell, rather than using the system's default.
+    pub fn with_shell(self, shell: impl Into<PathBuf>) -> Self {
+        Self {
+            shell: Some(shell.into()),
+            ..self
+        }
+    }
+
+    /// Set the supplementary groups the user should be a member of.
+    pub fn with_groups(
+        self,
+        groups: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            groups: groups.into_iter().map(Into::into).collect(),
+            ..self
+        }
+    }
+
+    /// Set the home directory, rather than `/home/<name>`.
+    pub fn with_home(self, home: impl Into<PathBuf>) -> Self {
+        Self {
+            home: Some(home.into()),
+            ..self
+        }
+    }
+
+    /// Whether the home directory is created along with the user, which it
+    /// is by default.
+    pub fn with_create_home(self, create_home: bool) -> Self {
+        Self {
+            create_home,
+            ..self
+        }
+    }
+
+    /// Whether the user is created as a system account, with a UID in the
+    /// system range and no password aging.
+    pub fn with_system(self, system: bool) -> Self {
+        Self { system, ..self }
+    }
+
+    /// Whether this is the VM's admin user, which is created in the
+    /// [`ADMIN_GROUPS`](crate::distro::ADMIN_GROUPS) as well as its own. It
+    /// is by default; other users get only their own groups.
+    pub fn with_admin(self, admin: bool) -> Self {
+        Self { admin, ..self }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn shell(&self) -> Option<&Path> {
+        self.shell.as_deref()
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    /// The home directory: `/root` for root and `/home/<name>` for everyone
+    /// else, unless set explicitly.
+    pub fn home(&self) -> PathBuf {
+        match &self.home {
+            Some(home) => home.clone(),
+            None if self.is_root() => PathBuf::from("/root"),
+            None => Path::new("/home").join(&self.name),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.name == "root"
+    }
+
+    /// Where the user's authorized keys are written.
+    ///
+    /// For root, `root_keys_path` overrides the file in the home directory.
+    pub fn authorized_keys_path(
+        &self,
+        root_keys_path: Option<&Path>,
+    ) -> PathBuf {
+        match root_keys_path {
+            Some(path) if self.is_root() => path.to_path_buf(),
+            _ => self.home().join(".ssh").join("authorized_keys"),
+        }
+    }
+
+    /// The file at `path`, relative to the home directory, rejecting a path
+    /// which escapes the home directory.
+    ///
+    /// Besides absolute paths and `..` components climbing out of the home
+    /// directory, a path whose existing directories resolve outside of it,
+    /// e.g. through a symlink, is rejected.
+    pub fn keys_path_in_home(&self, path: &Path) -> Result<PathBuf, Error> {
+        let outside = || Error::KeysPathOutsideHome {
+            path: path.to_path_buf(),
+        };
+        let mut relative = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err(outside());
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(outside())
+                }
+            }
+        }
+        if relative.as_os_str().is_empty() {
+            return Err(outside());
+        }
+
+        let home = self.home();
+        let keys_path = home.join(relative);
+        let dir = keys_path.parent().expect("the path has a file name");
+        if let (Ok(home), Ok(dir)) = (home.canonicalize(), dir.canonicalize()) {
+            if !dir.starts_with(home) {
+                return Err(outside());
+            }
+        }
+
+        Ok(keys_path)
+    }
+
+    pub fn create_home(&self) -> bool {
+        self.create_home
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.system
+    }
+
+    /// Check the login shell is usable.
+    ///
+    /// A relative path is an error; a shell that doesn't exist yet is only
+    /// warned about since it may be installed later.
+    pub(crate) fn validate_shell(&self) -> Result<(), Error> {
+        if let Some(shell) = &self.shell {
+            if !shell.is_absolute() {
+                return Err(Error::InvalidShell {
+                    shell: shell.clone(),
+                });
+            }
+            if !shell.exists() {
+                tracing::warn!(
+                    user = self.name,
+                    shell = %shell.display(),
+                    "Login shell does not exist"
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub async fn set_ssh_keys(
     keys: Vec<PublicKeys>,
     username: String,
     file_path: String,
 ) -> Result<(), Error> {
-    let mut authorized_keys_path = file_path;
-    authorized_keys_path.push_str("/authorized_keys");
+    let authorized_keys_path = PathBuf::from(file_path).join("authorized_keys");
 
-    let mut authorized_keys = File::create(authorized_keys_path.clone())?;
-    for key in keys {
-        writeln!(authorized_keys, "{}", key.key_data)?;
+    write_authorized_keys(keys, &username, &authorized_keys_path)
+}
+
+/// The mode of the files written for the user, e.g. `authorized_keys`.
+pub const DEFAULT_FILE_MODE: u32 = 0o600;
+
+/// The mode of the directories created for the user, e.g. `.ssh`.
+pub const DEFAULT_DIR_MODE: u32 = 0o700;
+
+/// How often [`wait_for_home`] checks the home directory.
+pub const HOME_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wait up to `timeout` for `home` to be a writable directory, checking
+/// every `interval`, e.g. for a home on a network filesystem which is mounted
+/// after azure-init starts.
+///
+/// Keys written before the filesystem is mounted would be hidden by it.
+pub async fn wait_for_home(
+    home: &Path,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<(), Error> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if home.is_dir() && access(home, AccessFlags::W_OK).is_ok() {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() + interval > deadline {
+            return Err(Error::HomeNotReady {
+                home: home.to_path_buf(),
+                timeout,
+            });
+        }
+        tracing::info!(
+            home = %home.display(),
+            "Home directory is not writable yet, waiting"
+        );
+        tokio::time::sleep(interval).await;
     }
-    let metadata = fs::metadata(authorized_keys_path.clone())?;
-    let permissions = metadata.permissions();
-    let mut new_permissions = permissions.clone();
-    new_permissions.set_mode(0o600);
-    fs::set_permissions(authorized_keys_path.clone(), new_permissions)?;
+}
 
-    let uid_username = CString::new(username.clone())?;
-    let uid_passwd = unsafe { libc::getpwnam(uid_username.as_ptr()) };
-    let uid = unsafe { (*uid_passwd).pw_uid };
-    let new_uid = Uid::from_raw(uid);
+/// Write `keys` to the authorized keys file at `path`, owned by `username`
+/// and only accessible to them.
+pub fn write_authorized_keys(
+    keys: Vec<PublicKeys>,
+    username: &str,
+    path: &Path,
+) -> Result<(), Error> {
+    write_authorized_keys_with_mode(keys, username, path, DEFAULT_FILE_MODE)
+}
 
-    let gid_groupname = CString::new(username.clone())?;
-    let gid_group = unsafe { libc::getgrnam(gid_groupname.as_ptr()) };
-    let gid = unsafe { (*gid_group).gr_gid };
-    let new_gid = Gid::from_raw(gid);
+/// Like [`write_authorized_keys`], with the file's mode set to `mode`.
+pub fn write_authorized_keys_with_mode(
+    keys: Vec<PublicKeys>,
+    username: &str,
+    path: &Path,
+    mode: u32,
+) -> Result<(), Error> {
+    let ssh_dir = path.parent().unwrap_or(Path::new("/"));
 
-    let _set_ownership = nix::unistd::chown(
-        authorized_keys_path.as_str(),
-        Some(new_uid),
-        Some(new_gid),
-    );
+    let user =
+        nix::unistd::User::from_name(username)?.ok_or(Error::UserMissing {
+            user: username.to_string(),
+        })?;
+
+    // Write to a temporary file in the same directory and rename it into
+    // place, so authorized_keys is always either the old or the new file and
+    // never a partially written one.
+    let mut authorized_keys = NamedTempFile::new_in(ssh_dir)?;
+    for key in keys {
+        writeln!(authorized_keys, "{}", key.key_data)?;
+    }
+    authorized_keys.as_file().sync_all()?;
+    fs::set_permissions(authorized_keys.path(), Permissions::from_mode(mode))?;
+    nix::unistd::chown(authorized_keys.path(), Some(user.uid), Some(user.gid))?;
+    authorized_keys.persist(path).map_err(|e| e.error)?;
 
     Ok(())
 }
@@ -57,27 +295,70 @@ pub async fn create_ssh_directory(
     let mut file_path = home_path.to_owned();
     file_path.push_str("/.ssh");
 
-    create_dir(file_path.clone())?;
+    create_ssh_directory_at(username, Path::new(&file_path))
+}
+
+/// Create `path`, if it doesn't already exist, owned by `username` and only
+/// accessible to them.
+pub fn create_ssh_directory_at(
+    username: &str,
+    path: &Path,
+) -> Result<(), Error> {
+    create_ssh_directory_with_mode(username, path, DEFAULT_DIR_MODE)
+}
+
+/// Like [`create_ssh_directory_at`], with the directory's mode set to
+/// `mode`.
+///
+/// An existing `path` is only reused if it is a real directory: a symlink,
+/// e.g. one the user left in their home to a system directory, is refused
+/// rather than followed.
+pub fn create_ssh_directory_with_mode(
+    username: &str,
+    path: &Path,
+    mode: u32,
+) -> Result<(), Error> {
+    match create_dir(path) {
+        Ok(()) => {}
+        // e.g. root's, which images often ship.
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e.into()),
+    }
 
     let user =
         nix::unistd::User::from_name(username)?.ok_or(Error::UserMissing {
             user: username.to_string(),
         })?;
-    nix::unistd::chown(file_path.as_str(), Some(user.uid), Some(user.gid))?;
+    // Change the directory through a descriptor, so the checks made opening
+    // it still hold.
+    let dir = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)?;
+    std::os::unix::fs::fchown(
+        &dir,
+        Some(user.uid.as_raw()),
+        Some(user.gid.as_raw()),
+    )?;
 
-    let metadata = fs::metadata(&file_path)?;
-    let permissions = metadata.permissions();
-    let mut new_permissions = permissions.clone();
-    new_permissions.set_mode(0o700);
-    fs::set_permissions(&file_path, new_permissions)?;
+    dir.set_permissions(Permissions::from_mode(mode))?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
 
-    use super::create_ssh_directory;
+    use super::{
+        create_ssh_directory, create_ssh_directory_at,
+        create_ssh_directory_with_mode, set_ssh_keys, wait_for_home,
+        write_authorized_keys, write_authorized_keys_with_mode, User,
+    };
+    use crate::error::Error;
+    use crate::imds::PublicKeys;
 
     #[tokio::test]
     #[should_panic]
@@ -92,4 +373,259 @@ mod tests {
         .await
         .unwrap();
     }
+
+    #[tokio::test]
+    async fn ssh_keys_written_atomically() {
+        let ssh_dir = tempfile::tempdir().unwrap();
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let authorized_keys = ssh_dir.path().join("authorized_keys");
+        fs::write(&authorized_keys, "ssh-rsa old_key\n").unwrap();
+
+        let keys = vec![
+            PublicKeys {
+                key_data: "ssh-rsa test_key_1".to_string(),
+                path: "/path/to/.ssh/authorized_keys".to_string(),
+            },
+            PublicKeys {
+                key_data: "ssh-rsa test_key_2".to_string(),
+                path: "/path/to/.ssh/authorized_keys".to_string(),
+            },
+        ];
+        set_ssh_keys(
+            keys,
+            user.name.clone(),
+            ssh_dir.path().to_str().unwrap().to_string(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&authorized_keys).unwrap(),
+            "ssh-rsa test_key_1\nssh-rsa test_key_2\n"
+        );
+        let metadata = fs::metadata(&authorized_keys).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(metadata.uid(), user.uid.as_raw());
+        assert_eq!(metadata.gid(), user.gid.as_raw());
+
+        // Only the final file should remain; the temporary file is renamed.
+        assert_eq!(fs::read_dir(ssh_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn root_paths() {
+        let root = User::new("root");
+        assert!(root.is_root());
+        assert_eq!(root.home(), std::path::Path::new("/root"));
+        assert_eq!(
+            root.authorized_keys_path(None),
+            std::path::Path::new("/root/.ssh/authorized_keys")
+        );
+        let custom = std::path::Path::new("/etc/ssh/keys/root");
+        assert_eq!(root.authorized_keys_path(Some(custom)), custom);
+
+        // The override only applies to root.
+        let user = User::new("azureuser");
+        assert_eq!(
+            user.authorized_keys_path(Some(custom)),
+            std::path::Path::new("/home/azureuser/.ssh/authorized_keys")
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_ssh_directory_fixed_up() {
+        let home = tempfile::tempdir().unwrap();
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let ssh_dir = home.path().join(".ssh");
+        fs::create_dir(&ssh_dir).unwrap();
+        fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        create_ssh_directory_at(&user.name, &ssh_dir).unwrap();
+        let metadata = fs::metadata(&ssh_dir).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        assert_eq!(metadata.uid(), user.uid.as_raw());
+
+        let path = ssh_dir.join("keys");
+        let keys = vec![PublicKeys {
+            key_data: "ssh-rsa test_key".to_string(),
+            path: path.to_string_lossy().into_owned(),
+        }];
+        write_authorized_keys(keys, &user.name, &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ssh-rsa test_key\n");
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn configured_modes_applied() {
+        let home = tempfile::tempdir().unwrap();
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let ssh_dir = home.path().join(".ssh");
+
+        create_ssh_directory_with_mode(&user.name, &ssh_dir, 0o500).unwrap();
+        let metadata = fs::metadata(&ssh_dir).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o500);
+
+        // Let the test write into the directory it just locked down.
+        fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(0o700))
+            .unwrap();
+        let path = ssh_dir.join("authorized_keys");
+        let keys = vec![PublicKeys {
+            key_data: "ssh-rsa test_key".to_string(),
+            path: path.to_string_lossy().into_owned(),
+        }];
+        write_authorized_keys_with_mode(keys, &user.name, &path, 0o400)
+            .unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o400);
+    }
+
+    #[test]
+    fn ssh_directory_symlink_refused() {
+        let home = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        fs::set_permissions(target.path(), fs::Permissions::from_mode(0o755))
+            .unwrap();
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let ssh_dir = home.path().join(".ssh");
+        std::os::unix::fs::symlink(target.path(), &ssh_dir).unwrap();
+
+        let result =
+            create_ssh_directory_with_mode(&user.name, &ssh_dir, 0o700);
+        assert!(matches!(result, Err(Error::Io(_))));
+        // The target was left alone.
+        let metadata = fs::metadata(target.path()).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+
+        // So is a file in its place.
+        let file = home.path().join("file");
+        fs::write(&file, "").unwrap();
+        let result = create_ssh_directory_with_mode(&user.name, &file, 0o700);
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn keys_path_in_home() {
+        let home = tempfile::tempdir().unwrap();
+        let user = User::new("azureuser").with_home(home.path());
+
+        assert_eq!(
+            user.keys_path_in_home(Path::new("./.ssh/../keys/authorized_keys"))
+                .unwrap(),
+            home.path().join("keys/authorized_keys")
+        );
+        for path in ["../../etc/passwd", ".ssh/../../x", "/etc/passwd", "."] {
+            assert!(
+                matches!(
+                    user.keys_path_in_home(Path::new(path)),
+                    Err(Error::KeysPathOutsideHome { .. })
+                ),
+                "{path}"
+            );
+        }
+
+        // A symlinked directory leading out of the home is caught too.
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), home.path().join(".ssh"))
+            .unwrap();
+        assert!(matches!(
+            user.keys_path_in_home(Path::new(".ssh/authorized_keys")),
+            Err(Error::KeysPathOutsideHome { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn home_ready_after_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("azureuser");
+        let mount = {
+            let home = home.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                fs::create_dir(&home).unwrap();
+            })
+        };
+
+        wait_for_home(
+            &home,
+            std::time::Duration::from_secs(5),
+            std::time::Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+        mount.await.unwrap();
+        assert!(home.is_dir());
+    }
+
+    #[tokio::test]
+    async fn home_never_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("azureuser");
+
+        let error = wait_for_home(
+            &home,
+            std::time::Duration::from_millis(50),
+            std::time::Duration::from_millis(10),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, Error::HomeNotReady { .. }));
+
+        // A file where the home directory should be isn't ready either.
+        fs::write(&home, "").unwrap();
+        let result = wait_for_home(
+            &home,
+            std::time::Duration::from_millis(20),
+            std::time::Duration::from_millis(10),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn user_builder() {
+        let user = User::new("azureuser");
+        assert_eq!(user.name(), "azureuser");
+        assert_eq!(user.shell(), None);
+
+        let user = user.with_shell("/bin/bash");
+        assert_eq!(user.shell(), Some(std::path::Path::new("/bin/bash")));
+        assert!(user.validate_shell().is_ok());
+
+        assert_eq!(user.home(), std::path::Path::new("/home/azureuser"));
+        assert!(user.create_home());
+        let user = user.with_home("/opt/app").with_create_home(false);
+        assert_eq!(user.home(), std::path::Path::new("/opt/app"));
+        assert!(!user.create_home());
+
+        assert!(!user.is_system());
+        let user = user.with_system(true);
+        assert!(user.is_system());
+
+        let user = user.with_groups(["wheel", "docker"]);
+        assert_eq!(user.groups(), ["wheel", "docker"]);
+        assert_eq!(user.shell(), Some(std::path::Path::new("/bin/bash")));
+    }
+
+    #[test]
+    fn shell_validation() {
+        let user = User::new("azureuser").with_shell("bash");
+        assert!(matches!(
+            user.validate_shell(),
+            Err(Error::InvalidShell { .. })
+        ));
+
+        // A missing shell is only warned about.
+        let user = User::new("azureuser").with_shell("/no/such/shell");
+        assert!(user.validate_shell().is_ok());
+    }
 }
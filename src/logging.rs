@@ -0,0 +1,647 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+
+use regex::Regex;
+
+use tracing::Subscriber;
+use tracing_subscriber::filter::{filter_fn, FilterExt};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// Environment variable holding the log filter, in `EnvFilter` syntax.
+pub const LOG_ENV: &str = "AZURE_INIT_LOG";
+
+/// Values of keys which look secret, always redacted. Only the `value` group
+/// of a pattern is redacted, or the whole match if it has none.
+///
+/// A quoted value is redacted up to its closing quote, spaces and all, and
+/// so is one wrapped in `Some(..)`, as an `Option` field is in `Debug`
+/// output. An unquoted value ends at whitespace or punctuation.
+const DEFAULT_REDACT_PATTERNS: &[&str] = &[
+    concat!(
+        r#"(?i)(password|passwd|secret|token)["']?\s*[=:]\s*(Some\()?"#,
+        r#""(?P<value>(?:[^"\\]|\\.)*)""#
+    ),
+    concat!(
+        r#"(?i)(password|passwd|secret|token)["']?\s*[=:]\s*(Some\()?"#,
+        r#"'(?P<value>(?:[^'\\]|\\.)*)'"#
+    ),
+    // The value must end where it seems to, so `Some` isn't taken for one.
+    concat!(
+        r#"(?i)(password|passwd|secret|token)["']?\s*[=:]\s*"#,
+        r#"(?P<value>[^\s"',}()]+)([\s"',})]|$)"#
+    ),
+];
+
+/// What replaces redacted text.
+const REDACTED: &str = "***";
+
+/// The escape sequences stderr is coloured with.
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new("\x1b\\[[0-9;]*m").expect("the pattern is valid")
+});
+
+/// Handles to the parts of logging which are set up once the configuration
+/// is loaded.
+#[derive(Clone, Debug)]
+pub struct Logging {
+    log_file: LogFile,
+    redactor: Redactor,
+}
+
+impl Logging {
+    /// Start appending the log to `path`.
+    ///
+    /// If the file is already larger than `max_bytes`, it is first rotated to
+    /// `<path>.1`, keeping at most `max_files` old logs; zero disables
+    /// rotation.
+    pub fn open_file(
+        &self,
+        path: &Path,
+        max_bytes: u64,
+        max_files: u32,
+    ) -> Result<(), anyhow::Error> {
+        if max_bytes > 0 {
+            // An oversized log is better than none, so open it regardless.
+            if let Err(error) = rotate(path, max_bytes, max_files) {
+                tracing::warn!(
+                    ?error,
+                    path = %path.display(),
+                    "Unable to rotate the log file"
+                );
+            }
+        }
+        self.log_file.open(path)
+    }
+
+    /// Redact matches of `patterns` from the log, as well as the defaults.
+    pub fn set_redact_patterns(
+        &self,
+        patterns: &[String],
+    ) -> Result<(), regex::Error> {
+        self.redactor.set_patterns(patterns)
+    }
+}
+
+/// The bytes available to unprivileged users on the filesystem holding
+/// `path`.
+pub fn available_bytes(path: &Path) -> nix::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Whether the filesystem the log at `path` would be written to has at least
+/// `min_free_bytes` available, as `available` reports; zero disables the
+/// check.
+///
+/// A log filling the disk can wedge the system, so a warning is logged when
+/// there's too little space. If the space can't be checked, the log is
+/// written anyway.
+pub fn has_free_space(
+    path: &Path,
+    min_free_bytes: u64,
+    available: impl Fn(&Path) -> nix::Result<u64>,
+) -> bool {
+    if min_free_bytes == 0 {
+        return true;
+    }
+    // The log may not exist yet, but its directory must.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match available(dir) {
+        Ok(bytes) if bytes < min_free_bytes => {
+            tracing::warn!(
+                path = %path.display(),
+                available = bytes,
+                min_free_bytes,
+                "Too little free space, not writing the log file"
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(error) => {
+            tracing::warn!(
+                ?error,
+                path = %path.display(),
+                "Unable to check the free space for the log file"
+            );
+            true
+        }
+    }
+}
+
+/// Rotate the log at `path` if it's larger than `max_bytes`: `<path>.1`
+/// becomes `<path>.2` and so on up to `max_files`, dropping the oldest, and
+/// `path` becomes `<path>.1`.
+///
+/// Returns whether the log was rotated.
+fn rotate(
+    path: &Path,
+    max_bytes: u64,
+    max_files: u32,
+) -> std::io::Result<bool> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() > max_bytes => {}
+        Ok(_) => return Ok(false),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(false)
+        }
+        Err(error) => return Err(error),
+    }
+
+    if max_files == 0 {
+        std::fs::remove_file(path)?;
+        return Ok(true);
+    }
+    let rotated = |n: u32| {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(format!(".{n}"));
+        PathBuf::from(rotated)
+    };
+    for n in (1..max_files).rev() {
+        match std::fs::rename(rotated(n), rotated(n + 1)) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(error)
+            }
+            _ => {}
+        }
+    }
+    std::fs::rename(path, rotated(1))?;
+
+    Ok(true)
+}
+
+/// Replaces secrets in log lines before they are written.
+#[derive(Clone, Debug)]
+struct Redactor(Arc<RwLock<Vec<Regex>>>);
+
+impl Default for Redactor {
+    fn default() -> Self {
+        let redactor = Self(Arc::default());
+        redactor
+            .set_patterns(&[])
+            .expect("default patterns are valid");
+        redactor
+    }
+}
+
+impl Redactor {
+    fn set_patterns(&self, patterns: &[String]) -> Result<(), regex::Error> {
+        let regexes = DEFAULT_REDACT_PATTERNS
+            .iter()
+            .copied()
+            .chain(patterns.iter().map(String::as_str))
+            .map(Regex::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        *self.0.write().unwrap() = regexes;
+
+        Ok(())
+    }
+
+    /// Redact secrets from `text`.
+    ///
+    /// Colour codes can split a key from its value, e.g. a field's name from
+    /// its `=`, hiding the secret from the patterns, so a coloured line is
+    /// matched without them too. If that finds a secret, the line is written
+    /// without colour.
+    fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let redacted = self.redact_plain(text);
+        let plain = ANSI_ESCAPE.replace_all(&redacted, "").into_owned();
+        if plain.len() == redacted.len() {
+            return redacted;
+        }
+        match self.redact_plain(&plain) {
+            Cow::Owned(plain) => Cow::Owned(plain),
+            Cow::Borrowed(_) => redacted,
+        }
+    }
+
+    fn redact_plain<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for regex in self.0.read().unwrap().iter() {
+            if !regex.is_match(&text) {
+                continue;
+            }
+            let redacted =
+                regex.replace_all(&text, |caps: &regex::Captures| {
+                    let whole = caps.get(0).expect("group 0 always matches");
+                    match caps.name("value") {
+                        Some(value) => {
+                            let start = value.start() - whole.start();
+                            let end = value.end() - whole.start();
+                            let whole = whole.as_str();
+                            format!(
+                                "{}{REDACTED}{}",
+                                &whole[..start],
+                                &whole[end..]
+                            )
+                        }
+                        None => REDACTED.to_string(),
+                    }
+                });
+            text = Cow::Owned(redacted.into_owned());
+        }
+
+        text
+    }
+}
+
+/// Writes to `inner` with secrets redacted.
+///
+/// The formatter writes each event in one call, so a secret is never split
+/// across writes.
+struct Redacting<W> {
+    inner: W,
+    redactor: Redactor,
+}
+
+impl<W: Write> Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.inner
+            .write_all(self.redactor.redact(&text).as_bytes())?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The log file, which is only known once the configuration is loaded.
+///
+/// Nothing is written until it is opened.
+#[derive(Clone, Debug, Default)]
+struct LogFile(Arc<Mutex<Option<File>>>);
+
+impl LogFile {
+    fn open(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        *self.0.lock().unwrap() = Some(file);
+
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(file) => file.write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Install the global subscriber, which logs to stderr, returning the
+/// handle to finish setting up logging with once the configuration is
+/// loaded.
+///
+/// Only errors are logged to stderr unless `verbosity` raises the level, and
+/// the file gets the info level; the filter in [`LOG_ENV`], when set, takes
+/// precedence for both. Nothing at all is logged to stderr if `quiet` is set,
+/// whatever the filter.
+pub fn setup_layers(verbosity: u8, quiet: bool) -> Logging {
+    let env = std::env::var(LOG_ENV).ok();
+    let (subscriber, logging) = subscriber(verbosity, quiet, env.as_deref());
+    subscriber.init();
+
+    logging
+}
+
+/// The subscriber [`setup_layers`] installs, for use without installing it.
+pub fn subscriber(
+    verbosity: u8,
+    quiet: bool,
+    env: Option<&str>,
+) -> (impl Subscriber + Send + Sync, Logging) {
+    subscriber_with_stderr(std::io::stderr, verbosity, quiet, env)
+}
+
+fn subscriber_with_stderr<S, W>(
+    stderr: S,
+    verbosity: u8,
+    quiet: bool,
+    env: Option<&str>,
+) -> (impl Subscriber + Send + Sync, Logging)
+where
+    S: Fn() -> W + Send + Sync + 'static,
+    W: Write + 'static,
+{
+    let log_file = LogFile::default();
+    let redactor = Redactor::default();
+    let file_writer = Redacting {
+        inner: log_file.clone(),
+        redactor: redactor.clone(),
+    };
+    let stderr_redactor = redactor.clone();
+    let opened = log_file.clone();
+    let file_filter = EnvFilter::new(env.unwrap_or("info"))
+        .and(filter_fn(move |_| opened.is_open()));
+
+    let subscriber = tracing_subscriber::registry()
+        .with(
+            fmt::layer()
+                .with_writer(move || Redacting {
+                    inner: stderr(),
+                    redactor: stderr_redactor.clone(),
+                })
+                .with_filter(stderr_filter(verbosity, quiet, env)),
+        )
+        .with(
+            fmt::layer()
+                .with_writer(move || Redacting {
+                    inner: file_writer.inner.clone(),
+                    redactor: file_writer.redactor.clone(),
+                })
+                .with_ansi(false)
+                .with_filter(file_filter),
+        );
+
+    (subscriber, Logging { log_file, redactor })
+}
+
+fn stderr_filter(verbosity: u8, quiet: bool, env: Option<&str>) -> EnvFilter {
+    if quiet {
+        return EnvFilter::new("off");
+    }
+    if let Some(directives) = env {
+        return EnvFilter::new(directives);
+    }
+
+    let level = match verbosity {
+        0 => "error",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    EnvFilter::new(level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_levels() {
+        for (verbosity, level) in [
+            (0, "error"),
+            (1, "info"),
+            (2, "debug"),
+            (3, "trace"),
+            (9, "trace"),
+        ] {
+            assert_eq!(
+                stderr_filter(verbosity, false, None).to_string(),
+                level
+            );
+        }
+    }
+
+    #[test]
+    fn env_overrides_verbosity() {
+        let filter = stderr_filter(2, false, Some("libazureinit=warn"));
+        assert_eq!(filter.to_string(), "libazureinit=warn");
+    }
+
+    #[test]
+    fn quiet_overrides_env() {
+        let filter = stderr_filter(0, true, Some("libazureinit=warn"));
+        assert_eq!(filter.to_string(), "off");
+    }
+
+    /// A stderr stand-in which the test can read back.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn quiet_logs_nothing_to_stderr() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init.log");
+
+        let stderr = Buffer::default();
+        let writer = stderr.clone();
+        let (subscriber, logging) = subscriber_with_stderr(
+            move || writer.clone(),
+            3,
+            true,
+            Some("trace"),
+        );
+        let _guard = tracing::subscriber::set_default(subscriber);
+        logging.open_file(&path, 0, 0).unwrap();
+
+        tracing::error!("an error");
+        tracing::info!("some information");
+
+        assert!(stderr.0.lock().unwrap().is_empty());
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("an error"));
+        assert!(contents.contains("some information"));
+    }
+
+    #[test]
+    fn errors_logged_to_stderr() {
+        let stderr = Buffer::default();
+        let writer = stderr.clone();
+        let (subscriber, _logging) =
+            subscriber_with_stderr(move || writer.clone(), 0, false, None);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        tracing::error!("an error");
+        tracing::info!("some information");
+
+        let output =
+            String::from_utf8(stderr.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("an error"));
+        assert!(!output.contains("some information"));
+    }
+
+    #[test]
+    fn secrets_redacted_on_coloured_stderr() {
+        let stderr = Buffer::default();
+        let writer = stderr.clone();
+        let (subscriber, _logging) =
+            subscriber_with_stderr(move || writer.clone(), 1, false, None);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        tracing::info!(user = "azureuser", "Creating the user");
+        tracing::info!(password = "hunter2", "Setting the password");
+
+        let output =
+            String::from_utf8(stderr.0.lock().unwrap().clone()).unwrap();
+        let (created, set) = output.split_once('\n').unwrap();
+        assert!(created.contains('\x1b'), "{output:?}");
+        assert!(!set.contains("hunter2"), "{output:?}");
+        assert!(set.contains(r#"password="***""#), "{output:?}");
+    }
+
+    #[test]
+    fn log_file_opened_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init.log");
+        let (subscriber, logging) = subscriber(0, false, None);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        tracing::info!("before the file is opened");
+        logging.open_file(&path, 0, 0).unwrap();
+        tracing::info!("after the file is opened");
+        tracing::debug!("below the file's level");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("before the file is opened"));
+        assert!(contents.contains("after the file is opened"));
+        assert!(!contents.contains("below the file's level"));
+    }
+
+    #[test]
+    fn oversized_log_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init.log");
+        let old = |n: u32| dir.path().join(format!("azure-init.log.{n}"));
+        std::fs::write(&path, "x".repeat(100)).unwrap();
+        std::fs::write(old(1), "first").unwrap();
+        std::fs::write(old(2), "second").unwrap();
+
+        let (subscriber, logging) = subscriber(0, false, None);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        logging.open_file(&path, 10, 2).unwrap();
+        tracing::info!("after rotation");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("after rotation"));
+        assert!(!contents.contains("xxx"));
+        assert_eq!(std::fs::read_to_string(old(1)).unwrap(), "x".repeat(100));
+        assert_eq!(std::fs::read_to_string(old(2)).unwrap(), "first");
+        assert!(!old(3).exists());
+    }
+
+    #[test]
+    fn log_skipped_when_space_low() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init.log");
+        let checked = Mutex::new(vec![]);
+        let available = |bytes: u64| {
+            let checked = &checked;
+            move |dir: &Path| {
+                checked.lock().unwrap().push(dir.to_path_buf());
+                Ok(bytes)
+            }
+        };
+
+        assert!(!has_free_space(&path, 4096, available(1024)));
+        assert_eq!(*checked.lock().unwrap(), vec![dir.path().to_path_buf()]);
+        assert!(has_free_space(&path, 4096, available(4096)));
+        // Disabled, the space isn't even checked.
+        assert!(has_free_space(&path, 0, available(0)));
+        assert_eq!(checked.lock().unwrap().len(), 2);
+        // Nor is the log skipped when the space can't be checked.
+        assert!(has_free_space(&path, 4096, |_| Err(nix::Error::EACCES)));
+
+        assert!(available_bytes(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn small_log_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init.log");
+        std::fs::write(&path, "earlier\n").unwrap();
+
+        assert!(!rotate(&path, 100, 5).unwrap());
+        assert!(!rotate(&dir.path().join("missing.log"), 100, 5).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "earlier\n");
+        assert!(!dir.path().join("azure-init.log.1").exists());
+
+        // Without old logs to keep, an oversized log is just removed.
+        assert!(rotate(&path, 1, 0).unwrap());
+        assert!(!path.exists());
+        assert!(!dir.path().join("azure-init.log.1").exists());
+    }
+
+    #[test]
+    fn secrets_redacted() {
+        let redactor = Redactor::default();
+        assert_eq!(
+            redactor.redact(r#"password="hunter2" user=azureuser"#),
+            r#"password="***" user=azureuser"#
+        );
+        assert_eq!(redactor.redact("Token: abc123, ok"), "Token: ***, ok");
+        assert_eq!(redactor.redact("nothing to see"), "nothing to see");
+        assert_eq!(
+            redactor
+                .redact(r#"User { password: Some("hunter2"), sudo: true }"#),
+            r#"User { password: Some("***"), sudo: true }"#
+        );
+        assert_eq!(
+            redactor.redact(r#"token="abc def" user=azureuser"#),
+            r#"token="***" user=azureuser"#
+        );
+        assert_eq!(
+            redactor.redact(r#"{"secret": "a \"quoted\" b", "ok": 1}"#),
+            r#"{"secret": "***", "ok": 1}"#
+        );
+        assert_eq!(
+            redactor.redact("passwd='x y' token=abc secret=None"),
+            "passwd='***' token=*** secret=***"
+        );
+
+        redactor
+            .set_patterns(&["sig=[0-9a-f]+".to_string()])
+            .unwrap();
+        assert_eq!(
+            redactor.redact("url?sig=deadbeef&secret=x"),
+            "url?***&secret=***"
+        );
+        assert!(redactor.set_patterns(&["(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn secrets_redacted_in_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("azure-init.log");
+        let (subscriber, logging) = subscriber(0, false, None);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        logging.open_file(&path, 0, 0).unwrap();
+        logging
+            .set_redact_patterns(&["ssh-rsa \\S+".to_string()])
+            .unwrap();
+
+        tracing::info!(password = "hunter2", "Setting the password");
+        tracing::info!("Writing key ssh-rsa AAAAB3Nza for the user");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("hunter2"), "{contents}");
+        assert!(contents.contains(r#"password="***""#), "{contents}");
+        assert!(!contents.contains("AAAAB3Nza"), "{contents}");
+        assert!(contents.contains("Writing key *** for the user"));
+    }
+}
@@ -1,129 +1,1753 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
 
-use libazureinit::imds::InstanceMetadata;
+use libazureinit::config::{self, Config};
+use libazureinit::imds::{InstanceMetadata, PublicKeys};
 use libazureinit::{
-    distro,
+    cloud_init, container, custom_data, dhcp,
     error::Error as LibError,
-    goalstate, imds, media,
+    goalstate::{self, Goalstate, Health},
+    imds, lock, machine_info, media,
     media::Environment,
-    reqwest::{header, Client},
-    user,
+    provision::{progress, Provision, ProvisionSummary},
+    reqwest::{header, Client, ClientBuilder},
+    status,
+    user::User,
 };
 
+mod logging;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-fn get_environment() -> Result<Environment, anyhow::Error> {
+/// Minimal provisioning agent for Azure.
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to a configuration file, applied on top of the system
+    /// configuration.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Path to a directory of `*.toml` drop-ins, applied in order of file
+    /// name before the file passed with `--config`.
+    #[arg(long)]
+    config_dir: Option<PathBuf>,
+
+    /// Also write the log to this file, overriding
+    /// `azure_init_log_path.path` in the configuration.
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Print the settings which differ from the built-in defaults, after
+    /// all configuration sources are merged, and exit.
+    #[arg(long)]
+    config_check: bool,
+
+    /// Print the configuration files which were loaded, in the order they
+    /// were merged, and exit.
+    #[arg(long)]
+    show_config_paths: bool,
+
+    /// Print the VM ID, as used to tell a reboot from a new VM, and exit.
+    #[arg(long)]
+    print_vm_id: bool,
+
+    /// Don't report the VM's health to the wireserver, e.g. when developing
+    /// outside Azure.
+    #[arg(long, env = "AZURE_INIT_NO_REPORT")]
+    no_report: bool,
+
+    /// Log more detail to stderr; repeat for more (-v, -vv, -vvv).
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log nothing to stderr, e.g. when run from a script. The log file, if
+    /// any, is unaffected.
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Where to read the VM's metadata from: `imds` ignores the provisioning
+    /// media's settings, `ovf` skips IMDS, and `auto` reads both, falling
+    /// back from IMDS to the media for each setting.
+    ///
+    /// With `imds`, the media is still read to find out whether the VM is
+    /// preprovisioned, and the user is always IMDS's admin user, or the
+    /// configured default; provisioning fails if there is neither.
+    #[arg(long, value_enum, default_value_t = SourceMode::Auto)]
+    metadata_source: SourceMode,
+
+    /// Override the IMDS base URL, e.g. to test against a local mock.
+    #[arg(
+        long,
+        env = "AZURE_INIT_IMDS_URL",
+        hide = true,
+        value_parser = parse_url
+    )]
+    imds_url: Option<String>,
+
+    /// Override how long to wait for a connection to IMDS, in seconds.
+    #[arg(long, value_parser = parse_timeout)]
+    imds_connect_timeout: Option<f64>,
+
+    /// Override how long to wait for each IMDS request, in seconds.
+    #[arg(long, value_parser = parse_timeout)]
+    imds_total_timeout: Option<f64>,
+
+    /// Override the wireserver base URL, e.g. to test against a local mock.
+    #[arg(
+        long,
+        env = "AZURE_INIT_WIRESERVER_URL",
+        hide = true,
+        value_parser = parse_url
+    )]
+    wireserver_url: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a JSON Schema describing the configuration file.
+    ConfigSchema,
+    /// Report the VM's health to the wireserver, e.g. after fixing a failed
+    /// provisioning by hand.
+    Health {
+        #[arg(long, value_enum)]
+        state: HealthState,
+        /// Why provisioning failed, reported with the failed state.
+        #[arg(long)]
+        reason: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SourceMode {
+    Auto,
+    Imds,
+    Ovf,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HealthState {
+    Ready,
+    Failed,
+}
+
+impl Cli {
+    /// Apply command line overrides, which take precedence over any value
+    /// from the configuration files.
+    fn apply_overrides(&self, config: &mut Config) {
+        if let Some(url) = &self.imds_url {
+            config.imds.endpoint = url.clone();
+        }
+        if let Some(url) = &self.wireserver_url {
+            config.wireserver.endpoint = url.clone();
+        }
+        if let Some(secs) = self.imds_connect_timeout {
+            config.imds.connect_timeout_secs = secs;
+        }
+        if let Some(secs) = self.imds_total_timeout {
+            config.imds.total_timeout_secs = secs;
+        }
+        if let Some(path) = &self.log_file {
+            config.azure_init_log_path.path = Some(path.clone());
+        }
+    }
+}
+
+fn parse_url(url: &str) -> Result<String, LibError> {
+    config::validate_url(url)?;
+    Ok(url.to_string())
+}
+
+fn parse_timeout(secs: &str) -> Result<f64, anyhow::Error> {
+    let secs = secs.parse()?;
+    config::validate_timeout("timeout", secs)?;
+    Ok(secs)
+}
+
+fn get_environment(config: &Config) -> Result<Environment, anyhow::Error> {
     let ovf_devices = media::get_mount_device()?;
-    let mut environment: Option<Environment> = None;
 
-    // loop until it finds a correct device.
-    for dev in ovf_devices {
-        environment = match media::mount_parse_ovf_env(dev) {
-            Ok(env) => Some(env),
-            Err(_) => continue,
+    media::first_environment(ovf_devices, |dev| {
+        media::mount_parse_environment(dev, &config.provisioning_media)
+    })
+    .ok_or_else(|| anyhow::anyhow!("Unable to get list of block devices"))
+}
+
+/// Read the OVF environment and the instance metadata from the sources
+/// `mode` allows.
+///
+/// A skipped IMDS is never awaited, so nothing is requested from it; in its
+/// place is empty metadata, which the OVF environment's settings take
+/// precedence over. The OVF environment is always read, since only it says
+/// whether the VM is preprovisioned, but in `imds` mode only its platform
+/// settings are kept.
+async fn read_metadata(
+    mode: SourceMode,
+    environment: impl Future<Output = Result<Environment, anyhow::Error>>,
+    instance_metadata: impl Future<Output = Result<InstanceMetadata, anyhow::Error>>,
+) -> Result<(Environment, InstanceMetadata), anyhow::Error> {
+    let environment = environment.await?;
+    let environment = if mode == SourceMode::Imds {
+        tracing::info!("Ignoring the provisioning media's settings");
+        Environment {
+            platform_settings_section: environment.platform_settings_section,
+            ..Default::default()
         }
+    } else {
+        environment
+    };
+    let instance_metadata = if mode == SourceMode::Ovf {
+        tracing::info!("Skipping IMDS");
+        InstanceMetadata::builder().build()
+    } else {
+        instance_metadata.await?
+    };
+
+    Ok((environment, instance_metadata))
+}
+
+/// Whether SSH password authentication should be disabled, as IMDS says, or
+/// as the OVF environment says when IMDS was skipped.
+fn password_auth_disabled(
+    mode: SourceMode,
+    instance_metadata: &InstanceMetadata,
+    environment: &Environment,
+) -> bool {
+    if mode == SourceMode::Ovf {
+        environment
+            .provisioning_section
+            .linux_prov_conf_set
+            .disable_ssh_password_authentication
+    } else {
+        instance_metadata
+            .compute
+            .os_profile
+            .disable_password_authentication
     }
+}
+
+/// Where the provisioned user's name came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MetadataSource {
+    Imds,
+    Ovf,
+    /// The configured default username.
+    Config,
+}
 
-    environment
-        .ok_or_else(|| anyhow::anyhow!("Unable to get list of block devices"))
+impl MetadataSource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetadataSource::Imds => "imds",
+            MetadataSource::Ovf => "ovf",
+            MetadataSource::Config => "config",
+        }
+    }
 }
 
+/// The user to provision: IMDS's admin user if password authentication is
+/// disabled, else the OVF environment's user, else the configured
+/// `default_username`.
+///
+/// In `imds` mode there is no OVF user, so IMDS's admin user is taken
+/// whether or not password authentication is disabled.
 fn get_username(
+    mode: SourceMode,
     instance_metadata: &InstanceMetadata,
     environment: &Environment,
-) -> Result<String, anyhow::Error> {
-    if instance_metadata
-        .compute
-        .os_profile
-        .disable_password_authentication
-    {
+    user_provisioners: &config::UserProvisioners,
+) -> Result<(String, MetadataSource), anyhow::Error> {
+    let os_profile = &instance_metadata.compute.os_profile;
+    if os_profile.disable_password_authentication || mode == SourceMode::Imds {
         // password authentication is disabled
-        Ok(instance_metadata.compute.os_profile.admin_username.clone())
-    } else {
-        // password authentication is enabled
+        match os_profile.admin_username.as_deref() {
+            Some(username) if !username.is_empty() => {
+                return Ok((username.to_string(), MetadataSource::Imds));
+            }
+            _ => tracing::info!(
+                "IMDS did not provide an admin username, using the OVF one"
+            ),
+        }
+    }
 
-        Ok(environment
-            .clone()
-            .provisioning_section
-            .linux_prov_conf_set
-            .username)
+    let username = &environment
+        .provisioning_section
+        .linux_prov_conf_set
+        .username;
+    if !username.is_empty() {
+        return Ok((username.clone(), MetadataSource::Ovf));
+    }
+
+    match &user_provisioners.default_username {
+        Some(username) if !username.is_empty() => {
+            tracing::warn!(
+                user = username,
+                "No username was provided, using the configured default"
+            );
+            Ok((username.clone(), MetadataSource::Config))
+        }
+        _ if mode == SourceMode::Imds => {
+            Err(anyhow::Error::from(LibError::UsernameFailure).context(
+                "IMDS provided no admin username, and the provisioning \
+                 media's is ignored with --metadata-source imds",
+            ))
+        }
+        _ => Err(LibError::UsernameFailure.into()),
+    }
+}
+
+/// Apply the cloud-config in the base64-encoded `custom_data`, if any: its
+/// hostname replaces `hostname`, its keys for `username` are authorized
+/// along with `keys`, and its files are written.
+///
+/// The custom data is optional, so failures are logged rather than keeping
+/// the user and their keys from being provisioned.
+fn apply_custom_data(
+    custom_data: &str,
+    username: &str,
+    hostname: &mut String,
+    keys: &mut Vec<PublicKeys>,
+) {
+    let custom_data = match custom_data::decode(custom_data) {
+        Ok(Some(custom_data)) => custom_data,
+        Ok(None) => return,
+        Err(error) => {
+            tracing::warn!(?error, "Failed to read the custom data");
+            return;
+        }
+    };
+
+    if let Some(custom_hostname) = custom_data.hostname() {
+        tracing::info!(
+            hostname = custom_hostname,
+            "Using the hostname from the custom data"
+        );
+        *hostname = custom_hostname.to_string();
+    }
+    keys.extend(custom_data.authorized_keys(username).into_iter().map(
+        |key_data| PublicKeys {
+            key_data,
+            path: String::new(),
+        },
+    ));
+    if let Err(error) = custom_data.write_files() {
+        tracing::warn!(?error, "Failed to write the custom data's files");
+    }
+}
+
+/// The hostname to set: IMDS's computer name, else the OVF environment's,
+/// else the one in the DHCP lease if `dhcp_fallback` is configured.
+///
+/// Without any, the hostname is empty, as before the fallbacks existed.
+fn get_hostname(
+    instance_metadata: &InstanceMetadata,
+    environment: &Environment,
+    hostname_provisioners: &config::HostnameProvisioners,
+) -> String {
+    let computer_name = &instance_metadata.compute.os_profile.computer_name;
+    if !computer_name.is_empty() {
+        return computer_name.clone();
+    }
+
+    let hostname = &environment
+        .provisioning_section
+        .linux_prov_conf_set
+        .hostname;
+    if !hostname.is_empty() {
+        tracing::info!("IMDS did not provide a hostname, using the OVF one");
+        return hostname.clone();
+    }
+
+    if hostname_provisioners.dhcp_fallback {
+        if let Some(hostname) = dhcp::lease_hostname() {
+            tracing::warn!(
+                hostname,
+                "No hostname was provided, using the DHCP lease's"
+            );
+            return hostname;
+        }
     }
+
+    String::new()
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    match provision().await {
+    let cli = Cli::parse();
+    let logging = logging::setup_layers(cli.verbose, cli.quiet);
+
+    if let Some(Command::ConfigSchema) = cli.command {
+        let schema = serde_json::to_string_pretty(&config::schema())
+            .expect("schema is valid JSON");
+        println!("{schema}");
+        return ExitCode::SUCCESS;
+    }
+
+    if cli.print_vm_id {
+        match status::get_vm_id() {
+            Some(vm_id) => println!("{vm_id}"),
+            None => println!("unavailable"),
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let result = match Config::load_with_sources(
+        cli.config_dir.as_deref(),
+        cli.config.as_deref(),
+    ) {
+        Ok((mut config, sources)) => {
+            cli.apply_overrides(&mut config);
+            // The patterns were checked when the configuration was loaded.
+            logging
+                .set_redact_patterns(&config.telemetry.redact_patterns)
+                .expect("redaction patterns are valid");
+            if let Some(path) =
+                config.azure_init_log_path.path.as_ref().filter(|path| {
+                    logging::has_free_space(
+                        path,
+                        config.telemetry.min_free_bytes,
+                        logging::available_bytes,
+                    )
+                })
+            {
+                // Stderr still has the log, so carry on without the file.
+                if let Err(error) = logging.open_file(
+                    path,
+                    config.telemetry.log_max_bytes,
+                    config.telemetry.log_max_files,
+                ) {
+                    tracing::error!(
+                        ?error,
+                        path = %path.display(),
+                        "Unable to open the log file"
+                    );
+                }
+            }
+            if cli.show_config_paths {
+                for path in sources {
+                    println!("{}", path.display());
+                }
+                return ExitCode::SUCCESS;
+            }
+            if cli.config_check {
+                for (key, value) in config.non_default() {
+                    println!("{key} = {value}");
+                }
+                return ExitCode::SUCCESS;
+            }
+            match &cli.command {
+                Some(Command::Health { state, reason }) => {
+                    report_health(&config, *state, reason.clone()).await
+                }
+                _ => {
+                    provision(&config, cli.metadata_source, !cli.no_report)
+                        .await
+                }
+            }
+        }
+        Err(e) => Err(e.into()),
+    };
+
+    match result {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             eprintln!("{:?}", e);
-            let config: u8 = exitcode::CONFIG
-                .try_into()
-                .expect("Error code must be less than 256");
-            match e.root_cause().downcast_ref::<LibError>() {
-                Some(LibError::UserMissing { user: _ }) => {
-                    ExitCode::from(config)
-                }
-                Some(LibError::NonEmptyPassword) => ExitCode::from(config),
-                Some(_) | None => ExitCode::FAILURE,
-            }
+            exit_code(&e)
         }
     }
 }
 
-async fn provision() -> Result<(), anyhow::Error> {
+/// The exit code for a run that failed with `e`: that of the outermost
+/// [`LibError`] in its chain, see [`LibError::exit_code`], or `1` if there
+/// is none.
+fn exit_code(e: &anyhow::Error) -> ExitCode {
+    match e.chain().find_map(|cause| cause.downcast_ref::<LibError>()) {
+        Some(error) => ExitCode::from(
+            u8::try_from(error.exit_code())
+                .expect("Error code must be less than 256"),
+        ),
+        None => ExitCode::FAILURE,
+    }
+}
+
+/// The header tying a run's requests to the platform's logs.
+const CLIENT_REQUEST_ID: &str = "x-ms-client-request-id";
+
+/// A client builder for requests made during the run identified by
+/// `request_id`, sent as the `x-ms-client-request-id` header.
+fn client_builder(
+    http: &config::Http,
+    request_id: &str,
+) -> Result<ClientBuilder, anyhow::Error> {
+    let mut user_agent = format!("azure-init v{VERSION}");
+    if let Some(suffix) = &http.user_agent_suffix {
+        user_agent = format!("{user_agent} {suffix}");
+    }
+
     let mut default_headers = header::HeaderMap::new();
-    let user_agent = header::HeaderValue::from_str(
-        format!("azure-init v{VERSION}").as_str(),
-    )?;
-    default_headers.insert(header::USER_AGENT, user_agent);
-    let client = Client::builder()
+    default_headers.insert(
+        header::USER_AGENT,
+        header::HeaderValue::from_str(&user_agent)?,
+    );
+    default_headers.insert(
+        CLIENT_REQUEST_ID,
+        header::HeaderValue::from_str(request_id)?,
+    );
+
+    Ok(Client::builder()
         .timeout(std::time::Duration::from_secs(30))
-        .default_headers(default_headers)
+        .default_headers(default_headers))
+}
+
+fn http_client(
+    config: &Config,
+    request_id: &str,
+) -> Result<Client, anyhow::Error> {
+    Ok(client_builder(&config.http, request_id)?.build()?)
+}
+
+/// A client for IMDS, with its configured timeouts.
+fn imds_client(
+    config: &Config,
+    request_id: &str,
+) -> Result<Client, anyhow::Error> {
+    let imds = &config.imds;
+    let client = client_builder(&config.http, request_id)?
+        .connect_timeout(imds.connect_timeout())
+        .timeout(imds.total_timeout())
         .build()?;
 
-    let instance_metadata = imds::query(&client).await?;
-    let username = get_username(&instance_metadata, &get_environment()?)?;
+    Ok(client)
+}
 
-    let mut file_path = "/home/".to_string();
-    file_path.push_str(username.as_str());
+async fn provision(
+    config: &Config,
+    mode: SourceMode,
+    report: bool,
+) -> Result<(), anyhow::Error> {
+    if config.conflict_detection.defer_to_cloud_init && cloud_init::is_active()
+    {
+        tracing::info!("cloud-init is active, deferring to it");
+        return Ok(());
+    }
+    if config.container.skip && container::is_container() {
+        tracing::info!("Running in a container, skipping provisioning");
+        return Ok(());
+    }
 
-    // always pass an empty password
-    distro::create_user_with_useradd(username.as_str())
-        .with_context(|| format!("Unabled to create user '{username}'"))?;
-    distro::set_password_with_passwd(username.as_str(), "").with_context(
-        || format!("Unabled to set an empty password for user '{username}'"),
-    )?;
+    // Held until provisioning is done, so a second run can't provision at
+    // the same time.
+    let _lock = match lock::try_lock(Path::new(lock::PATH_LOCK)) {
+        Ok(lock) => lock,
+        Err(LibError::AlreadyRunning { path }) => {
+            tracing::info!(
+                path = %path.display(),
+                "Another instance is provisioning, leaving it to that one"
+            );
+            return Ok(());
+        }
+        Err(error) => return Err(error.into()),
+    };
 
-    user::create_ssh_directory(username.as_str(), &file_path)
+    let start = Instant::now();
+    let request_id = uuid::Uuid::new_v4().to_string();
+    tracing::info!(%request_id, "Starting provisioning");
+    let client = http_client(config, &request_id)?;
+    let imds_client = imds_client(config, &request_id)?;
+
+    let environment = async {
+        let environment = match get_environment(config) {
+            Ok(environment) => environment,
+            // IMDS has the settings; the media would only have said whether
+            // the VM is preprovisioned.
+            Err(error) if mode == SourceMode::Imds => {
+                tracing::warn!(
+                    ?error,
+                    "Failed to read the provisioning media, assuming the VM \
+                     isn't preprovisioned"
+                );
+                Environment::default()
+            }
+            Err(error) => return Err(error),
+        };
+        if !environment
+            .platform_settings_section
+            .platform_settings
+            .preprovisioned_vm
+        {
+            return Ok(environment);
+        }
+        if mode == SourceMode::Ovf {
+            anyhow::bail!("A preprovisioned VM can't be assigned without IMDS");
+        }
+        tracing::info!("VM is preprovisioned, waiting to be assigned");
+        wait_for_assignment(&client, &imds_client, config, report).await
+    };
+    let instance_metadata = async {
+        imds::query_via_proxy(
+            &imds_client,
+            config.azure_proxy_agent.proxy_endpoint(),
+            &config.imds.endpoint,
+            config.imds.fallback_endpoint(),
+        )
         .await
-        .with_context(|| "Failed to create ssh directory.")?;
+        .inspect_err(log_imds_guidance)
+        .map_err(anyhow::Error::from)
+    };
+    let (environment, instance_metadata) =
+        read_metadata(mode, environment, instance_metadata).await?;
+    let security_profile = &instance_metadata.compute.security_profile;
+    tracing::info!(
+        target: "azure_init::security_profile",
+        security_type = security_profile.security_type,
+        secure_boot_enabled = security_profile.secure_boot_enabled,
+        virtual_tpm_enabled = security_profile.virtual_tpm_enabled,
+        "VM security profile"
+    );
+    let (username, source) = get_username(
+        mode,
+        &instance_metadata,
+        &environment,
+        &config.user_provisioners,
+    )?;
 
-    file_path.push_str("/.ssh");
+    if config.machine_info.enable && mode != SourceMode::Ovf {
+        // The file is informational, so don't fail provisioning over it.
+        if let Err(error) = machine_info::write_machine_info(
+            &instance_metadata.compute,
+            Path::new(machine_info::PATH_MACHINE_INFO),
+        ) {
+            tracing::warn!(?error, "Failed to write machine-info");
+        }
+    }
+
+    let mut hostname = get_hostname(
+        &instance_metadata,
+        &environment,
+        &config.hostname_provisioners,
+    );
 
-    user::set_ssh_keys(
+    let disable_password_auth =
+        password_auth_disabled(mode, &instance_metadata, &environment);
+    let mut keys = resolve_ssh_keys(
         instance_metadata.compute.public_keys,
-        username.to_string(),
-        file_path.clone(),
+        environment
+            .provisioning_section
+            .linux_prov_conf_set
+            .ssh_authorized_keys,
+    );
+    if config.custom_data.enable {
+        if let Some(custom_data) =
+            &instance_metadata.compute.os_profile.custom_data
+        {
+            apply_custom_data(custom_data, &username, &mut hostname, &mut keys);
+        }
+    }
+
+    let provision = Provision::builder()
+        .hostname(hostname)
+        .private_ip(
+            instance_metadata
+                .network
+                .primary_private_ip()
+                .map(str::to_string),
+        )
+        .user(User::new(username))
+        .keys(keys)
+        .config(config)
+        .disable_password_auth(disable_password_auth)
+        .status_path(progress::PATH_STATUS_FILE)
+        .build();
+    let ssh_keys = provision.authorized_keys().len();
+    let summary = provision.provision_async().await;
+    log_summary(
+        &summary,
+        source,
+        provision.user(),
+        ssh_keys,
+        config.hostname_provisioners.enable,
+        start.elapsed(),
+    );
+
+    if !summary.is_success() {
+        let (configured, failed): (Vec<_>, Vec<_>) = summary
+            .steps()
+            .into_iter()
+            .partition(|(_, result)| result.is_ok());
+        for (step, result) in &failed {
+            if let Err(e) = result {
+                tracing::error!(step, error = ?e, "Provisioning step failed");
+            }
+        }
+        let configured: Vec<_> =
+            configured.into_iter().map(|(step, _)| step).collect();
+        let failed: Vec<_> = failed.into_iter().map(|(step, _)| step).collect();
+        summary.into_result().with_context(|| {
+            format!(
+                "Failed to provision the VM (configured: [{}], failed: [{}])",
+                configured.join(", "),
+                failed.join(", ")
+            )
+        })?;
+    }
+
+    report_ready(&client, config, report).await
+}
+
+/// Pick the SSH keys to provision: the `imds` keys if there are any, else
+/// the `ovf` keys from the provisioning media.
+///
+/// The configured extra keys aren't picked here; [`Provision`] authorizes
+/// them alongside these, and [`Provision::authorized_keys`] gives the merged
+/// set.
+fn resolve_ssh_keys(
+    imds: Vec<PublicKeys>,
+    ovf: Vec<String>,
+) -> Vec<PublicKeys> {
+    let (source, keys) = if !imds.is_empty() {
+        ("imds", imds)
+    } else {
+        ("ovf", to_public_keys(ovf))
+    };
+    tracing::info!(source, keys = keys.len(), "Resolved SSH keys");
+
+    keys
+}
+
+/// Keys without a path of their own, which go to the user's file.
+fn to_public_keys(keys: Vec<String>) -> Vec<PublicKeys> {
+    keys.into_iter()
+        .map(|key_data| PublicKeys {
+            key_data,
+            path: String::new(),
+        })
+        .collect()
+}
+
+/// Log a single event summarizing what provisioning did, for triage.
+fn log_summary(
+    summary: &ProvisionSummary,
+    source: MetadataSource,
+    user: &User,
+    ssh_keys: usize,
+    hostname_enabled: bool,
+    elapsed: Duration,
+) {
+    tracing::info!(
+        target: "azure_init::summary",
+        source = source.as_str(),
+        user = user.name(),
+        user_created = summary.user.is_ok(),
+        groups = user.groups().len(),
+        ssh_keys,
+        hostname_set = hostname_enabled && summary.hostname.is_ok(),
+        success = summary.is_success(),
+        elapsed_ms = elapsed.as_millis() as u64,
+        "Provisioning finished"
+    );
+}
+
+/// Report the VM's health to the wireserver, unless `report` is false.
+async fn report_ready(
+    client: &Client,
+    config: &Config,
+    report: bool,
+) -> Result<(), anyhow::Error> {
+    if !report {
+        tracing::info!("Health reporting is disabled, skipping the wireserver");
+        return Ok(());
+    }
+
+    let goalstate = fetch_goalstate(client, config).await?;
+    send_health(client, config, &goalstate, &Health::Ready).await
+}
+
+/// Report `state` to the wireserver on its own, outside of provisioning.
+async fn report_health(
+    config: &Config,
+    state: HealthState,
+    reason: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let health = match state {
+        HealthState::Ready => Health::Ready,
+        HealthState::Failed => Health::NotReady { reason },
+    };
+
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let client = http_client(config, &request_id)?;
+    let goalstate = fetch_goalstate(&client, config).await?;
+    send_health(&client, config, &goalstate, &health).await
+}
+
+/// Fetch the goalstate, which can then be reused for every wireserver
+/// request rather than fetched again.
+async fn fetch_goalstate(
+    client: &Client,
+    config: &Config,
+) -> Result<Goalstate, anyhow::Error> {
+    goalstate::get_goalstate(client, &config.wireserver.endpoint)
+        .await
+        .with_context(|| "Failed to get desired goalstate.")
+}
+
+async fn send_health(
+    client: &Client,
+    config: &Config,
+    goalstate: &Goalstate,
+    health: &Health,
+) -> Result<(), anyhow::Error> {
+    goalstate::report_health_state(
+        client,
+        &config.wireserver.endpoint,
+        goalstate,
+        health,
+        config.wireserver.health_format,
     )
     .await
-    .with_context(|| "Failed to write ssh public keys.")?;
+    .with_context(|| "Failed to report VM health.")?;
 
-    distro::set_hostname_with_hostnamectl(
-        instance_metadata.compute.os_profile.computer_name.as_str(),
+    Ok(())
+}
+
+/// Explain the likely cause of an IMDS failure.
+fn log_imds_guidance(error: &LibError) {
+    match error {
+        LibError::ImdsDisabled { .. } => tracing::error!(
+            "IMDS returned 404; it may be disabled for this VM or blocked by \
+             a network security rule"
+        ),
+        LibError::ImdsTimeout { .. } => tracing::error!(
+            "IMDS is unreachable; check the VM has network connectivity and \
+             a route to the endpoint"
+        ),
+        _ => {}
+    }
+}
+
+/// Report a preprovisioned VM as ready, so that it can be assigned, and wait
+/// for the environment it gets assigned with.
+async fn wait_for_assignment(
+    client: &Client,
+    imds_client: &Client,
+    config: &Config,
+    report: bool,
+) -> Result<Environment, anyhow::Error> {
+    report_ready(client, config, report).await?;
+
+    let timeout =
+        Duration::from_secs(config.imds.preprovision_poll_timeout_secs);
+    let environment = imds::wait_for_reprovision_data(
+        imds_client,
+        &config.imds.endpoint,
+        timeout,
+        imds::REPROVISION_POLL_INTERVAL,
     )
-    .with_context(|| "Failed to set hostname.")?;
+    .await?;
+
+    Ok(environment)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    fn metadata(os_profile: serde_json::Value) -> InstanceMetadata {
+        serde_json::from_value(serde_json::json!({
+            "compute": {
+                "osProfile": os_profile,
+                "publicKeys": []
+            }
+        }))
+        .unwrap()
+    }
+
+    fn ovf_environment(username: &str) -> Environment {
+        let mut environment = Environment::default();
+        environment
+            .provisioning_section
+            .linux_prov_conf_set
+            .username = username.to_string();
+        environment
+    }
+
+    #[test]
+    fn username_from_imds() {
+        let metadata = metadata(serde_json::json!({
+            "adminUsername": "imdsuser",
+            "computerName": "test-host",
+            "disablePasswordAuthentication": "true"
+        }));
+        let username = get_username(
+            SourceMode::Auto,
+            &metadata,
+            &ovf_environment("ovfuser"),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(username, ("imdsuser".to_string(), MetadataSource::Imds));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let running = anyhow::Error::from(LibError::AlreadyRunning {
+            path: PathBuf::from(lock::PATH_LOCK),
+        });
+        assert_eq!(exit_code(&running), ExitCode::SUCCESS);
+        let missing = anyhow::Error::from(LibError::UserMissing {
+            user: "azureuser".to_string(),
+        });
+        assert_eq!(exit_code(&missing), ExitCode::from(78));
+        let io = anyhow::Error::from(LibError::Io(
+            std::io::ErrorKind::PermissionDenied.into(),
+        ))
+        .context("Failed to provision the VM");
+        assert_eq!(exit_code(&io), ExitCode::from(74));
+        let other = anyhow::anyhow!("not a library error");
+        assert_eq!(exit_code(&other), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn custom_data_applied() {
+        // A cloud-config setting the hostname to custom-host and authorizing
+        // "ssh-ed25519 AAAA custom@host".
+        let custom_data = "I2Nsb3VkLWNvbmZpZwpob3N0bmFtZTogY3VzdG9tLWhvc3QKc3NoX2F1dGhvcml6ZWRfa2V5czoKICAtIHNzaC1lZDI1NTE5IEFBQUEgY3VzdG9tQGhvc3QK";
+        let mut hostname = "imds-host".to_string();
+        let mut keys = vec![PublicKeys {
+            key_data: "ssh-rsa AAAA imds@host".to_string(),
+            path: "/home/azureuser/.ssh/authorized_keys".to_string(),
+        }];
+
+        apply_custom_data(custom_data, "azureuser", &mut hostname, &mut keys);
+        assert_eq!(hostname, "custom-host");
+        let keys: Vec<_> = keys.into_iter().map(|key| key.key_data).collect();
+        assert_eq!(
+            keys,
+            vec!["ssh-rsa AAAA imds@host", "ssh-ed25519 AAAA custom@host"]
+        );
+    }
+
+    #[test]
+    fn custom_data_failures_not_fatal() {
+        let mut hostname = "imds-host".to_string();
+        let mut keys = vec![];
+        apply_custom_data("not base64!", "azureuser", &mut hostname, &mut keys);
+        assert_eq!(hostname, "imds-host");
+
+        // A cloud-config authorizing "ssh-ed25519 AAAA custom@host", with a
+        // file in an unsupported encoding, which isn't written.
+        let custom_data = "I2Nsb3VkLWNvbmZpZwpzc2hfYXV0aG9yaXplZF9rZXlzOgogIC0gc3NoLWVkMjU1MTkgQUFBQSBjdXN0b21AaG9zdAp3cml0ZV9maWxlczoKICAtIHBhdGg6IC9ub25leGlzdGVudC9henVyZS1pbml0LXRlc3QKICAgIGVuY29kaW5nOiBnemlwCg==";
+        apply_custom_data(custom_data, "azureuser", &mut hostname, &mut keys);
+        assert_eq!(keys.len(), 1);
+        assert!(!Path::new("/nonexistent/azure-init-test").exists());
+    }
+
+    #[test]
+    fn hostname_falls_back_to_ovf() {
+        let mut environment = Environment::default();
+        environment
+            .provisioning_section
+            .linux_prov_conf_set
+            .hostname = "ovf-host".to_string();
+
+        let imds = metadata(serde_json::json!({
+            "computerName": "imds-host",
+            "disablePasswordAuthentication": "true"
+        }));
+        assert_eq!(
+            get_hostname(&imds, &environment, &Default::default()),
+            "imds-host"
+        );
+        let missing = metadata(serde_json::json!({
+            "computerName": "",
+            "disablePasswordAuthentication": "true"
+        }));
+        assert_eq!(
+            get_hostname(&missing, &environment, &Default::default()),
+            "ovf-host"
+        );
+        assert_eq!(
+            get_hostname(
+                &missing,
+                &Environment::default(),
+                &Default::default()
+            ),
+            ""
+        );
+    }
+
+    #[test]
+    fn username_falls_back_to_ovf() {
+        let environment = ovf_environment("ovfuser");
+
+        let missing = metadata(serde_json::json!({
+            "computerName": "test-host",
+            "disablePasswordAuthentication": "true"
+        }));
+        assert_eq!(
+            get_username(
+                SourceMode::Auto,
+                &missing,
+                &environment,
+                &Default::default()
+            )
+            .unwrap()
+            .0,
+            "ovfuser"
+        );
+
+        let empty = metadata(serde_json::json!({
+            "adminUsername": "",
+            "computerName": "test-host",
+            "disablePasswordAuthentication": "true"
+        }));
+        assert_eq!(
+            get_username(
+                SourceMode::Auto,
+                &empty,
+                &environment,
+                &Default::default()
+            )
+            .unwrap(),
+            ("ovfuser".to_string(), MetadataSource::Ovf)
+        );
+    }
+
+    #[test]
+    fn username_default() {
+        let metadata = metadata(serde_json::json!({
+            "computerName": "test-host",
+            "disablePasswordAuthentication": "true"
+        }));
+        let environment = ovf_environment("");
+
+        let error = get_username(
+            SourceMode::Auto,
+            &metadata,
+            &environment,
+            &Default::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<LibError>(),
+            Some(LibError::UsernameFailure)
+        ));
+
+        let user_provisioners = config::UserProvisioners {
+            default_username: Some("azureuser".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_username(
+                SourceMode::Auto,
+                &metadata,
+                &environment,
+                &user_provisioners
+            )
+            .unwrap(),
+            ("azureuser".to_string(), MetadataSource::Config)
+        );
+        // The default is only a last resort.
+        assert_eq!(
+            get_username(
+                SourceMode::Auto,
+                &metadata,
+                &ovf_environment("ovfuser"),
+                &user_provisioners
+            )
+            .unwrap()
+            .0,
+            "ovfuser"
+        );
+    }
+
+    #[test]
+    fn url_overrides_take_precedence() {
+        let mut config = Config::default();
+        config.imds.endpoint = "http://127.0.0.1:1".to_string();
+        config.wireserver.endpoint = "http://127.0.0.1:2".to_string();
+
+        let cli = Cli::try_parse_from([
+            "azure-init",
+            "--imds-url",
+            "http://127.0.0.1:8080",
+            "--wireserver-url",
+            "http://127.0.0.1:8081",
+        ])
+        .unwrap();
+        cli.apply_overrides(&mut config);
+
+        assert_eq!(config.imds.endpoint, "http://127.0.0.1:8080");
+        assert_eq!(config.wireserver.endpoint, "http://127.0.0.1:8081");
+    }
+
+    #[test]
+    fn config_kept_without_overrides() {
+        let mut config = Config::default();
+        config.imds.endpoint = "http://127.0.0.1:1".to_string();
+
+        let cli = Cli::try_parse_from(["azure-init"]).unwrap();
+        cli.apply_overrides(&mut config);
+
+        assert_eq!(config.imds.endpoint, "http://127.0.0.1:1");
+        assert_eq!(
+            config.wireserver.endpoint,
+            Config::default().wireserver.endpoint
+        );
+    }
+
+    #[test]
+    fn show_config_paths_lists_cli_sources_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let drop_ins = dir.path().join("azure-init.toml.d");
+        std::fs::create_dir(&drop_ins).unwrap();
+        std::fs::write(drop_ins.join("10-test.toml"), "").unwrap();
+        let file = dir.path().join("azure-init.toml");
+        std::fs::write(&file, "").unwrap();
+
+        let cli = Cli::try_parse_from([
+            "azure-init",
+            "--show-config-paths",
+            "--config-dir",
+            drop_ins.to_str().unwrap(),
+            "--config",
+            file.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(cli.show_config_paths);
+
+        let (_, sources) = Config::load_with_sources(
+            cli.config_dir.as_deref(),
+            cli.config.as_deref(),
+        )
+        .unwrap();
+        assert!(sources.ends_with(&[drop_ins.join("10-test.toml"), file]));
+    }
+
+    #[test]
+    fn config_check_includes_overrides() {
+        let mut config = Config::default();
+        let cli = Cli::try_parse_from([
+            "azure-init",
+            "--config-check",
+            "--imds-url",
+            "http://127.0.0.1:8080",
+        ])
+        .unwrap();
+        assert!(cli.config_check);
+        cli.apply_overrides(&mut config);
+
+        let diff = config.non_default();
+        assert_eq!(diff.keys().collect::<Vec<_>>(), vec!["imds.endpoint"]);
+    }
+
+    #[test]
+    fn verbose_is_counted() {
+        let cli = Cli::try_parse_from(["azure-init"]).unwrap();
+        assert_eq!(cli.verbose, 0);
+        let cli = Cli::try_parse_from(["azure-init", "-vv"]).unwrap();
+        assert_eq!(cli.verbose, 2);
+    }
+
+    #[test]
+    fn quiet_conflicts_with_verbose() {
+        let cli = Cli::try_parse_from(["azure-init", "--quiet"]).unwrap();
+        assert!(cli.quiet);
+        assert!(Cli::try_parse_from(["azure-init", "-q", "-v"]).is_err());
+    }
 
-    let vm_goalstate = goalstate::get_goalstate(&client)
+    #[test]
+    fn metadata_source_flag() {
+        let cli = Cli::try_parse_from(["azure-init"]).unwrap();
+        assert_eq!(cli.metadata_source, SourceMode::Auto);
+        let cli =
+            Cli::try_parse_from(["azure-init", "--metadata-source", "ovf"])
+                .unwrap();
+        assert_eq!(cli.metadata_source, SourceMode::Ovf);
+        assert!(Cli::try_parse_from([
+            "azure-init",
+            "--metadata-source",
+            "dhcp"
+        ])
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn imds_source_ignores_media_settings() {
+        let mut media = ovf_environment("ovfuser");
+        media
+            .platform_settings_section
+            .platform_settings
+            .preprovisioned_vm = true;
+        let (environment, instance_metadata) =
+            read_metadata(SourceMode::Imds, async { Ok(media) }, async {
+                Ok(InstanceMetadata::builder()
+                    .admin_username("imdsuser")
+                    .build())
+            })
+            .await
+            .unwrap();
+
+        // Only whether the VM is preprovisioned is kept.
+        assert!(
+            environment
+                .platform_settings_section
+                .platform_settings
+                .preprovisioned_vm
+        );
+        assert_eq!(
+            environment.provisioning_section,
+            Environment::default().provisioning_section
+        );
+        assert_eq!(
+            get_username(
+                SourceMode::Imds,
+                &instance_metadata,
+                &environment,
+                &Default::default()
+            )
+            .unwrap(),
+            ("imdsuser".to_string(), MetadataSource::Imds)
+        );
+    }
+
+    #[test]
+    fn imds_source_requires_imds_username() {
+        let metadata = metadata(serde_json::json!({
+            "computerName": "test-host",
+            "disablePasswordAuthentication": "false"
+        }));
+
+        let error = get_username(
+            SourceMode::Imds,
+            &metadata,
+            &Environment::default(),
+            &Default::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("--metadata-source imds"));
+        assert!(matches!(
+            error.downcast_ref::<LibError>(),
+            Some(LibError::UsernameFailure)
+        ));
+    }
+
+    #[tokio::test]
+    async fn ovf_source_skips_imds() {
+        let requested = AtomicBool::new(false);
+        let (environment, instance_metadata) = read_metadata(
+            SourceMode::Ovf,
+            async { Ok(ovf_environment("ovfuser")) },
+            async {
+                requested.store(true, Ordering::SeqCst);
+                Ok(InstanceMetadata::builder()
+                    .admin_username("imdsuser")
+                    .build())
+            },
+        )
         .await
-        .with_context(|| "Failed to get desired goalstate.")?;
-    goalstate::report_health(&client, vm_goalstate)
+        .unwrap();
+
+        assert!(!requested.load(Ordering::SeqCst));
+        let (username, source) = get_username(
+            SourceMode::Ovf,
+            &instance_metadata,
+            &environment,
+            &config::UserProvisioners::default(),
+        )
+        .unwrap();
+        assert_eq!(username, "ovfuser");
+        assert_eq!(source, MetadataSource::Ovf);
+    }
+
+    #[tokio::test]
+    async fn password_auth_from_ovf_without_imds() {
+        let mut environment = ovf_environment("ovfuser");
+        environment
+            .provisioning_section
+            .linux_prov_conf_set
+            .disable_ssh_password_authentication = true;
+        let (environment, instance_metadata) =
+            read_metadata(SourceMode::Ovf, async { Ok(environment) }, async {
+                unreachable!("IMDS is skipped")
+            })
+            .await
+            .unwrap();
+        assert!(password_auth_disabled(
+            SourceMode::Ovf,
+            &instance_metadata,
+            &environment
+        ));
+
+        // Otherwise IMDS decides, whatever the OVF environment says.
+        let instance_metadata = InstanceMetadata::builder()
+            .disable_password_authentication(false)
+            .build();
+        assert!(!password_auth_disabled(
+            SourceMode::Auto,
+            &instance_metadata,
+            &environment
+        ));
+        assert!(!password_auth_disabled(
+            SourceMode::Ovf,
+            &instance_metadata,
+            &ovf_environment("ovfuser")
+        ));
+    }
+
+    #[tokio::test]
+    async fn auto_source_reads_both() {
+        let (environment, instance_metadata) = read_metadata(
+            SourceMode::Auto,
+            async { Ok(ovf_environment("ovfuser")) },
+            async {
+                Ok(InstanceMetadata::builder()
+                    .admin_username("imdsuser")
+                    .build())
+            },
+        )
         .await
-        .with_context(|| "Failed to report VM health.")?;
+        .unwrap();
 
-    Ok(())
+        assert_eq!(environment, ovf_environment("ovfuser"));
+        assert_eq!(
+            instance_metadata
+                .compute
+                .os_profile
+                .admin_username
+                .as_deref(),
+            Some("imdsuser")
+        );
+    }
+
+    #[test]
+    fn config_schema_subcommand() {
+        let cli = Cli::try_parse_from(["azure-init", "config-schema"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::ConfigSchema)));
+    }
+
+    #[test]
+    fn imds_timeout_overrides() {
+        let mut config = Config::default();
+        config.imds.connect_timeout_secs = 10.0;
+        config.imds.total_timeout_secs = 20.0;
+
+        let cli = Cli::try_parse_from([
+            "azure-init",
+            "--imds-connect-timeout",
+            "0.5",
+            "--imds-total-timeout",
+            "1.5",
+        ])
+        .unwrap();
+        cli.apply_overrides(&mut config);
+        assert_eq!(config.imds.connect_timeout_secs, 0.5);
+        assert_eq!(config.imds.total_timeout_secs, 1.5);
+
+        let result =
+            Cli::try_parse_from(["azure-init", "--imds-total-timeout", "-1"]);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn imds_client_uses_total_timeout() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accept the connection but never answer.
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let mut config = Config::default();
+        let cli =
+            Cli::try_parse_from(["azure-init", "--imds-total-timeout", "0.2"])
+                .unwrap();
+        cli.apply_overrides(&mut config);
+
+        let client = imds_client(&config, "request").unwrap();
+        let start = Instant::now();
+        let error = client
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.is_timeout());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn log_file_overrides_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.log");
+        let cli_path = dir.path().join("cli.log");
+        let mut config = Config::default();
+        config.azure_init_log_path.path = Some(config_path.clone());
+
+        let cli = Cli::try_parse_from([
+            "azure-init",
+            "--log-file",
+            cli_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        cli.apply_overrides(&mut config);
+        assert_eq!(config.azure_init_log_path.path.as_ref(), Some(&cli_path));
+
+        let (subscriber, logging) = logging::subscriber(0, false, None);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        logging
+            .open_file(
+                config.azure_init_log_path.path.as_ref().unwrap(),
+                config.telemetry.log_max_bytes,
+                config.telemetry.log_max_files,
+            )
+            .unwrap();
+        tracing::info!("logged to the CLI path");
+
+        let contents = std::fs::read_to_string(&cli_path).unwrap();
+        assert!(contents.contains("logged to the CLI path"));
+        assert!(!config_path.exists());
+    }
+
+    #[test]
+    fn print_vm_id_flag() {
+        let cli = Cli::try_parse_from(["azure-init"]).unwrap();
+        assert!(!cli.print_vm_id);
+
+        let cli = Cli::try_parse_from(["azure-init", "--print-vm-id"]).unwrap();
+        assert!(cli.print_vm_id);
+    }
+
+    #[test]
+    fn no_report_flag() {
+        let cli = Cli::try_parse_from(["azure-init"]).unwrap();
+        assert!(!cli.no_report);
+        let cli = Cli::try_parse_from(["azure-init", "--no-report"]).unwrap();
+        assert!(cli.no_report);
+    }
+
+    #[tokio::test]
+    async fn no_report_makes_no_requests() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.wireserver.endpoint =
+            format!("http://{}", listener.local_addr().unwrap());
+
+        report_ready(&Client::new(), &config, false).await.unwrap();
+
+        let accepted =
+            tokio::time::timeout(Duration::from_millis(100), listener.accept())
+                .await;
+        assert!(accepted.is_err(), "the wireserver was contacted");
+    }
+
+    /// Serve a goalstate, then accept a health report, returning both
+    /// requests.
+    async fn mock_wireserver() -> (String, tokio::task::JoinHandle<Vec<String>>)
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let goalstate = "<Goalstate>\
+            <Container>\
+                <ContainerId>container</ContainerId>\
+                <RoleInstanceList>\
+                    <RoleInstance>\
+                        <InstanceId>instance</InstanceId>\
+                    </RoleInstance>\
+                </RoleInstanceList>\
+            </Container>\
+            <Version>2012-11-30</Version>\
+            <Incarnation>1</Incarnation>\
+        </Goalstate>";
+
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+            for body in [goalstate, ""] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                let mut buf = vec![0; 4096];
+                // Read the headers and however much of the body is declared.
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, rest)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_ascii_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(|len| len.parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if rest.len() >= length || n == 0 {
+                            break;
+                        }
+                    }
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nConnection: close\r\n\
+                     Content-Length: {}\r\n\r\n{body}",
+                    body.len(),
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        (endpoint, server)
+    }
+
+    #[tokio::test]
+    async fn requests_share_request_id() {
+        let (endpoint, server) = mock_wireserver().await;
+        let mut config = Config::default();
+        config.wireserver.endpoint = endpoint;
+        config.http.user_agent_suffix = Some("image/1.0".to_string());
+
+        let client = http_client(&config, "0f8fad5b-d9cb-469f").unwrap();
+        let goalstate = fetch_goalstate(&client, &config).await.unwrap();
+        send_health(&client, &config, &goalstate, &Health::Ready)
+            .await
+            .unwrap();
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            let request = request.to_ascii_lowercase();
+            assert!(request.contains(
+                "\r\nx-ms-client-request-id: 0f8fad5b-d9cb-469f\r\n"
+            ));
+            assert!(request.contains(&format!(
+                "\r\nuser-agent: azure-init v{VERSION} image/1.0\r\n"
+            )));
+        }
+    }
+
+    #[test]
+    fn health_subcommand() {
+        let cli = Cli::try_parse_from([
+            "azure-init",
+            "health",
+            "--state",
+            "failed",
+            "--reason",
+            "disk full",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Health {
+                state: HealthState::Failed,
+                reason: Some(_)
+            })
+        ));
+
+        let result =
+            Cli::try_parse_from(["azure-init", "health", "--state", "meh"]);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn health_reports_ready() {
+        let (endpoint, server) = mock_wireserver().await;
+        let mut config = Config::default();
+        config.wireserver.endpoint = endpoint;
+
+        report_health(&config, HealthState::Ready, None)
+            .await
+            .unwrap();
+
+        let request = server.await.unwrap().pop().unwrap();
+        assert!(request.starts_with("POST /machine/?comp=health"));
+        assert!(request.contains("<State>Ready</State>"));
+        assert!(request.contains("<ContainerId>container</ContainerId>"));
+    }
+
+    #[tokio::test]
+    async fn health_reported_as_json() {
+        let (endpoint, server) = mock_wireserver().await;
+        let mut config = Config::default();
+        config.wireserver.endpoint = endpoint;
+        config.wireserver.health_format = config::HealthFormat::Json;
+
+        report_health(&config, HealthState::Ready, None)
+            .await
+            .unwrap();
+
+        let request = server.await.unwrap().pop().unwrap();
+        assert!(request
+            .to_ascii_lowercase()
+            .contains("\r\ncontent-type: application/json\r\n"));
+        assert!(request.contains(r#""State":"Ready""#));
+    }
+
+    #[tokio::test]
+    async fn health_reports_failed() {
+        let (endpoint, server) = mock_wireserver().await;
+        let mut config = Config::default();
+        config.wireserver.endpoint = endpoint;
+
+        report_health(
+            &config,
+            HealthState::Failed,
+            Some("disk full".to_string()),
+        )
+        .await
+        .unwrap();
+
+        let request = server.await.unwrap().pop().unwrap();
+        assert!(request.contains("<State>NotReady</State>"));
+        assert!(request.contains("<Description>disk full</Description>"));
+    }
+
+    #[tokio::test]
+    async fn health_fails_without_wireserver() {
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.wireserver.endpoint =
+            format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let result = report_health(&config, HealthState::Ready, None).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn summary_logged() {
+        use std::sync::{Arc, Mutex};
+
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::Layer;
+
+        /// An event's fields' names and values.
+        type Event = Vec<(String, String)>;
+
+        /// Records the fields of every summary event.
+        #[derive(Clone, Default)]
+        struct Summaries(Arc<Mutex<Vec<Event>>>);
+
+        struct Fields(Vec<(String, String)>);
+
+        impl Visit for Fields {
+            fn record_debug(
+                &mut self,
+                field: &Field,
+                value: &dyn std::fmt::Debug,
+            ) {
+                self.0
+                    .push((field.name().to_string(), format!("{value:?}")));
+            }
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for Summaries {
+            fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+                if event.metadata().target() == "azure_init::summary" {
+                    let mut fields = Fields(vec![]);
+                    event.record(&mut fields);
+                    self.0.lock().unwrap().push(fields.0);
+                }
+            }
+        }
+
+        let summaries = Summaries::default();
+        let subscriber = tracing_subscriber::registry().with(summaries.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let summary = ProvisionSummary {
+            hostname: Err(LibError::NoHostnameProvisioner),
+            system: Ok(()),
+            user: Ok(()),
+            groups: Ok(()),
+            password: Ok(()),
+            sudo: Ok(()),
+            ssh_keys: Ok(()),
+            extra_users: Ok(()),
+            sshd: Ok(()),
+            hooks: Ok(()),
+            post_provision: Ok(()),
+        };
+        let user = User::new("azureuser").with_groups(["adm", "wheel"]);
+        log_summary(
+            &summary,
+            MetadataSource::Imds,
+            &user,
+            3,
+            true,
+            Duration::from_millis(1500),
+        );
+
+        let summaries = summaries.0.lock().unwrap();
+        assert_eq!(summaries.len(), 1);
+        let field = |name: &str| {
+            summaries[0]
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap_or_else(|| panic!("missing field {name}"))
+        };
+        assert_eq!(field("source"), "\"imds\"");
+        assert_eq!(field("user"), "\"azureuser\"");
+        assert_eq!(field("user_created"), "true");
+        assert_eq!(field("groups"), "2");
+        assert_eq!(field("ssh_keys"), "3");
+        assert_eq!(field("hostname_set"), "false");
+        assert_eq!(field("success"), "false");
+        assert_eq!(field("elapsed_ms"), "1500");
+    }
+
+    #[test]
+    fn ssh_key_precedence() {
+        let imds = vec![PublicKeys {
+            key_data: "ssh-rsa imds".to_string(),
+            path: "/home/azureuser/.ssh/authorized_keys".to_string(),
+        }];
+        let ovf = vec!["ssh-rsa ovf".to_string()];
+        let key_data = |keys: Vec<PublicKeys>| -> Vec<String> {
+            keys.into_iter().map(|key| key.key_data).collect()
+        };
+
+        assert_eq!(
+            key_data(resolve_ssh_keys(imds.clone(), ovf.clone())),
+            vec!["ssh-rsa imds"]
+        );
+        let keys = resolve_ssh_keys(vec![], ovf.clone());
+        assert_eq!(keys[0].path, "");
+        assert_eq!(key_data(keys), vec!["ssh-rsa ovf"]);
+        assert!(resolve_ssh_keys(vec![], vec![]).is_empty());
+    }
+
+    #[test]
+    fn invalid_url_rejected() {
+        let result =
+            Cli::try_parse_from(["azure-init", "--imds-url", "not-a-url"]);
+        assert!(result.is_err());
+
+        let result = Cli::try_parse_from([
+            "azure-init",
+            "--wireserver-url",
+            "file:///etc/passwd",
+        ]);
+        assert!(result.is_err());
+    }
 }
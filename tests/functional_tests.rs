@@ -2,9 +2,11 @@
 // Licensed under the MIT License.
 
 use libazureinit::imds::PublicKeys;
+use libazureinit::user::User;
 use libazureinit::{
     distro, goalstate,
     reqwest::{header, Client},
+    runner::SystemRunner,
     user,
 };
 
@@ -30,7 +32,9 @@ async fn main() {
 
     println!("Querying wireserver for Goalstate");
 
-    let get_goalstate_result = goalstate::get_goalstate(&client).await;
+    let get_goalstate_result =
+        goalstate::get_goalstate(&client, goalstate::DEFAULT_WIRESERVER_URL)
+            .await;
     let vm_goalstate = match get_goalstate_result {
         Ok(vm_goalstate) => vm_goalstate,
         Err(_err) => return,
@@ -40,8 +44,12 @@ async fn main() {
     println!();
     println!("Reporting VM Health to wireserver");
 
-    let report_health_result =
-        goalstate::report_health(&client, vm_goalstate).await;
+    let report_health_result = goalstate::report_health(
+        &client,
+        goalstate::DEFAULT_WIRESERVER_URL,
+        &vm_goalstate,
+    )
+    .await;
     match report_health_result {
         Ok(report_health) => report_health,
         Err(_err) => return,
@@ -60,9 +68,9 @@ async fn main() {
         username.as_str()
     );
 
-    distro::create_user_with_useradd(username.as_str())
+    distro::create_user_with_useradd(&SystemRunner, &User::new(username))
         .expect("Failed to create user for user '{username}'");
-    distro::set_password_with_passwd(username.as_str(), "")
+    distro::set_password_with_passwd(&SystemRunner, username.as_str(), "")
         .expect("Unabled to set an empty passord for user '{username}'");
 
     println!("User {} was successfully created", username.as_str());
@@ -102,7 +110,7 @@ async fn main() {
     println!();
     println!("Attempting to set the VM hostname");
 
-    distro::set_hostname_with_hostnamectl("test-hostname-set")
+    distro::set_hostname_with_hostnamectl(&SystemRunner, "test-hostname-set")
         .expect("Failed to set hostname");
 
     println!("VM hostname successfully set");